@@ -20,14 +20,18 @@ use wrapper::mini_lsm_wrapper;
 use anyhow::Result;
 use bytes::Bytes;
 use clap::{Parser, ValueEnum};
+use mini_lsm_wrapper::block::DEFAULT_RESTART_INTERVAL;
 use mini_lsm_wrapper::compact::{
     CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
     TieredCompactionOptions,
 };
 use mini_lsm_wrapper::iterators::StorageIterator;
-use mini_lsm_wrapper::lsm_storage::{LsmStorageOptions, MiniLsm};
+use mini_lsm_wrapper::lsm_storage::{DurabilityMode, LsmStorageOptions, MiniLsm};
+use mini_lsm_wrapper::table::{CompressionType, DEFAULT_BLOOM_BITS_PER_KEY, FilterType};
+use mini_lsm_wrapper::wal::WalOptions;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Clone, ValueEnum)]
 enum CompactionStrategy {
@@ -332,6 +336,17 @@ fn main() -> Result<()> {
         args.path,
         LsmStorageOptions {
             block_size: 4096,
+            block_restart_interval: DEFAULT_RESTART_INTERVAL,
+            block_hash_index: true,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            filter_type: FilterType::Classic,
+            prefix_extractor: None,
+            index_partition_size: 0,
+            compression_per_level: vec![
+                CompressionType::None,
+                CompressionType::Lz4,
+                CompressionType::Zstd,
+            ],
             target_sst_size: 2 << 20, // 2MB
             num_memtable_limit: 3,
             compaction_options: match args.compaction {
@@ -359,8 +374,16 @@ fn main() -> Result<()> {
                     })
                 }
             },
-            enable_wal: args.enable_wal,
+            durability: if args.enable_wal {
+                DurabilityMode::SyncManual
+            } else {
+                DurabilityMode::NoWal
+            },
+            wal_options: WalOptions::default(),
+            value_log: None,
+            merge_operator: None,
             serializable: args.serializable,
+            lock_wait_timeout: Duration::from_secs(1),
         },
     )?;
 
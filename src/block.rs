@@ -18,54 +18,245 @@
 mod builder;
 mod iterator;
 
-pub use builder::BlockBuilder;
+pub use builder::{BlockBuilder, DEFAULT_RESTART_INTERVAL};
 use bytes::{Buf, BufMut, Bytes};
 pub use iterator::BlockIterator;
+
+use crate::key::KeySlice;
+
 pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+pub(crate) const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// The layout of the entries of a block, recorded per SST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockFormat {
+    /// u16 key and value lengths and offsets, limiting a block to 64KB.
+    V1,
+    /// Varint key and value lengths and u32 offsets.
+    V2,
+    /// Like `V2`, but keys are prefix-compressed against the previous key and stored in full at
+    /// restart points, whose u32 offsets replace the offsets of every entry.
+    V3,
+    /// Like `V3`, with the `ValueType` (u8) of each entry before its value length.
+    V4,
+}
+
+impl BlockFormat {
+    /// The size of each offset, and of the number of offsets, at the end of a block.
+    fn sizeof_offset(self) -> usize {
+        match self {
+            BlockFormat::V1 => SIZEOF_U16,
+            BlockFormat::V2 | BlockFormat::V3 | BlockFormat::V4 => SIZEOF_U32,
+        }
+    }
+
+    /// Whether the blocks have restart points, and may have a hash index.
+    fn has_restarts(self) -> bool {
+        matches!(self, BlockFormat::V3 | BlockFormat::V4)
+    }
+}
+
+/// Append `value` to `buf` as a LEB128 varint.
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// Read a LEB128 varint from `buf`, advancing it.
+pub(crate) fn get_varint(buf: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// The size of `value` encoded as a varint.
+pub(crate) fn varint_len(value: usize) -> usize {
+    (usize::BITS - (value | 1).leading_zeros()).div_ceil(7) as usize
+}
+
+/// Set in the number of restart points of a block followed by a hash index.
+const HASH_INDEX_FLAG: u32 = 1 << 31;
+/// A bucket of the hash index without any key.
+pub(crate) const HASH_NO_ENTRY: u8 = u8::MAX;
+/// A bucket of the hash index with keys of different restart intervals.
+pub(crate) const HASH_COLLISION: u8 = u8::MAX - 1;
+
+/// The number of buckets of the hash index of `num_keys` keys, for a load factor of 0.75.
+pub(crate) fn hash_index_buckets(num_keys: usize) -> usize {
+    num_keys * 4 / 3 + 1
+}
+
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted key-value pairs.
 pub struct Block {
     pub(crate) data: Vec<u8>,
-    pub(crate) offsets: Vec<u16>,
+    /// Offsets of the restart points, the first of which is the first entry.
+    pub(crate) offsets: Vec<u32>,
+    /// Maps the hash of a key to the restart point it follows, `HASH_NO_ENTRY` or
+    /// `HASH_COLLISION`. Empty if the block has no hash index.
+    pub(crate) hash_index: Vec<u8>,
+    /// Whether each entry carries its `ValueType`, as in `BlockFormat::V4`. Otherwise an empty
+    /// value is a tombstone.
+    pub(crate) typed: bool,
 }
 
 impl Block {
     /// Encode the internal data to the data layout illustrated in the course
     /// Note: You may want to recheck if any of the expected field is missing from your output
+    ///
+    /// The hash index, if any, follows the entries as `buckets | number of buckets (u32)`, and is
+    /// flagged by `HASH_INDEX_FLAG` in the number of restart points.
     pub fn encode(&self) -> Bytes {
         let mut buf = self.data.clone();
         let data_len = self.data.len();
         let offsets_len = self.offsets.len();
+        let mut num_offsets = offsets_len as u32;
+        if !self.hash_index.is_empty() {
+            buf.put_slice(&self.hash_index);
+            buf.put_u32(self.hash_index.len() as u32);
+            num_offsets |= HASH_INDEX_FLAG;
+        }
+        let offsets_start = buf.len();
         for offset in &self.offsets {
-            buf.put_u16(*offset);
+            buf.put_u32(*offset);
         }
-        let offset_real_len = buf.len() - data_len;
+        let offset_real_len = buf.len() - offsets_start;
         debug_assert_eq!(
             offset_real_len,
-            offsets_len * SIZEOF_U16,
-            "offset_real_len should be equal to offsets_len * SIZEOF_U16"
+            offsets_len * SIZEOF_U32,
+            "offset_real_len should be equal to offsets_len * SIZEOF_U32"
         );
         // Add number of elements at the end of the block
-        buf.put_u16(offsets_len as u16);
-        debug_assert!(buf.len() < 4096, "buf.len() should be less than 4096");
+        buf.put_u32(num_offsets);
+        debug_assert!(
+            data_len <= u32::MAX as usize,
+            "data_len should fit in the u32 offsets"
+        );
         buf.into()
     }
 
+    /// Split `data` encoded in `format` into its entries, its offsets and its hash index (empty if
+    /// there is none), or `None` if it is too short to hold the sections it claims.
+    fn split_encoded(data: &[u8], format: BlockFormat) -> Option<(&[u8], &[u8], &[u8])> {
+        let sizeof_offset = format.sizeof_offset();
+        let (rest, mut num_raw) = data.split_at(data.len().checked_sub(sizeof_offset)?);
+        let mut entry_offsets_num = num_raw.get_uint(sizeof_offset) as usize;
+        let has_hash_index =
+            format.has_restarts() && entry_offsets_num & HASH_INDEX_FLAG as usize != 0;
+        if has_hash_index {
+            entry_offsets_num &= !(HASH_INDEX_FLAG as usize);
+        }
+        let offsets_len = entry_offsets_num.checked_mul(sizeof_offset)?;
+        let (rest, offsets_raw) = rest.split_at(rest.len().checked_sub(offsets_len)?);
+        if !has_hash_index {
+            return Some((rest, offsets_raw, &[]));
+        }
+        let (rest, mut num_buckets_raw) = rest.split_at(rest.len().checked_sub(SIZEOF_U32)?);
+        let num_buckets = num_buckets_raw.get_u32() as usize;
+        let (entries, hash_index) = rest.split_at(rest.len().checked_sub(num_buckets)?);
+        Some((entries, offsets_raw, hash_index))
+    }
+
+    /// Whether `data` in `format` is long enough to hold the offsets it claims, with every offset
+    /// inside the data section and every bucket of the hash index pointing to a restart point, so
+    /// that `decode` does not panic on it.
+    pub(crate) fn is_well_formed(data: &[u8], format: BlockFormat) -> bool {
+        let sizeof_offset = format.sizeof_offset();
+        let Some((entries, offsets_raw, hash_index)) = Self::split_encoded(data, format) else {
+            return false;
+        };
+        let entry_offsets_num = offsets_raw.len() / sizeof_offset;
+        entry_offsets_num > 0
+            && offsets_raw
+                .chunks(sizeof_offset)
+                .all(|mut x| (x.get_uint(sizeof_offset) as usize) < entries.len())
+            && hash_index
+                .iter()
+                .all(|&bucket| bucket >= HASH_COLLISION || (bucket as usize) < entry_offsets_num)
+    }
+
     /// Decode from the data layout, transform the input `data` to a single `Block`
     pub fn decode(data: &[u8]) -> Self {
-        debug_assert!(data.len() < 4096, "data.len() should be less than 4096");
-        // get the number of elements
-        let entry_offsets_num = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - entry_offsets_num * SIZEOF_U16;
-        debug_assert!(data_end > 0, "data_end should be greater than 0");
-        let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
+        Self::decode_with_restarts(data, BlockFormat::V4)
+    }
+
+    /// Decode a block with restart points, which is kept in its own format.
+    fn decode_with_restarts(data: &[u8], format: BlockFormat) -> Self {
+        let (entries, offsets_raw, hash_index) =
+            Self::split_encoded(data, format).expect("malformed block");
+        debug_assert!(!entries.is_empty(), "data_end should be greater than 0");
         // get offset array
         let offsets = offsets_raw
-            .chunks(SIZEOF_U16)
-            .map(|mut x| x.get_u16())
+            .chunks(SIZEOF_U32)
+            .map(|mut x| x.get_u32())
             .collect();
-        // retrieve data
-        let data = data[0..data_end].to_vec();
         // create block
-        Self { data, offsets }
+        Self {
+            data: entries.to_vec(),
+            offsets,
+            hash_index: hash_index.to_vec(),
+            typed: format == BlockFormat::V4,
+        }
+    }
+
+    /// The bucket of the hash index for `key`, or `None` if the block has no hash index.
+    pub(crate) fn hash_bucket(&self, key: KeySlice) -> Option<u8> {
+        if self.hash_index.is_empty() {
+            return None;
+        }
+        let key_hash = farmhash::fingerprint32(key.raw_ref()) as usize;
+        Some(self.hash_index[key_hash % self.hash_index.len()])
+    }
+
+    /// Decode a block written in `format`, converting it to the current layout.
+    pub fn decode_with_format(data: &[u8], format: BlockFormat) -> Self {
+        match format {
+            BlockFormat::V1 => {
+                Self::decode_legacy(data, BlockFormat::V1, |buf| buf.get_u16() as usize)
+            }
+            BlockFormat::V2 => Self::decode_legacy(data, BlockFormat::V2, get_varint),
+            BlockFormat::V3 | BlockFormat::V4 => Self::decode_with_restarts(data, format),
+        }
+    }
+
+    /// Decode a block whose entries all have an offset and keys prefix-compressed against the
+    /// first key, with offsets and lengths read by `get_len`, and rebuild it with restart points.
+    fn decode_legacy(
+        data: &[u8],
+        format: BlockFormat,
+        get_len: impl Fn(&mut &[u8]) -> usize,
+    ) -> Self {
+        let sizeof_offset = format.sizeof_offset();
+        let (entries, offsets_raw, _) = Self::split_encoded(data, format).expect("malformed block");
+        let mut builder = BlockBuilder::new(usize::MAX);
+        let mut first_key = Vec::new();
+        let mut key = Vec::new();
+        for mut offset_raw in offsets_raw.chunks(sizeof_offset) {
+            let mut entry = &entries[offset_raw.get_uint(sizeof_offset) as usize..];
+            let overlap_len = get_len(&mut entry);
+            let key_len = get_len(&mut entry);
+            key.clear();
+            key.extend_from_slice(&first_key[..overlap_len]);
+            key.extend_from_slice(&entry[..key_len]);
+            entry.advance(key_len);
+            let value_len = get_len(&mut entry);
+            assert!(builder.add(KeySlice::from_slice(&key), &entry[..value_len]));
+            if first_key.is_empty() {
+                first_key = key.clone();
+            }
+        }
+        builder.build()
     }
 }
+
+#[cfg(test)]
+mod tests;
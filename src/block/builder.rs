@@ -15,29 +15,44 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
-use super::{Block, SIZEOF_U16};
+use super::{
+    Block, HASH_COLLISION, HASH_NO_ENTRY, SIZEOF_U32, hash_index_buckets, put_varint, varint_len,
+};
 use crate::key::{KeySlice, KeyVec};
+use crate::value_type::ValueType;
 use bytes::BufMut;
 
+/// Entries between two restart points by default.
+pub const DEFAULT_RESTART_INTERVAL: usize = 16;
+
 /// Builds a block.
 pub struct BlockBuilder {
-    /// Offsets of each key-value entries.
-    offsets: Vec<u16>,
+    /// Offsets of the restart points, the entries whose key is stored in full.
+    offsets: Vec<u32>,
     /// All serialized key-value pairs in the block.
     data: Vec<u8>,
     /// The expected block size.
     block_size: usize,
-    /// The first key in the block
-    first_key: KeyVec,
+    /// The last key added, which the next key is prefix-compressed against.
+    last_key: KeyVec,
+    /// Entries between two restart points.
+    restart_interval: usize,
+    /// Entries added since the last restart point.
+    num_since_restart: usize,
+    /// Whether to append a hash index of the keys to the block.
+    hash_index: bool,
+    /// The hash of each key added and the index of the restart point it follows, for the hash
+    /// index.
+    key_hashes: Vec<(u32, usize)>,
 }
-// compute the overlap between the first key and the current key
-fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
+// compute the overlap between the previous key and the current key
+fn compute_overlap(last_key: KeySlice, key: KeySlice) -> usize {
     let mut i = 0;
     loop {
-        if i >= first_key.len() || i >= key.len() {
+        if i >= last_key.len() || i >= key.len() {
             break;
         }
-        if first_key.raw_ref()[i] != key.raw_ref()[i] {
+        if last_key.raw_ref()[i] != key.raw_ref()[i] {
             break;
         }
         i += 1;
@@ -52,39 +67,92 @@ impl BlockBuilder {
             offsets: Vec::new(),
             data: Vec::new(),
             block_size,
-            first_key: KeyVec::new(),
+            last_key: KeyVec::new(),
+            restart_interval: DEFAULT_RESTART_INTERVAL,
+            num_since_restart: 0,
+            hash_index: false,
+            key_hashes: Vec::new(),
         }
     }
+
+    /// Store a key in full every `restart_interval` entries. Seeks binary-search these restart
+    /// points and then scan at most `restart_interval` entries; a larger interval prefix-compresses
+    /// more keys.
+    pub fn with_restart_interval(mut self, restart_interval: usize) -> Self {
+        assert!(restart_interval > 0, "restart interval must be positive");
+        self.restart_interval = restart_interval;
+        self
+    }
+
+    /// Append a hash index to the block, mapping each key to its restart point so that a point
+    /// lookup does not binary-search the restart points. Blocks with more restart points than the
+    /// index can address are built without it.
+    pub fn with_hash_index(mut self, hash_index: bool) -> Self {
+        self.hash_index = hash_index;
+        self
+    }
+
     fn estimated_size(&self) -> usize {
-        SIZEOF_U16 /* number of key-value pairs in the block */ +  self.offsets.len() * SIZEOF_U16 /* offsets */ + self.data.len()
-        // key-value pairs
+        let hash_index_size = if self.hash_index {
+            hash_index_buckets(self.key_hashes.len()) + SIZEOF_U32 /* number of buckets */
+        } else {
+            0
+        };
+        SIZEOF_U32 /* number of restart points in the block */ +  self.offsets.len() * SIZEOF_U32 /* restart offsets */ + self.data.len() /* key-value pairs */ + hash_index_size
     }
-    /// Adds a key-value pair to the block. Returns false when the block is full.
+    /// Adds a key-value pair to the block, where an empty value is a tombstone. Returns false when
+    /// the block is full.
     #[must_use]
     pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
+        self.add_typed(key, ValueType::of_untyped(value), value)
+    }
+
+    /// Adds an entry of any type to the block. Returns false when the block is full.
+    #[must_use]
+    pub fn add_typed(&mut self, key: KeySlice, value_type: ValueType, value: &[u8]) -> bool {
         assert!(!key.is_empty(), "key must not be empty");
-        if self.estimated_size() + key.len() + value.len() + 3 * SIZEOF_U16/* key_len, value_len and offset */ > self.block_size
-            && !self.is_empty()
-        {
+        let is_restart = self.is_empty() || self.num_since_restart >= self.restart_interval;
+        // prefix store key, in full at the restart points
+        let overlap = if is_restart {
+            0
+        } else {
+            compute_overlap(self.last_key.as_key_slice(), key)
+        };
+        let entry_size = varint_len(overlap)
+            + varint_len(key.len() - overlap)
+            + key.len()
+            - overlap
+            + 1 /* value type */
+            + varint_len(value.len())
+            + value.len()
+            + if is_restart { SIZEOF_U32 } else { 0 } /* restart offset */;
+        if self.estimated_size() + entry_size > self.block_size && !self.is_empty() {
             return false;
         }
-        // add the offset
-        self.offsets.push(self.data.len() as u16);
-        // prefix store key
-        let overlap = compute_overlap(self.first_key.as_key_slice(), key);
+        if is_restart {
+            // add the restart offset
+            self.offsets.push(self.data.len() as u32);
+            self.num_since_restart = 0;
+        }
         // Encode key overlap
-        self.data.put_u16(overlap as u16);
+        put_varint(&mut self.data, overlap);
         // Encode key length
-        self.data.put_u16((key.len() - overlap) as u16);
+        put_varint(&mut self.data, key.len() - overlap);
         // Encode key content
         self.data.put(&key.raw_ref()[overlap..]);
+        // Encode value type
+        self.data.put_u8(value_type.to_u8());
         // Encode value length
-        self.data.put_u16(value.len() as u16);
+        put_varint(&mut self.data, value.len());
         // Encode value content
         self.data.put(value);
-        // Set the first key
-        if self.first_key.is_empty() {
-            self.first_key.set_from_slice(key);
+        self.last_key.set_from_slice(key);
+        self.num_since_restart += 1;
+        if self.hash_index {
+            self.key_hashes.push((
+                farmhash::fingerprint32(key.raw_ref()),
+                self.offsets.len() - 1,
+            ));
         }
         true
     }
@@ -99,9 +167,33 @@ impl BlockBuilder {
         if self.is_empty() {
             panic!("block should not be empty");
         }
+        let hash_index = self.build_hash_index();
         Block {
             data: self.data,
             offsets: self.offsets,
+            hash_index,
+            typed: true,
         }
     }
+
+    fn build_hash_index(&self) -> Vec<u8> {
+        // A bucket holds the index of a restart point below the reserved values.
+        if !self.hash_index || self.offsets.len() > HASH_COLLISION as usize {
+            return Vec::new();
+        }
+        let mut buckets = vec![HASH_NO_ENTRY; hash_index_buckets(self.key_hashes.len())];
+        let num_buckets = buckets.len();
+        for &(key_hash, restart_idx) in &self.key_hashes {
+            let bucket = &mut buckets[key_hash as usize % num_buckets];
+            if *bucket == HASH_NO_ENTRY || *bucket == restart_idx as u8 {
+                *bucket = restart_idx as u8;
+            } else {
+                *bucket = HASH_COLLISION;
+            }
+        }
+        buckets
+    }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use bytes::BufMut;
+use tempfile::tempdir;
+
+use crate::block::{Block, BlockBuilder, BlockIterator};
+use crate::iterators::StorageIterator;
+use crate::key::{KeySlice, KeyVec};
+use crate::lsm_storage::{DurabilityMode, LsmStorageInner, LsmStorageOptions};
+use crate::table::{
+    BlockMeta, CompressionType, FileObject, SsTable, SsTableBuilder, SsTableIterator,
+};
+use crate::test_harness::key_of;
+
+fn value_of(idx: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + idx) as u8).collect()
+}
+
+const VALUE_LENS: [usize; 6] = [10, 70_000, 1 << 20, 0, 65_535, 3 << 20];
+
+#[test]
+fn test_block_large_values() {
+    let mut builder = BlockBuilder::new(16 << 20);
+    for (idx, len) in VALUE_LENS.iter().enumerate() {
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx, *len)
+        ));
+    }
+    let block = builder.build();
+    let block = Arc::new(Block::decode(&block.encode()));
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for (idx, len) in VALUE_LENS.iter().enumerate() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx)[..]);
+        assert_eq!(iter.value(), &value_of(idx, *len)[..]);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+    let iter = BlockIterator::create_and_seek_to_key(
+        block,
+        KeySlice::for_testing_from_slice_no_ts(&key_of(2)),
+    );
+    assert_eq!(iter.value().len(), 1 << 20);
+}
+
+#[test]
+fn test_sst_large_values() {
+    let dir = tempdir().unwrap();
+    for compression in [CompressionType::None, CompressionType::Zstd] {
+        let path = dir.path().join(format!("{:?}.sst", compression));
+        let mut builder = SsTableBuilder::new(4096).with_compression(compression);
+        for (idx, len) in VALUE_LENS.iter().enumerate() {
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                &value_of(idx, *len),
+            );
+        }
+        drop(builder.build_for_test(&path).unwrap());
+        let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        for (idx, len) in VALUE_LENS.iter().enumerate() {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx)[..]);
+            assert_eq!(iter.value(), &value_of(idx, *len)[..]);
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+    }
+}
+
+/// Encode a block in the layout used before the format version, with u16 lengths and offsets.
+fn encode_v1_block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut offsets = Vec::new();
+    for (key, value) in entries {
+        offsets.push(buf.len() as u16);
+        buf.put_u16(0);
+        buf.put_u16(key.len() as u16);
+        buf.put_slice(key);
+        buf.put_u16(value.len() as u16);
+        buf.put_slice(value);
+    }
+    for offset in &offsets {
+        buf.put_u16(*offset);
+    }
+    buf.put_u16(offsets.len() as u16);
+    buf
+}
+
+#[test]
+fn test_open_v1_sst() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let blocks = [0..3, 3..5]
+        .map(|range| {
+            range
+                .map(|idx| (key_of(idx), value_of(idx, 100)))
+                .collect::<Vec<_>>()
+        })
+        .to_vec();
+    let mut buf = Vec::new();
+    let mut block_meta = Vec::new();
+    for entries in &blocks {
+        block_meta.push(BlockMeta {
+            offset: buf.len(),
+            first_key: KeyVec::for_testing_from_vec_no_ts(entries[0].0.clone()).into_key_bytes(),
+            last_key: KeyVec::for_testing_from_vec_no_ts(entries.last().unwrap().0.clone())
+                .into_key_bytes(),
+        });
+        let start = buf.len();
+        buf.extend(encode_v1_block(entries));
+        buf.put_u8(0); // codec
+        let checksum = crc32fast::hash(&buf[start..]);
+        buf.put_u32(checksum);
+    }
+    let meta_offset = buf.len();
+    BlockMeta::encode_block_meta(&block_meta, &mut buf);
+    let bloom_offset = buf.len();
+    buf.put_u32(meta_offset as u32);
+    buf.put_u32(bloom_offset as u32);
+    buf.put_u8(0); // full index
+    std::fs::write(&path, buf).unwrap();
+
+    let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+    assert_eq!(sst.num_of_blocks(), 2);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for (key, value) in blocks.iter().flatten() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), &key[..]);
+        assert_eq!(iter.value(), &value[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let iter = SsTableIterator::create_and_seek_to_key(
+        sst,
+        KeySlice::for_testing_from_slice_no_ts(&key_of(3)),
+    )
+    .unwrap();
+    assert_eq!(iter.value(), &value_of(3, 100)[..]);
+}
+
+#[test]
+fn test_wal_large_values() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    storage.put(b"k", &[b'v'; 100_000]).unwrap();
+    for (idx, len) in VALUE_LENS.iter().enumerate() {
+        storage.put(&key_of(idx), &value_of(idx, *len)).unwrap();
+    }
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    assert_eq!(
+        storage.get(b"k").unwrap().as_deref(),
+        Some(&[b'v'; 100_000][..])
+    );
+    for (idx, len) in VALUE_LENS.iter().enumerate() {
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().as_deref(),
+            Some(&value_of(idx, *len)[..])
+        );
+    }
+}
@@ -20,8 +20,9 @@ use std::sync::Arc;
 use bytes::Buf;
 
 use crate::key::{KeySlice, KeyVec};
+use crate::value_type::ValueType;
 
-use super::{Block, SIZEOF_U16};
+use super::{Block, HASH_COLLISION, HASH_NO_ENTRY, get_varint};
 
 /// Iterates on a block.
 pub struct BlockIterator {
@@ -31,32 +32,31 @@ pub struct BlockIterator {
     key: KeyVec,
     /// the current value range in the block.data, corresponds to the current key
     value_range: (usize, usize),
-    /// Current index of the key-value pair, should be in range of [0, num_of_elements)
-    idx: usize,
-    /// The first key in the block
-    first_key: KeyVec,
+    /// The type of the current entry.
+    value_type: ValueType,
+    /// The offset of the current entry in the block.data
+    offset: usize,
     /// Whether reverse iteration
     prev: bool,
 }
 
 impl Block {
-    // we always need first key to compute overlap and combine a entrie key
-    fn get_first_key(&self) -> KeyVec {
-        let mut buf = &self.data[..];
-        buf.get_u16();
-        let key_len = buf.get_u16();
-        let key = &buf[..key_len as usize];
-        KeyVec::from_vec(key.to_vec())
+    /// The key of the `idx`-th restart point, which is stored in full.
+    fn restart_key(&self, idx: usize) -> KeySlice<'_> {
+        let mut buf = &self.data[self.offsets[idx] as usize..];
+        get_varint(&mut buf);
+        let key_len = get_varint(&mut buf);
+        KeySlice::from_slice(&buf[..key_len])
     }
 }
 impl BlockIterator {
     fn new(block: Arc<Block>, prev: bool) -> Self {
         Self {
-            first_key: block.get_first_key(),
             block,
             key: KeyVec::new(),
             value_range: (0, 0),
-            idx: 0,
+            value_type: ValueType::Put,
+            offset: 0,
             prev,
         }
     }
@@ -68,18 +68,10 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a reverse block iterator and seek to the last entry.
     pub fn create_and_seek_to_last(block: Arc<Block>) -> Self {
-        let offset_len = block.offsets.len();
         let mut iter = Self::new(block, true);
-        // seek to the last entry in the block
-        if offset_len > 0 {
-            let last_idx = offset_len - 1;
-            iter.seek_to(last_idx);
-        } else {
-            // if there is no entry, just return an invalid iterator
-            iter.key.clear();
-            iter.value_range = (0, 0);
-        }
+        iter.seek_to_last();
         iter
     }
 
@@ -90,8 +82,24 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterator at `key` for a point lookup, through the hash index of the block if
+    /// it has one. Unlike `create_and_seek_to_key`, the iterator is invalid unless the block
+    /// contains `key`.
+    pub fn create_and_lookup_key(block: Arc<Block>, key: KeySlice) -> Self {
+        let mut iter = Self::new(block, false);
+        iter.lookup_key(key);
+        iter
+    }
+
+    /// Creates a reverse block iterator and seek to the last key that <= `key`.
+    pub fn create_and_seek_for_prev(block: Arc<Block>, key: KeySlice) -> Self {
+        let mut iter = Self::new(block, true);
+        iter.seek_for_prev(key);
+        iter
+    }
+
     /// Returns the key of the current entry.
-    pub fn key(&self) -> KeySlice {
+    pub fn key(&self) -> KeySlice<'_> {
         debug_assert!(!self.key.is_empty(), "invalid iterator");
         self.key.as_key_slice()
     }
@@ -104,6 +112,12 @@ impl BlockIterator {
         &self.block.data[start..end]
     }
 
+    /// Returns the type of the current entry.
+    pub fn value_type(&self) -> ValueType {
+        debug_assert!(!self.key.is_empty(), "invalid iterator");
+        self.value_type
+    }
+
     /// Returns true if the iterator is valid.
     /// Note: You may want to make use of `key`
     pub fn is_valid(&self) -> bool {
@@ -112,89 +126,163 @@ impl BlockIterator {
 
     /// Seeks to the first key in the block.
     pub fn seek_to_first(&mut self) {
-        self.seek_to(0);
+        self.seek_to_restart(0);
     }
 
+    /// Seeks to the last key in the block, scanning forward from the last restart point.
     pub fn seek_to_last(&mut self) {
-        let offset_len = self.block.offsets.len();
-        if offset_len == 0 {
+        if self.block.offsets.is_empty() {
             // if there is no entry, just return an invalid iterator
-            self.key.clear();
-            self.value_range = (0, 0);
+            self.invalidate();
             return;
         }
-        let last_idx = offset_len - 1;
-        self.seek_to(last_idx);
+        self.seek_to_restart(self.block.offsets.len() - 1);
+        while self.value_range.1 < self.block.data.len() {
+            self.next_entry();
+        }
     }
 
     /// Move to the next key in the block.
     pub fn next(&mut self) {
         if self.prev {
-            println!("Before next, idx: {}, prev: true", self.idx);
-            if self.idx == 0 {
-                // if we are at the first element, we can't go back anymore
-                self.key.clear();
-                self.value_range = (0, 0);
-                return;
-            }
-            self.idx -= 1;
-            println!("After next, idx: {}, prev: true", self.idx);
-            self.seek_to(self.idx);
+            self.prev_entry();
+        } else {
+            self.next_entry();
+        }
+    }
+
+    /// Move to the entry following the current one.
+    fn next_entry(&mut self) {
+        let offset = self.value_range.1;
+        if offset >= self.block.data.len() {
+            self.invalidate();
+            return;
+        }
+        self.decode_entry(offset);
+    }
+
+    /// Move to the entry preceding the current one, by scanning forward from the last restart
+    /// point before it.
+    fn prev_entry(&mut self) {
+        let target = self.offset;
+        if target == 0 {
+            // if we are at the first element, we can't go back anymore
+            self.invalidate();
             return;
         }
-        self.idx += 1;
-        self.seek_to(self.idx);
+        let restart_idx = self
+            .block
+            .offsets
+            .partition_point(|&offset| (offset as usize) < target)
+            - 1;
+        self.seek_to_restart(restart_idx);
+        while self.value_range.1 < target {
+            self.next_entry();
+        }
     }
-    /// Seeks to the idx-th key in the block.
-    fn seek_to(&mut self, idx: usize) {
+
+    /// Seeks to the `idx`-th restart point.
+    fn seek_to_restart(&mut self, idx: usize) {
         // reach the end of block, return false
         if idx >= self.block.offsets.len() {
-            self.key.clear();
-            self.value_range = (0, 0);
+            self.invalidate();
             return;
         }
-
-        let offset = self.block.offsets[idx] as usize;
-        self.seek_to_offset(offset);
-        self.idx = idx;
+        self.decode_entry(self.block.offsets[idx] as usize);
     }
 
-    fn seek_to_offset(&mut self, offset: usize) {
+    /// Decode the entry at `offset`, whose key shares a prefix with the current key.
+    fn decode_entry(&mut self, offset: usize) {
         let mut entry = &self.block.data[offset..];
-        // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
+        // Since `get_varint()` will automatically move the ptr ahead here,
         // we don't need to manually advance it
-        let overlap_len = entry.get_u16() as usize;
-        let key_len = entry.get_u16() as usize;
-        let key = &entry[..key_len];
-        self.key.clear();
-        // combine overlap first key and unique key
-        self.key.append(&self.first_key.raw_ref()[..overlap_len]);
-        self.key.append(key);
+        let overlap_len = get_varint(&mut entry);
+        let key_len = get_varint(&mut entry);
+        // combine the overlap with the previous key and the unique key
+        let mut key = std::mem::take(&mut self.key).into_inner();
+        key.truncate(overlap_len);
+        key.extend_from_slice(&entry[..key_len]);
+        self.key = KeyVec::from_vec(key);
 
         entry.advance(key_len);
-        let value_len = entry.get_u16() as usize;
-        let value_offset_begin = offset + SIZEOF_U16/* overlap len */ + SIZEOF_U16/* key len */ + key_len + SIZEOF_U16/* value len */;
+        let value_type = self
+            .block
+            .typed
+            .then(|| ValueType::from_u8(entry.get_u8()).expect("malformed block"));
+        let value_len = get_varint(&mut entry);
+        let value_offset_begin = self.block.data.len() - entry.len();
         let value_offset_end = value_offset_begin + value_len;
         self.value_range = (value_offset_begin, value_offset_end);
-        entry.advance(value_len);
+        self.value_type = value_type.unwrap_or_else(|| ValueType::of_untyped(self.value()));
+        self.offset = offset;
+    }
+
+    fn invalidate(&mut self) {
+        self.key.clear();
+        self.value_range = (0, 0);
     }
 
     /// Seek to the first key that >= `key`.
     /// Note: You should assume the key-value pairs in the block are sorted when being added by
     /// callers.
     pub fn seek_to_key(&mut self, key: KeySlice) {
+        // binary search the last restart point <= `key`, then scan forward from it
         let mut low = 0;
         let mut high = self.block.offsets.len();
         while low < high {
             let mid = (low + high) / 2;
-            self.seek_to(mid);
-            assert!(self.is_valid());
-            match self.key().cmp(&key) {
-                std::cmp::Ordering::Less => low = mid + 1,
-                std::cmp::Ordering::Equal => return,
-                std::cmp::Ordering::Greater => high = mid,
+            if self.block.restart_key(mid) <= key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let restart_idx = low.saturating_sub(1);
+        self.seek_to_restart(restart_idx);
+        while self.is_valid() && self.key() < key {
+            self.next_entry();
+        }
+    }
+
+    /// Seek to `key`, or invalidate the iterator if the block does not contain it.
+    fn lookup_key(&mut self, key: KeySlice) {
+        match self.block.hash_bucket(key) {
+            Some(HASH_NO_ENTRY) => {
+                self.invalidate();
+                return;
+            }
+            Some(restart_idx) if restart_idx != HASH_COLLISION => {
+                // the key can only be in the restart interval the bucket points to
+                let restart_idx = restart_idx as usize;
+                let end = self
+                    .block
+                    .offsets
+                    .get(restart_idx + 1)
+                    .map_or(self.block.data.len(), |&offset| offset as usize);
+                self.seek_to_restart(restart_idx);
+                while self.is_valid() && self.key() < key && self.value_range.1 < end {
+                    self.next_entry();
+                }
             }
+            // no hash index, or keys of several restart intervals in the bucket
+            _ => self.seek_to_key(key),
+        }
+        if self.is_valid() && self.key() != key {
+            self.invalidate();
+        }
+    }
+
+    /// Seek to the last key that <= `key`. Only meaningful for a reverse iterator, as `next`
+    /// should move on to smaller keys.
+    pub fn seek_for_prev(&mut self, key: KeySlice) {
+        self.seek_to_key(key);
+        if !self.is_valid() {
+            self.seek_to_last();
+        } else if self.key() > key {
+            self.prev_entry();
         }
-        self.seek_to(low);
     }
 }
+
+#[cfg(test)]
+mod tests;
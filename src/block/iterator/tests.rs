@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::block::{Block, BlockBuilder, BlockIterator};
+use crate::key::KeySlice;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions};
+use crate::table::{FileObject, SsTable, SsTableBuilder};
+use crate::test_harness::value_of;
+use crate::value_type::ValueType;
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:05}", idx * 2).into_bytes()
+}
+
+fn generate_block(num: usize, restart_interval: usize, hash_index: bool) -> Arc<Block> {
+    let mut builder = BlockBuilder::new(1 << 20)
+        .with_restart_interval(restart_interval)
+        .with_hash_index(hash_index);
+    for idx in 0..num {
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx)
+        ));
+    }
+    Arc::new(Block::decode(&builder.build().encode()))
+}
+
+/// Look up every key of the block and the keys between them.
+fn check_lookups(block: Arc<Block>, num: usize) {
+    for target in 0..num * 2 + 1 {
+        let key = format!("key_{:05}", target).into_bytes();
+        let iter = BlockIterator::create_and_lookup_key(
+            block.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key),
+        );
+        if target % 2 == 0 && target < num * 2 {
+            assert!(iter.is_valid(), "key {} not found", target);
+            assert_eq!(iter.value(), &value_of(target / 2)[..]);
+        } else {
+            assert!(!iter.is_valid(), "absent key {} found", target);
+        }
+    }
+}
+
+#[test]
+fn test_block_hash_index_lookup() {
+    let num = 200;
+    for restart_interval in [1, 4, 16, 1000] {
+        let block = generate_block(num, restart_interval, true);
+        assert!(!block.hash_index.is_empty());
+        check_lookups(block.clone(), num);
+        // The hash index does not change ordered iteration.
+        let mut iter = BlockIterator::create_and_seek_to_first(block);
+        for idx in 0..num {
+            assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx)[..]);
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+    }
+    let without_index = generate_block(num, 16, false);
+    assert!(without_index.hash_index.is_empty());
+    check_lookups(without_index.clone(), num);
+    assert!(generate_block(num, 16, true).encode().len() > without_index.encode().len());
+}
+
+#[test]
+fn test_block_hash_index_too_many_restarts() {
+    // A bucket cannot address 300 restart points, so the block falls back to binary search.
+    let block = generate_block(300, 1, true);
+    assert!(block.hash_index.is_empty());
+    check_lookups(block, 300);
+}
+
+#[test]
+fn test_sst_get_with_hash_index() {
+    let dir = tempdir().unwrap();
+    let num = 1000;
+    for hash_index in [false, true] {
+        let path = dir.path().join(format!("{}.sst", hash_index));
+        let mut builder = SsTableBuilder::new(256).with_block_hash_index(hash_index);
+        for idx in 0..num {
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+                &value_of(idx),
+            );
+        }
+        drop(builder.build_for_test(&path).unwrap());
+        let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+        assert!(sst.num_of_blocks() > 10);
+        assert_eq!(
+            sst.read_block(0).unwrap().hash_index.is_empty(),
+            !hash_index
+        );
+        for target in 0..num * 2 + 1 {
+            let key = format!("key_{:05}", target).into_bytes();
+            let value = sst
+                .get(KeySlice::for_testing_from_slice_no_ts(&key))
+                .unwrap();
+            if target % 2 == 0 && target < num * 2 {
+                let (value_type, value) = value.unwrap();
+                assert_eq!(value_type, ValueType::Put);
+                assert_eq!(&value[..], &value_of(target / 2)[..]);
+            } else {
+                assert_eq!(value, None);
+            }
+        }
+        assert_eq!(
+            sst.get(KeySlice::for_testing_from_slice_no_ts(b"a"))
+                .unwrap(),
+            None
+        );
+    }
+}
+
+#[test]
+fn test_storage_get_with_hash_index() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 256;
+    options.block_hash_index = true;
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    let mut builder = storage.new_sst_builder();
+    for idx in 0..500 {
+        let value = if idx % 5 == 0 {
+            Vec::new()
+        } else {
+            value_of(idx)
+        };
+        builder.add(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)), &value);
+    }
+    let sst_id = storage.next_sst_id();
+    let sst = builder
+        .build(
+            sst_id,
+            Some(storage.block_cache.clone()),
+            storage.path_of_sst(sst_id),
+        )
+        .unwrap();
+    assert!(!sst.read_block(0).unwrap().hash_index.is_empty());
+    {
+        let mut guard = storage.state.write();
+        let mut snapshot = guard.as_ref().clone();
+        snapshot.l0_sstables.insert(0, sst_id);
+        snapshot.sstables.insert(sst_id, Arc::new(sst));
+        *guard = Arc::new(snapshot);
+    }
+    for idx in 0..500 {
+        let value = storage.get(&key_of(idx)).unwrap();
+        if idx % 5 == 0 {
+            assert_eq!(value, None);
+        } else {
+            assert_eq!(value.as_deref(), Some(&value_of(idx)[..]));
+        }
+        assert_eq!(
+            storage
+                .get(format!("key_{:05}", idx * 2 + 1).as_bytes())
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::Arc;
+
+use bytes::BufMut;
+use tempfile::tempdir;
+
+use crate::block::{Block, BlockBuilder, BlockIterator};
+use crate::iterators::StorageIterator;
+use crate::key::{KeySlice, KeyVec};
+use crate::table::{BlockMeta, FileObject, SsTable, SsTableIterator};
+use crate::test_harness::value_of;
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("user_profile_{:05}", idx * 2).into_bytes()
+}
+
+fn generate_block(num: usize, restart_interval: usize) -> Arc<Block> {
+    let mut builder = BlockBuilder::new(1 << 20).with_restart_interval(restart_interval);
+    for idx in 0..num {
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+            &value_of(idx)
+        ));
+    }
+    Arc::new(Block::decode(&builder.build().encode()))
+}
+
+#[test]
+fn test_restarts_shrink_block() {
+    let full_keys = generate_block(1000, 1).encode().len();
+    let with_restarts = generate_block(1000, 16).encode().len();
+    assert!(
+        with_restarts * 10 < full_keys * 7,
+        "restart points only shrink the block from {} to {} bytes",
+        full_keys,
+        with_restarts
+    );
+}
+
+#[test]
+fn test_block_restarts_iterate_and_seek() {
+    let num = 100;
+    for restart_interval in [1, 2, 7, 16, 1000] {
+        let block = generate_block(num, restart_interval);
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        for idx in 0..num {
+            assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx)[..]);
+            assert_eq!(iter.value(), &value_of(idx)[..]);
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+
+        let mut iter = BlockIterator::create_and_seek_to_last(block.clone());
+        for idx in (0..num).rev() {
+            assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx)[..]);
+            assert_eq!(iter.value(), &value_of(idx)[..]);
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+
+        // Seek to every key, and between keys.
+        for target in 0..num * 2 + 1 {
+            let key = format!("user_profile_{:05}", target).into_bytes();
+            let key = KeySlice::for_testing_from_slice_no_ts(&key);
+            let mut iter = BlockIterator::create_and_seek_to_key(block.clone(), key);
+            if target < num * 2 - 1 {
+                assert_eq!(
+                    iter.key().for_testing_key_ref(),
+                    &key_of(target.div_ceil(2))[..]
+                );
+                iter.next();
+                if target.div_ceil(2) + 1 < num {
+                    assert_eq!(
+                        iter.key().for_testing_key_ref(),
+                        &key_of(target.div_ceil(2) + 1)[..]
+                    );
+                }
+            } else {
+                assert!(!iter.is_valid());
+            }
+            let mut iter = BlockIterator::create_and_seek_for_prev(block.clone(), key);
+            let idx = (target / 2).min(num - 1);
+            assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx)[..]);
+            iter.next();
+            if idx > 0 {
+                assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx - 1)[..]);
+            } else {
+                assert!(!iter.is_valid());
+            }
+        }
+        let iter = BlockIterator::create_and_seek_to_key(
+            block.clone(),
+            KeySlice::for_testing_from_slice_no_ts(b"a"),
+        );
+        assert_eq!(iter.key().for_testing_key_ref(), &key_of(0)[..]);
+    }
+}
+
+/// Encode a block in the layout without restart points: every entry has an offset and keys are
+/// prefix-compressed against the first key.
+fn encode_v2_block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut offsets = Vec::new();
+    let first_key = &entries[0].0;
+    for (idx, (key, value)) in entries.iter().enumerate() {
+        offsets.push(buf.len() as u32);
+        let overlap = if idx == 0 {
+            0
+        } else {
+            key.iter()
+                .zip(first_key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        buf.put_u8(overlap as u8);
+        buf.put_u8((key.len() - overlap) as u8);
+        buf.put_slice(&key[overlap..]);
+        buf.put_u8(value.len() as u8);
+        buf.put_slice(value);
+    }
+    for offset in &offsets {
+        buf.put_u32(*offset);
+    }
+    buf.put_u32(offsets.len() as u32);
+    buf
+}
+
+#[test]
+fn test_open_v2_sst() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let blocks = [0..40, 40..50]
+        .map(|range| {
+            range
+                .map(|idx| (key_of(idx), value_of(idx)))
+                .collect::<Vec<_>>()
+        })
+        .to_vec();
+    let mut buf = Vec::new();
+    let mut block_meta = Vec::new();
+    for entries in &blocks {
+        block_meta.push(BlockMeta {
+            offset: buf.len(),
+            first_key: KeyVec::for_testing_from_vec_no_ts(entries[0].0.clone()).into_key_bytes(),
+            last_key: KeyVec::for_testing_from_vec_no_ts(entries.last().unwrap().0.clone())
+                .into_key_bytes(),
+        });
+        let start = buf.len();
+        buf.extend(encode_v2_block(entries));
+        buf.put_u8(0); // codec
+        let checksum = crc32fast::hash(&buf[start..]);
+        buf.put_u32(checksum);
+    }
+    let meta_offset = buf.len();
+    BlockMeta::encode_block_meta(&block_meta, &mut buf);
+    let bloom_offset = buf.len();
+    buf.put_u32(meta_offset as u32);
+    buf.put_u32(bloom_offset as u32);
+    buf.put_u8(0); // full index
+    buf.put_u8(2); // format version
+    std::fs::write(&path, buf).unwrap();
+
+    let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for (key, value) in blocks.iter().flatten() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), &key[..]);
+        assert_eq!(iter.value(), &value[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    for idx in [0, 17, 39, 45] {
+        let iter = SsTableIterator::create_and_seek_to_key(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+        )
+        .unwrap();
+        assert_eq!(iter.value(), &value_of(idx)[..]);
+    }
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst).unwrap();
+    for (key, _) in blocks.iter().flatten().rev() {
+        assert_eq!(iter.key().for_testing_key_ref(), &key[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
@@ -22,7 +22,8 @@ mod tiered;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Result, ensure};
+use bytes::Bytes;
 pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
 use serde::{Deserialize, Serialize};
 pub use simple_leveled::{
@@ -30,8 +31,15 @@ pub use simple_leveled::{
 };
 pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
 
-use crate::lsm_storage::{LsmStorageInner, LsmStorageState};
-use crate::table::SsTable;
+use crate::iterators::StorageIterator;
+use crate::iterators::merge_iterator::MergeIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::{DurabilityMode, LsmStorageInner, LsmStorageState};
+use crate::manifest::ManifestRecord;
+use crate::merge_operator::{MergeOperator, merge_entries};
+use crate::range_tombstone::{RangeTombstone, RangeTombstoneFilter};
+use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
+use crate::value_type::ValueType;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CompactionTask {
@@ -53,6 +61,102 @@ impl CompactionTask {
             CompactionTask::Tiered(task) => task.bottom_tier_included,
         }
     }
+
+    /// The SSTs compacted by the task, from the newest.
+    fn input_sst_ids(&self) -> Vec<usize> {
+        match self {
+            CompactionTask::ForceFullCompaction {
+                l0_sstables,
+                l1_sstables,
+            } => l0_sstables.iter().chain(l1_sstables).copied().collect(),
+            CompactionTask::Leveled(task) => task
+                .upper_level_sst_ids
+                .iter()
+                .chain(&task.lower_level_sst_ids)
+                .copied()
+                .collect(),
+            CompactionTask::Simple(task) => task
+                .upper_level_sst_ids
+                .iter()
+                .chain(&task.lower_level_sst_ids)
+                .copied()
+                .collect(),
+            CompactionTask::Tiered(task) => task
+                .tiers
+                .iter()
+                .flat_map(|(_, ssts)| ssts)
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// The level the output SSTs are written to, which picks their compression.
+    fn output_level(&self) -> usize {
+        match self {
+            CompactionTask::ForceFullCompaction { .. } | CompactionTask::Tiered(_) => 1,
+            CompactionTask::Leveled(task) => task.lower_level,
+            CompactionTask::Simple(task) => task.lower_level,
+        }
+    }
+
+    /// Whether the range tombstones of the compacted SSTs must be written to the output. Once they
+    /// reach the bottom level, there is nothing older left for them to hide. Until keys carry
+    /// timestamps, no snapshot below the watermark can still read the keys they deleted.
+    fn keep_range_tombstones(&self) -> bool {
+        !self.compact_to_bottom_level()
+    }
+
+    /// The entry to write to the output for a key, given its entries in the compacted SSTs from the
+    /// newest, or `None` to drop the key. Merge operands are combined with the put or tombstone
+    /// below them into a put, and with each other otherwise, so that a single operand is written;
+    /// at the bottom level there is nothing older for them to apply to, and they are turned into a
+    /// put as well. A single delete is dropped together with the put below it, and a tombstone is
+    /// dropped at the bottom level, where it has nothing left to hide. An expired put is dropped
+    /// at the bottom level as well, or replaced by a tombstone above it.
+    ///
+    /// A single delete that meets anything but the single put of its key is misused: it is
+    /// reported, and handled like a delete.
+    pub(crate) fn compact_entries(
+        &self,
+        merge_operator: Option<&dyn MergeOperator>,
+        key: &[u8],
+        entries: &[(ValueType, Bytes)],
+    ) -> Result<Option<(ValueType, Bytes)>> {
+        if let [(ValueType::SingleDelete, _), older @ ..] = entries {
+            match older {
+                [] => {}
+                [(ValueType::Put | ValueType::PutWithExpiry, _)] => return Ok(None),
+                _ => {
+                    let older_types = older
+                        .iter()
+                        .map(|(value_type, _)| *value_type)
+                        .collect::<Vec<_>>();
+                    eprintln!(
+                        "single delete of {:?} over {:?} instead of a single put, handled as a delete",
+                        key, older_types
+                    );
+                    return self.compact_entries(
+                        merge_operator,
+                        key,
+                        &[(ValueType::Delete, Bytes::new())],
+                    );
+                }
+            }
+        }
+        let (value_type, value) =
+            merge_entries(merge_operator, key, entries, self.compact_to_bottom_level())?;
+        if !value_type.hides_key(&value) {
+            return Ok(Some((value_type, value)));
+        }
+        // An expired put still hides the older versions of the key, unless there are none left.
+        if self.compact_to_bottom_level() {
+            Ok(None)
+        } else if value_type.is_tombstone() {
+            Ok(Some((value_type, value)))
+        } else {
+            Ok(Some((ValueType::Delete, Bytes::new())))
+        }
+    }
 }
 
 pub(crate) enum CompactionController {
@@ -95,6 +199,21 @@ impl CompactionController {
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
+            (
+                _,
+                CompactionTask::ForceFullCompaction {
+                    l0_sstables,
+                    l1_sstables,
+                },
+            ) => {
+                let mut snapshot = snapshot.clone();
+                // L0 SSTs flushed during the compaction are kept.
+                snapshot.l0_sstables.retain(|id| !l0_sstables.contains(id));
+                assert_eq!(&snapshot.levels[0].1, l1_sstables);
+                snapshot.levels[0].1 = output.to_vec();
+                let removed = l0_sstables.iter().chain(l1_sstables).copied().collect();
+                (snapshot, removed)
+            }
             _ => unreachable!(),
         }
     }
@@ -123,16 +242,209 @@ pub enum CompactionOptions {
 }
 
 impl LsmStorageInner {
-    fn compact(&self, _task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
-        unimplemented!()
+    /// Merge the SSTs of `task` into new SSTs of at most about `target_sst_size` bytes. The entries
+    /// of each key are combined by `CompactionTask::compact_entries`. The range tombstones of the
+    /// input drop the older entries they cover, and are written to the output unless it is the
+    /// bottom level, each clipped to the key range of the output SST it is written to.
+    pub(crate) fn compact(&self, task: &CompactionTask) -> Result<Vec<Arc<SsTable>>> {
+        let value_log = self.pin_value_log();
+        let snapshot = self.state.read().clone();
+        let tables = task
+            .input_sst_ids()
+            .iter()
+            .map(|id| snapshot.sstables[id].clone())
+            .collect::<Vec<_>>();
+        let tombstones = Arc::new(
+            tables
+                .iter()
+                .flat_map(|table| table.range_tombstones().iter().cloned())
+                .collect::<Vec<_>>(),
+        );
+        let mut iters = Vec::with_capacity(tables.len());
+        let mut num_newer = 0;
+        for table in &tables {
+            iters.push(Box::new(RangeTombstoneFilter::create(
+                SsTableIterator::create_and_seek_to_first(table.clone())?,
+                tombstones.clone(),
+                num_newer,
+                false,
+            )?));
+            num_newer += table.range_tombstones().len();
+        }
+        let mut iter = MergeIterator::create_keep_duplicates(iters);
+
+        let merge_operator = self.options.merge_operator.as_deref();
+        let value_log = value_log.as_deref();
+        // The builders of the output, with the first key of each.
+        let mut builders: Vec<(SsTableBuilder, Bytes)> = Vec::new();
+        let mut current: Option<(SsTableBuilder, Bytes)> = None;
+        while iter.is_valid() {
+            let key = Bytes::copy_from_slice(iter.key().raw_ref());
+            let mut entries = Vec::new();
+            while iter.is_valid() && iter.key().raw_ref() == key {
+                entries.push((iter.value_type(), Bytes::copy_from_slice(iter.value())));
+                iter.next()?;
+            }
+            // Merge operands apply to the values written by the user, the others can be kept as
+            // stored. Only the operands and the value under them are read: the versions shadowed
+            // by that value may point into files the value log has already collected.
+            let resolved_by = value_log.filter(|_| {
+                entries
+                    .iter()
+                    .any(|(value_type, _)| *value_type == ValueType::Merge)
+            });
+            if let Some(value_log) = resolved_by {
+                for (value_type, value) in &mut entries {
+                    *value = value_log.resolve_entry(*value_type, value.clone())?;
+                    if *value_type != ValueType::Merge {
+                        break;
+                    }
+                }
+            }
+            let Some((value_type, mut value)) =
+                task.compact_entries(merge_operator, &key, &entries)?
+            else {
+                continue;
+            };
+            if let Some(value_log) = resolved_by {
+                let stored = value_log.separate(&[(&key, value_type, &value)], false)?;
+                value = stored.into_iter().next().unwrap().into();
+            }
+            let (builder, _) = current.get_or_insert_with(|| {
+                (
+                    self.new_sst_builder_for_level(task.output_level()),
+                    key.clone(),
+                )
+            });
+            builder.add_typed(KeySlice::from_slice(&key), value_type, &value);
+            if builder.estimated_size() >= self.options.target_sst_size {
+                builders.extend(current.take());
+            }
+        }
+        builders.extend(current.take());
+
+        if task.keep_range_tombstones() && !tombstones.is_empty() {
+            if builders.is_empty() {
+                builders.push((
+                    self.new_sst_builder_for_level(task.output_level()),
+                    Bytes::new(),
+                ));
+            }
+            // Each output SST covers the keys from its first key up to the first key of the next
+            // one, the first and last SSTs extending to the key range of the input, which covers
+            // its tombstones. The key range of each SST is extended to the tombstones clipped to
+            // it, so that the output SSTs do not overlap.
+            let input_start = tables
+                .iter()
+                .map(|table| table.first_key().raw_ref())
+                .min()
+                .unwrap();
+            let input_end = tables
+                .iter()
+                .map(|table| table.last_key().raw_ref())
+                .max()
+                .unwrap();
+            let lower_bounds = std::iter::once(Bytes::copy_from_slice(input_start))
+                .chain(
+                    builders
+                        .iter()
+                        .skip(1)
+                        .map(|(_, first_key)| first_key.clone()),
+                )
+                .collect::<Vec<_>>();
+            let upper_bounds = builders
+                .iter()
+                .skip(1)
+                .map(|(_, first_key)| first_key.clone())
+                .chain(std::iter::once(Bytes::copy_from_slice(input_end)))
+                .collect::<Vec<_>>();
+            for (((builder, _), lower), upper) in
+                builders.iter_mut().zip(lower_bounds).zip(upper_bounds)
+            {
+                for tombstone in tombstones.iter() {
+                    let start = tombstone.start.clone().max(lower.clone());
+                    let end = tombstone.end.clone().min(upper.clone());
+                    if start < end {
+                        builder.add_range_tombstone(RangeTombstone::new(&start, &end));
+                    }
+                }
+            }
+        }
+
+        let mut output = Vec::with_capacity(builders.len());
+        for (builder, _) in builders {
+            let id = self.next_sst_id();
+            output.push(Arc::new(builder.build(
+                id,
+                Some(self.block_cache.clone()),
+                self.path_of_sst(id),
+            )?));
+        }
+        Ok(output)
     }
 
     pub fn force_full_compaction(&self) -> Result<()> {
-        unimplemented!()
+        let snapshot = self.state.read().clone();
+        ensure!(
+            !matches!(self.compaction_controller, CompactionController::Tiered(_)),
+            "force full compaction is not supported with tiered compaction"
+        );
+        ensure!(
+            snapshot.levels[1..].iter().all(|(_, ssts)| ssts.is_empty()),
+            "force full compaction only merges L0 into L1, which must be the last non-empty level"
+        );
+        let task = CompactionTask::ForceFullCompaction {
+            l0_sstables: snapshot.l0_sstables.clone(),
+            l1_sstables: snapshot.levels[0].1.clone(),
+        };
+        let output = self.compact(&task)?;
+        self.apply_compaction(task, output)
     }
 
     fn trigger_compaction(&self) -> Result<()> {
-        unimplemented!()
+        let snapshot = self.state.read().clone();
+        let Some(task) = self
+            .compaction_controller
+            .generate_compaction_task(&snapshot)
+        else {
+            return Ok(());
+        };
+        let output = self.compact(&task)?;
+        self.apply_compaction(task, output)
+    }
+
+    /// Replace the input SSTs of `task` with `output` in the state, record it in the manifest, and
+    /// delete the input files.
+    fn apply_compaction(&self, task: CompactionTask, output: Vec<Arc<SsTable>>) -> Result<()> {
+        let output_ids = output.iter().map(|sst| sst.sst_id()).collect::<Vec<_>>();
+        // The manifest must not refer to SSTs that may be lost.
+        self.sync_dir()?;
+        let removed = {
+            let state_lock = self.state_lock.lock();
+            let mut snapshot = self.state.read().as_ref().clone();
+            for sst in output {
+                snapshot.sstables.insert(sst.sst_id(), sst);
+            }
+            let (mut snapshot, removed) = self.compaction_controller.apply_compaction_result(
+                &snapshot,
+                &task,
+                &output_ids,
+                false,
+            );
+            for id in &removed {
+                snapshot.sstables.remove(id);
+            }
+            if let Some(manifest) = &self.manifest {
+                manifest.add_record(&state_lock, ManifestRecord::Compaction(task, output_ids))?;
+            }
+            *self.state.write() = Arc::new(snapshot);
+            removed
+        };
+        // Readers holding an older state keep the files open.
+        for id in removed {
+            std::fs::remove_file(self.path_of_sst(id))?;
+        }
+        self.sync_dir()
     }
 
     pub(crate) fn spawn_compaction_thread(
@@ -160,7 +472,33 @@ impl LsmStorageInner {
         Ok(None)
     }
 
+    pub(crate) fn spawn_wal_sync_thread(
+        self: &Arc<Self>,
+        rx: crossbeam_channel::Receiver<()>,
+    ) -> Result<Option<std::thread::JoinHandle<()>>> {
+        if let DurabilityMode::SyncInterval(interval) = self.options.durability {
+            let this = self.clone();
+            let handle = std::thread::spawn(move || {
+                let ticker = crossbeam_channel::tick(interval);
+                loop {
+                    crossbeam_channel::select! {
+                        recv(ticker) -> _ => if let Err(e) = this.sync() {
+                            eprintln!("WAL sync failed: {}", e);
+                        },
+                        recv(rx) -> _ => return
+                    }
+                }
+            });
+            return Ok(Some(handle));
+        }
+        Ok(None)
+    }
+
     fn trigger_flush(&self) -> Result<()> {
+        let num_imm_memtables = self.state.read().imm_memtables.len();
+        if num_imm_memtables >= self.options.num_memtable_limit {
+            self.force_flush_next_imm_memtable()?;
+        }
         Ok(())
     }
 
@@ -16,6 +16,8 @@ pub mod concat_iterator;
 pub mod merge_iterator;
 pub mod two_merge_iterator;
 
+use crate::value_type::ValueType;
+
 pub trait StorageIterator {
     type KeyType<'a>: PartialEq + Eq + PartialOrd + Ord
     where
@@ -27,6 +29,12 @@ pub trait StorageIterator {
     /// Get the current key.
     fn key(&self) -> Self::KeyType<'_>;
 
+    /// Get the type of the current entry. Iterators over entries without a type treat an empty
+    /// value as a tombstone.
+    fn value_type(&self) -> ValueType {
+        ValueType::of_untyped(self.value())
+    }
+
     /// Check if the current iterator is valid.
     fn is_valid(&self) -> bool;
 
@@ -38,3 +46,22 @@ pub trait StorageIterator {
         1
     }
 }
+
+/// An iterator that can be repositioned without being recreated. After `seek_to_first` or `seek`,
+/// `next` moves to larger keys; after `seek_to_last` or `seek_for_prev`, to smaller keys.
+pub trait SeekableIterator: StorageIterator {
+    /// Move to the first entry.
+    fn seek_to_first(&mut self) -> anyhow::Result<()>;
+
+    /// Move to the first entry with a key >= `key`.
+    fn seek(&mut self, key: &[u8]) -> anyhow::Result<()>;
+
+    /// Move to the last entry.
+    fn seek_to_last(&mut self) -> anyhow::Result<()>;
+
+    /// Move to the last entry with a key <= `key`.
+    fn seek_for_prev(&mut self, key: &[u8]) -> anyhow::Result<()>;
+}
+
+#[cfg(test)]
+mod tests;
@@ -23,10 +23,12 @@ use anyhow::Result;
 // use rand::seq::index;
 
 use crate::key::KeySlice;
+use crate::value_type::ValueType;
 
-use super::StorageIterator;
+use super::{SeekableIterator, StorageIterator};
 
-struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>);
+/// An iterator with its index, and whether the merge is in descending key order.
+struct HeapWrapper<I: StorageIterator>(pub usize, pub Box<I>, pub bool);
 
 impl<I: StorageIterator> PartialEq for HeapWrapper<I> {
     fn eq(&self, other: &Self) -> bool {
@@ -44,54 +46,91 @@ impl<I: StorageIterator> PartialOrd for HeapWrapper<I> {
 
 impl<I: StorageIterator> Ord for HeapWrapper<I> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.1
-            .key()
-            .cmp(&other.1.key())
-            .then(self.0.cmp(&other.0))
-            .reverse()
+        let key_order = self.1.key().cmp(&other.1.key());
+        let key_order = if self.2 {
+            key_order.reverse()
+        } else {
+            key_order
+        };
+        key_order.then(self.0.cmp(&other.0)).reverse()
     }
 }
 
 /// Merge multiple iterators of the same type. If the same key occurs multiple times in some
-/// iterators, prefer the one with smaller index.
+/// iterators, prefer the one with smaller index. Reverse iterators are merged in descending key
+/// order with `create_rev`.
 pub struct MergeIterator<I: StorageIterator> {
     iters: BinaryHeap<HeapWrapper<I>>,
     current: Option<HeapWrapper<I>>,
+    /// The iterators that are invalid or have failed, kept to be repositioned by a seek.
+    exhausted: Vec<HeapWrapper<I>>,
+    /// Whether entries with the same key from other iterators are skipped.
+    dedup: bool,
 }
 
 impl<I: StorageIterator> MergeIterator<I> {
     pub fn create(iters: Vec<Box<I>>) -> Self {
-        // Case 1: if iters are empty, return an empty MergeIterator
-        if iters.is_empty() {
-            return Self {
-                iters: BinaryHeap::new(),
-                current: None,
-            };
-        }
+        Self::create_inner(iters, true, false)
+    }
 
-        let mut heap = BinaryHeap::new();
-        // Case 2: if all iterators are invalid, current iterator size if 0, has the pop of heap
-        if iters.iter().all(|x| !x.is_valid()) {
-            // All iterators are invalid, select the last one as the current.
-            let mut iters = iters;
-            return Self {
-                iters: heap,
-                current: Some(HeapWrapper(0, iters.pop().unwrap())), // pop one to keep the structure alive
-            };
-        }
-        // Case 3: if the iterator is valid, push into the heap
-        for (index, iter) in iters.into_iter().enumerate() {
-            // Push each iterator into the heap if it's valid.
-            if iter.is_valid() {
-                heap.push(HeapWrapper(index, iter));
+    /// Like `create`, but for iterators producing keys in descending order.
+    pub fn create_rev(iters: Vec<Box<I>>) -> Self {
+        Self::create_inner(iters, true, true)
+    }
+
+    /// Like `create`, but yields every entry of every iterator. Entries with the same key are
+    /// produced one after another, from the smallest index to the largest.
+    pub fn create_keep_duplicates(iters: Vec<Box<I>>) -> Self {
+        Self::create_inner(iters, false, false)
+    }
+
+    fn create_inner(iters: Vec<Box<I>>, dedup: bool, reverse: bool) -> Self {
+        let mut iter = Self {
+            iters: BinaryHeap::new(),
+            current: None,
+            exhausted: iters
+                .into_iter()
+                .enumerate()
+                .map(|(index, iter)| HeapWrapper(index, iter, reverse))
+                .collect(),
+            dedup,
+        };
+        iter.rebuild_heap();
+        iter
+    }
+
+    /// Push the valid iterators into the heap, and pop the one with the smallest key as the
+    /// current. If all iterators are invalid, one of them is still kept as the current.
+    fn rebuild_heap(&mut self) {
+        for iter in std::mem::take(&mut self.exhausted) {
+            if iter.1.is_valid() {
+                self.iters.push(iter);
+            } else {
+                self.exhausted.push(iter);
             }
         }
-        // current is the pop of the heap, which is the smallest key in the heap.
-        let current = heap.pop().unwrap();
-        Self {
-            iters: heap,
-            current: Some(current),
+        self.current = self.iters.pop().or_else(|| self.exhausted.pop());
+    }
+
+    /// Reposition every iterator with `seek`, merging them in descending order if `reverse`.
+    fn reposition(
+        &mut self,
+        reverse: bool,
+        mut seek: impl FnMut(&mut I) -> Result<()>,
+    ) -> Result<()> {
+        self.exhausted.extend(self.current.take());
+        self.exhausted.extend(std::mem::take(&mut self.iters));
+        let mut result = Ok(());
+        for iter in &mut self.exhausted {
+            iter.2 = reverse;
+            if let Err(e) = seek(&mut iter.1)
+                && result.is_ok()
+            {
+                result = Err(e);
+            }
         }
+        self.rebuild_heap();
+        result
     }
 }
 
@@ -100,7 +139,7 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
 {
     type KeyType<'a> = KeySlice<'a>;
 
-    fn key(&self) -> KeySlice {
+    fn key(&self) -> KeySlice<'_> {
         self.current.as_ref().unwrap().1.key()
     }
 
@@ -108,6 +147,10 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
         self.current.as_ref().unwrap().1.value()
     }
 
+    fn value_type(&self) -> ValueType {
+        self.current.as_ref().unwrap().1.value_type()
+    }
+
     fn is_valid(&self) -> bool {
         self.current
             .as_ref()
@@ -118,21 +161,20 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
     fn next(&mut self) -> Result<()> {
         let current = self.current.as_mut().unwrap();
         // 如果有相同的key，同时向前推进
-        while let Some(mut inner_iter) = self.iters.peek_mut() {
-            debug_assert!(
-                inner_iter.1.key() >= current.1.key(),
-                "heap invariant violated"
-            );
+        while self.dedup
+            && let Some(mut inner_iter) = self.iters.peek_mut()
+        {
+            debug_assert!(*inner_iter <= *current, "heap invariant violated");
             if inner_iter.1.key() == current.1.key() {
                 // Case 1: inner_iter does not have next iter, pop it from the heap
                 if let e @ Err(_) = inner_iter.1.next() {
                     // 绑定错误到 e 上
-                    PeekMut::pop(inner_iter);
+                    self.exhausted.push(PeekMut::pop(inner_iter));
                     return e;
                 }
                 // Case 2: inner_iter is not valid, pop it from the heap
                 if !inner_iter.1.is_valid() {
-                    PeekMut::pop(inner_iter);
+                    self.exhausted.push(PeekMut::pop(inner_iter));
                 }
             } else {
                 break;
@@ -144,17 +186,36 @@ impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>> StorageIt
         // if current iter is not valid, we need to pop from the heap and replace it with the next valid iterator
         if !current.1.is_valid() {
             if let Some(iter) = self.iters.pop() {
-                *current = iter;
+                self.exhausted.push(std::mem::replace(current, iter));
             }
             return Ok(());
         }
         // ensure the heap invariant is maintained after advancing the current iterator
-        if let Some(mut inner_iter) = self.iters.peek_mut() {
-            if *current < *inner_iter {
-                std::mem::swap(&mut *inner_iter, current);
-            }
+        if let Some(mut inner_iter) = self.iters.peek_mut()
+            && *current < *inner_iter
+        {
+            std::mem::swap(&mut *inner_iter, current);
         }
-        println!("key: {:?}, value: {:?}", current.1.key(), current.1.value());
         Ok(())
     }
 }
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>> + SeekableIterator>
+    SeekableIterator for MergeIterator<I>
+{
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.reposition(false, |iter| iter.seek_to_first())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(false, |iter| iter.seek(key))
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.reposition(true, |iter| iter.seek_to_last())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(true, |iter| iter.seek_for_prev(key))
+    }
+}
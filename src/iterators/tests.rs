@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use rand::{Rng, SeedableRng};
+use tempfile::tempdir;
+
+use crate::iterators::{SeekableIterator, StorageIterator};
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions};
+use crate::test_harness::{add_l0_sst, key_of};
+
+/// Take up to `n` entries from the current position.
+fn take(
+    iter: &mut impl for<'a> StorageIterator<KeyType<'a> = &'a [u8]>,
+    n: usize,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut result = Vec::new();
+    while iter.is_valid() && result.len() < n {
+        result.push((iter.key().to_vec(), iter.value().to_vec()));
+        iter.next().unwrap();
+    }
+    result
+}
+
+/// Fill a storage with two SSTs, an immutable memtable and the memtable, overwriting and deleting
+/// keys, and return the expected content.
+fn generate_storage(storage: &LsmStorageInner) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    let mut expected = BTreeMap::new();
+    for round in 0..4 {
+        let mut data = BTreeMap::new();
+        for _ in 0..150 {
+            let key = key_of(rng.gen_range(0..300));
+            let value = if rng.gen_bool(0.2) {
+                Vec::new()
+            } else {
+                format!("value_{}", round).into_bytes()
+            };
+            data.insert(key, value);
+        }
+        if round < 2 {
+            add_l0_sst(storage, &data, &[]);
+        } else {
+            for (key, value) in &data {
+                if value.is_empty() {
+                    storage.delete(key).unwrap();
+                } else {
+                    storage.put(key, value).unwrap();
+                }
+            }
+            if round == 2 {
+                storage
+                    .force_freeze_memtable(&storage.state_lock.lock())
+                    .unwrap();
+            }
+        }
+        expected.extend(data);
+    }
+    expected.retain(|_, value| !value.is_empty());
+    expected
+}
+
+#[test]
+fn test_lsm_iterator_seek() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 128;
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    let expected = generate_storage(&storage);
+
+    let (lower, upper) = (key_of(40), key_of(260));
+    let range = (Bound::Excluded(&lower[..]), Bound::Included(&upper[..]));
+    let expected = expected
+        .range::<[u8], _>(range)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect::<Vec<_>>();
+    let mut iter = storage.scan(range.0, range.1).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+    for _ in 0..200 {
+        let key = key_of(rng.gen_range(0..300));
+        if rng.gen_bool(0.5) {
+            iter.seek(&key).unwrap();
+            let start = expected.partition_point(|(k, _)| k < &key);
+            let end = (start + 5).min(expected.len());
+            assert_eq!(take(&mut iter, 5), expected[start..end], "seek({:?})", key);
+        } else {
+            iter.seek_for_prev(&key).unwrap();
+            let end = expected.partition_point(|(k, _)| k <= &key);
+            let start = end.saturating_sub(5);
+            let mut want = expected[start..end].to_vec();
+            want.reverse();
+            assert_eq!(take(&mut iter, 5), want, "seek_for_prev({:?})", key);
+        }
+    }
+
+    // Iterators exhausted by a full scan are repositioned too.
+    iter.seek_to_first().unwrap();
+    assert_eq!(take(&mut iter, usize::MAX), expected);
+    assert!(!iter.is_valid());
+    iter.seek_to_last().unwrap();
+    let mut reversed = take(&mut iter, usize::MAX);
+    reversed.reverse();
+    assert_eq!(reversed, expected);
+    iter.seek(&lower).unwrap();
+    assert_eq!(take(&mut iter, usize::MAX), expected);
+    iter.seek_for_prev(b"z").unwrap();
+    assert_eq!(iter.key(), &expected.last().unwrap().0[..]);
+}
@@ -12,19 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
-#![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
-
 use anyhow::Result;
 
-use super::StorageIterator;
+use super::{SeekableIterator, StorageIterator};
+use crate::value_type::ValueType;
 
 /// Merges two iterators of different types into one. If the two iterators have the same key, only
-/// produce the key once and prefer the entry from A.
+/// produce the key once and prefer the entry from A. Reverse iterators are merged in descending
+/// key order with `create_rev`.
 pub struct TwoMergeIterator<A: StorageIterator, B: StorageIterator> {
     a: A,
     b: B,
-    // Add fields as need
+    /// Whether the current entry comes from A.
+    choose_a: bool,
+    /// Whether both iterators produce keys in descending order.
+    reverse: bool,
 }
 
 impl<
@@ -32,8 +34,47 @@ impl<
     B: 'static + for<'a> StorageIterator<KeyType<'a> = A::KeyType<'a>>,
 > TwoMergeIterator<A, B>
 {
+    fn choose_a(a: &A, b: &B, reverse: bool) -> bool {
+        if !a.is_valid() {
+            return false;
+        }
+        if !b.is_valid() {
+            return true;
+        }
+        if reverse {
+            a.key() > b.key()
+        } else {
+            a.key() < b.key()
+        }
+    }
+
+    /// Skip the entry of B hidden by the entry of A with the same key.
+    fn skip_b(&mut self) -> Result<()> {
+        if self.a.is_valid() && self.b.is_valid() && self.b.key() == self.a.key() {
+            self.b.next()?;
+        }
+        Ok(())
+    }
+
     pub fn create(a: A, b: B) -> Result<Self> {
-        unimplemented!()
+        Self::create_inner(a, b, false)
+    }
+
+    /// Like `create`, but for iterators producing keys in descending order.
+    pub fn create_rev(a: A, b: B) -> Result<Self> {
+        Self::create_inner(a, b, true)
+    }
+
+    fn create_inner(a: A, b: B, reverse: bool) -> Result<Self> {
+        let mut iter = Self {
+            a,
+            b,
+            choose_a: false,
+            reverse,
+        };
+        iter.skip_b()?;
+        iter.choose_a = Self::choose_a(&iter.a, &iter.b, reverse);
+        Ok(iter)
     }
 }
 
@@ -45,18 +86,92 @@ impl<
     type KeyType<'a> = A::KeyType<'a>;
 
     fn key(&self) -> Self::KeyType<'_> {
-        unimplemented!()
+        if self.choose_a {
+            self.a.key()
+        } else {
+            self.b.key()
+        }
     }
 
     fn value(&self) -> &[u8] {
-        unimplemented!()
+        if self.choose_a {
+            self.a.value()
+        } else {
+            self.b.value()
+        }
+    }
+
+    fn value_type(&self) -> ValueType {
+        if self.choose_a {
+            self.a.value_type()
+        } else {
+            self.b.value_type()
+        }
     }
 
     fn is_valid(&self) -> bool {
-        unimplemented!()
+        if self.choose_a {
+            self.a.is_valid()
+        } else {
+            self.b.is_valid()
+        }
     }
 
     fn next(&mut self) -> Result<()> {
-        unimplemented!()
+        if self.choose_a {
+            self.a.next()?;
+        } else {
+            self.b.next()?;
+        }
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&self.a, &self.b, self.reverse);
+        Ok(())
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.a.num_active_iterators() + self.b.num_active_iterators()
+    }
+}
+
+impl<
+    A: 'static + SeekableIterator,
+    B: 'static + for<'a> StorageIterator<KeyType<'a> = A::KeyType<'a>> + SeekableIterator,
+> TwoMergeIterator<A, B>
+{
+    /// Reposition both iterators with `seek`, merging them in descending order if `reverse`.
+    fn reposition(
+        &mut self,
+        reverse: bool,
+        seek_a: impl FnOnce(&mut A) -> Result<()>,
+        seek_b: impl FnOnce(&mut B) -> Result<()>,
+    ) -> Result<()> {
+        self.reverse = reverse;
+        seek_a(&mut self.a)?;
+        seek_b(&mut self.b)?;
+        self.skip_b()?;
+        self.choose_a = Self::choose_a(&self.a, &self.b, reverse);
+        Ok(())
+    }
+}
+
+impl<
+    A: 'static + SeekableIterator,
+    B: 'static + for<'a> StorageIterator<KeyType<'a> = A::KeyType<'a>> + SeekableIterator,
+> SeekableIterator for TwoMergeIterator<A, B>
+{
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.reposition(false, |a| a.seek_to_first(), |b| b.seek_to_first())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(false, |a| a.seek(key), |b| b.seek(key))
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.reposition(true, |a| a.seek_to_last(), |b| b.seek_to_last())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(true, |a| a.seek_for_prev(key), |b| b.seek_for_prev(key))
     }
 }
@@ -15,47 +15,195 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+use std::ops::Bound;
+use std::sync::Arc;
+
 use anyhow::{Ok, Result, bail};
 use bytes::Bytes;
 
 use crate::{
-    iterators::{StorageIterator, merge_iterator::MergeIterator},
+    iterators::{
+        SeekableIterator, StorageIterator, merge_iterator::MergeIterator,
+        two_merge_iterator::TwoMergeIterator,
+    },
+    lsm_storage::LsmStorageState,
     mem_table::MemTableIterator,
+    merge_operator::{MergeOperator, merge_value},
+    range_tombstone::RangeTombstoneFilter,
+    table::SsTableIterator,
+    value_log::{PinnedValueLog, resolve_value},
+    value_type::ValueType,
 };
 
 /// Represents the internal type for an LSM iterator. This type will be changed across the course for multiple times.
-type LsmIteratorInner = MergeIterator<MemTableIterator>;
+type LsmIteratorInner = TwoMergeIterator<
+    MergeIterator<RangeTombstoneFilter<MemTableIterator>>,
+    MergeIterator<RangeTombstoneFilter<SsTableIterator>>,
+>;
 
 pub struct LsmIterator {
     inner: LsmIteratorInner,
+    /// The range of the scan. SST iterators are not bounded by it, so the iteration stops here
+    /// once past its end, and seeks are kept within it.
+    lower: Bound<Bytes>,
+    upper: Bound<Bytes>,
+    is_valid: bool,
+    /// Whether keys are produced in descending order.
+    reverse: bool,
+    /// Resolves the values stored in the value log, if enabled.
+    value_log: Option<PinnedValueLog>,
+    /// Combines merge operands with the older entries of their key, looked up in `snapshot`.
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// The state the iterator was created from.
+    snapshot: Arc<LsmStorageState>,
+    /// The current value, if not the one of `inner`: read from the value log or merged.
+    value: Option<Bytes>,
 }
 
 impl LsmIterator {
-    pub(crate) fn new(iter: LsmIteratorInner) -> Result<Self> {
-        let mut iter = Self { inner: iter };
+    pub(crate) fn new(
+        iter: LsmIteratorInner,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        value_log: Option<PinnedValueLog>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        snapshot: Arc<LsmStorageState>,
+    ) -> Result<Self> {
+        Self::new_inner(
+            iter,
+            lower,
+            upper,
+            false,
+            value_log,
+            merge_operator,
+            snapshot,
+        )
+    }
+
+    /// Create an iterator producing keys in descending order, from a reverse `iter`.
+    pub(crate) fn new_rev(
+        iter: LsmIteratorInner,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        value_log: Option<PinnedValueLog>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        snapshot: Arc<LsmStorageState>,
+    ) -> Result<Self> {
+        Self::new_inner(
+            iter,
+            lower,
+            upper,
+            true,
+            value_log,
+            merge_operator,
+            snapshot,
+        )
+    }
+
+    fn new_inner(
+        iter: LsmIteratorInner,
+        lower: Bound<Bytes>,
+        upper: Bound<Bytes>,
+        reverse: bool,
+        value_log: Option<PinnedValueLog>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        snapshot: Arc<LsmStorageState>,
+    ) -> Result<Self> {
+        let mut iter = Self {
+            is_valid: iter.is_valid(),
+            inner: iter,
+            lower,
+            upper,
+            reverse,
+            value_log,
+            merge_operator,
+            snapshot,
+            value: None,
+        };
+        iter.check_end_bound();
         iter.move_to_non_delete()?; // maybe the first key is a delete marker, we need to skip it.
         Ok(iter)
     }
 
+    /// Whether `key` is below the lower bound of the scan.
+    fn is_before_lower(&self, key: &[u8]) -> bool {
+        match &self.lower {
+            Bound::Unbounded => false,
+            Bound::Included(lower) => key < lower.as_ref(),
+            Bound::Excluded(lower) => key <= lower.as_ref(),
+        }
+    }
+
+    /// Whether `key` is above the upper bound of the scan.
+    fn is_after_upper(&self, key: &[u8]) -> bool {
+        match &self.upper {
+            Bound::Unbounded => false,
+            Bound::Included(upper) => key > upper.as_ref(),
+            Bound::Excluded(upper) => key >= upper.as_ref(),
+        }
+    }
+
+    /// Stop once past the end of the range, in the direction of the iteration.
+    fn check_end_bound(&mut self) {
+        if !self.is_valid {
+            return;
+        }
+        let key = self.inner.key().raw_ref();
+        self.is_valid = if self.reverse {
+            !self.is_before_lower(key)
+        } else {
+            !self.is_after_upper(key)
+        };
+    }
+
+    /// Update the state after the inner iterator is repositioned.
+    fn after_seek(&mut self, reverse: bool) -> Result<()> {
+        self.reverse = reverse;
+        self.is_valid = self.inner.is_valid();
+        self.check_end_bound();
+        self.move_to_non_delete()
+    }
+
     fn next_inner(&mut self) -> Result<()> {
         self.inner.next()?;
-        if !self.inner.is_valid() {
-            return Ok(());
-        }
+        self.is_valid = self.inner.is_valid();
+        self.check_end_bound();
         Ok(())
     }
+    /// Skip the tombstones and expired puts, and resolve the value of the entry reached.
     fn move_to_non_delete(&mut self) -> Result<()> {
-        while self.is_valid() && self.inner.value() == Bytes::copy_from_slice(b"") {
+        while self.is_valid() {
+            self.value = None;
+            match self.inner.value_type() {
+                ValueType::Delete | ValueType::SingleDelete => {}
+                value_type @ (ValueType::Put | ValueType::PutWithExpiry) => {
+                    if value_type.is_expired(self.inner.value()) {
+                        self.next_inner()?;
+                        continue;
+                    }
+                    if self.value_log.is_some() || value_type == ValueType::PutWithExpiry {
+                        self.value = Some(resolve_value(
+                            self.value_log.as_deref(),
+                            value_type,
+                            Bytes::copy_from_slice(self.inner.value()),
+                        )?);
+                    }
+                    break;
+                }
+                ValueType::Merge => {
+                    self.value = merge_value(
+                        &self.snapshot,
+                        self.inner.key().raw_ref(),
+                        self.merge_operator.as_deref(),
+                        self.value_log.as_deref(),
+                    )?;
+                    if self.value.is_some() {
+                        break;
+                    }
+                }
+            }
             self.next_inner()?;
         }
-        println!(
-            "key after move_to_non_delete: {:?}",
-            if self.is_valid() {
-                self.key()
-            } else {
-                b"<invalid>"
-            }
-        );
         Ok(())
     }
 }
@@ -64,7 +212,7 @@ impl StorageIterator for LsmIterator {
     type KeyType<'a> = &'a [u8];
 
     fn is_valid(&self) -> bool {
-        self.inner.is_valid()
+        self.is_valid
     }
 
     fn key(&self) -> &[u8] {
@@ -72,7 +220,15 @@ impl StorageIterator for LsmIterator {
     }
 
     fn value(&self) -> &[u8] {
-        self.inner.value()
+        match &self.value {
+            Some(value) => value,
+            None => self.inner.value(),
+        }
+    }
+
+    /// Tombstones are skipped and merge operands are resolved, so every entry produced is a put.
+    fn value_type(&self) -> ValueType {
+        ValueType::Put
     }
 
     fn next(&mut self) -> Result<()> {
@@ -82,6 +238,54 @@ impl StorageIterator for LsmIterator {
     }
 }
 
+/// Seeks are kept within the range of the scan: seeking before its start moves to the first key,
+/// and seeking past its end leaves the iterator invalid.
+impl SeekableIterator for LsmIterator {
+    fn seek_to_first(&mut self) -> Result<()> {
+        match self.lower.clone() {
+            Bound::Unbounded => self.inner.seek_to_first()?,
+            Bound::Included(lower) => self.inner.seek(&lower)?,
+            Bound::Excluded(lower) => {
+                self.inner.seek(&lower)?;
+                if self.inner.is_valid() && self.inner.key().raw_ref() == lower {
+                    self.inner.next()?;
+                }
+            }
+        }
+        self.after_seek(false)
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        if self.is_before_lower(key) {
+            return self.seek_to_first();
+        }
+        self.inner.seek(key)?;
+        self.after_seek(false)
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        match self.upper.clone() {
+            Bound::Unbounded => self.inner.seek_to_last()?,
+            Bound::Included(upper) => self.inner.seek_for_prev(&upper)?,
+            Bound::Excluded(upper) => {
+                self.inner.seek_for_prev(&upper)?;
+                if self.inner.is_valid() && self.inner.key().raw_ref() == upper {
+                    self.inner.next()?;
+                }
+            }
+        }
+        self.after_seek(true)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        if self.is_after_upper(key) {
+            return self.seek_to_last();
+        }
+        self.inner.seek_for_prev(key)?;
+        self.after_seek(true)
+    }
+}
+
 /// A wrapper around existing iterator, will prevent users from calling `next` when the iterator is
 /// invalid. If an iterator is already invalid, `next` does not do anything. If `next` returns an error,
 /// `is_valid` should return false, and `next` should always return an error.
@@ -97,6 +301,13 @@ impl<I: StorageIterator> FusedIterator<I> {
             has_errored: false,
         }
     }
+
+    /// Reposition the iterator with `seek`. A successful seek recovers from an earlier error.
+    fn reposition(&mut self, seek: impl FnOnce(&mut I) -> Result<()>) -> Result<()> {
+        let result = seek(&mut self.iter);
+        self.has_errored = result.is_err();
+        result
+    }
 }
 
 impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
@@ -125,16 +336,44 @@ impl<I: StorageIterator> StorageIterator for FusedIterator<I> {
         self.iter.value()
     }
 
+    fn value_type(&self) -> ValueType {
+        if !self.is_valid() {
+            panic!("Cannot call value_type() on an invalid iterator");
+        }
+        self.iter.value_type()
+    }
+
     fn next(&mut self) -> Result<()> {
         if self.has_errored {
             bail!("Cannot call next() on an iterator that has already errored");
         }
-        if self.iter.is_valid() {
-            if let Err(e) = self.iter.next() {
-                self.has_errored = true;
-                return Err(e); // propagate the error
-            }
+        if self.iter.is_valid()
+            && let Err(e) = self.iter.next()
+        {
+            self.has_errored = true;
+            return Err(e); // propagate the error
         }
         Ok(())
     }
 }
+
+impl<I: SeekableIterator> SeekableIterator for FusedIterator<I> {
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.reposition(|iter| iter.seek_to_first())
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(|iter| iter.seek(key))
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.reposition(|iter| iter.seek_to_last())
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.reposition(|iter| iter.seek_for_prev(key))
+    }
+}
+
+#[cfg(test)]
+mod tests;
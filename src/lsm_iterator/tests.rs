@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use tempfile::tempdir;
+
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions};
+use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::test_harness::{add_l0_sst, collect, key_of};
+
+#[test]
+fn test_sst_iterator_reverse() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..100 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx * 2)),
+            &key_of(idx),
+        );
+    }
+    builder.build_for_test(&path).unwrap();
+    let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+    assert!(sst.num_of_blocks() > 5);
+
+    let mut iter = SsTableIterator::create_and_seek_to_last(sst.clone()).unwrap();
+    for idx in (0..100).rev() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx * 2)[..]);
+        assert_eq!(iter.value(), &key_of(idx)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    for idx in 0..201 {
+        let iter = SsTableIterator::create_and_seek_for_prev(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx)),
+        )
+        .unwrap();
+        // The last even key <= idx.
+        assert_eq!(
+            iter.key().for_testing_key_ref(),
+            &key_of((idx / 2 * 2).min(198))[..]
+        );
+    }
+    let iter = SsTableIterator::create_and_seek_for_prev(
+        sst,
+        KeySlice::for_testing_from_slice_no_ts(b"a"),
+    )
+    .unwrap();
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_scan_rev() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 128;
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut expected = BTreeMap::new();
+    // Two SSTs, then an immutable memtable and the memtable, overwriting and deleting keys.
+    for round in 0..4 {
+        let mut data = BTreeMap::new();
+        for _ in 0..150 {
+            let key = key_of(rng.gen_range(0..300));
+            let value = if rng.gen_bool(0.2) {
+                Vec::new()
+            } else {
+                format!("value_{}", round).into_bytes()
+            };
+            data.insert(key, value);
+        }
+        if round < 2 {
+            add_l0_sst(&storage, &data, &[]);
+        } else {
+            for (key, value) in &data {
+                if value.is_empty() {
+                    storage.delete(key).unwrap();
+                } else {
+                    storage.put(key, value).unwrap();
+                }
+            }
+            if round == 2 {
+                storage
+                    .force_freeze_memtable(&storage.state_lock.lock())
+                    .unwrap();
+            }
+        }
+        expected.extend(data);
+    }
+    expected.retain(|_, value| !value.is_empty());
+
+    let bounds = [
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Included(key_of(50)), Bound::Included(key_of(250))),
+        (Bound::Excluded(key_of(50)), Bound::Excluded(key_of(250))),
+        (Bound::Unbounded, Bound::Excluded(key_of(100))),
+        (Bound::Included(key_of(200)), Bound::Unbounded),
+        (Bound::Included(key_of(120)), Bound::Included(key_of(120))),
+    ];
+    for (lower, upper) in bounds {
+        let lower = lower.as_ref().map(|x| x.as_slice());
+        let upper = upper.as_ref().map(|x| x.as_slice());
+        let expected = expected
+            .range::<[u8], _>((lower, upper))
+            .rev()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        let actual = collect(&mut storage.scan_rev(lower, upper).unwrap());
+        assert_eq!(actual, expected, "scan_rev({:?}, {:?})", lower, upper);
+        let mut forward = collect(&mut storage.scan(lower, upper).unwrap());
+        forward.reverse();
+        assert_eq!(forward, actual);
+    }
+}
@@ -15,29 +15,43 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
 
-use anyhow::{Ok, Result};
+use anyhow::{Context, Ok, Result, bail, ensure};
 use bytes::Bytes;
 use parking_lot::{Mutex, MutexGuard, RwLock};
 
-use crate::block::Block;
+use crate::block::DEFAULT_RESTART_INTERVAL;
 use crate::compact::{
     CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
     SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
 };
+use crate::iterators::StorageIterator;
 use crate::iterators::merge_iterator::MergeIterator;
+use crate::iterators::two_merge_iterator::TwoMergeIterator;
+use crate::key::KeySlice;
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
-use crate::manifest::Manifest;
+use crate::manifest::{Manifest, ManifestRecord};
 use crate::mem_table::MemTable;
+use crate::merge_operator::{MergeOperator, merge_entries, merge_value};
 use crate::mvcc::LsmMvccInner;
-use crate::table::SsTable;
+use crate::mvcc::txn::{CommitDurability, Transaction};
+use crate::range_tombstone::RangeTombstoneFilter;
+use crate::table::{
+    CachedBlock, CompressionType, DEFAULT_BLOOM_BITS_PER_KEY, FileObject, FilterType, SsTable,
+    SsTableBuilder, SsTableIterator,
+};
+use crate::value_log::{PinnedValueLog, ValueLog, ValueLogOptions, resolve_value};
+use crate::value_type::{ValueType, now_millis, split_expiry, with_expiry};
+use crate::wal::{BeforeSync, Wal, WalOptions, WalTicket};
 
-pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
+pub type BlockCache = moka::sync::Cache<(usize, usize), CachedBlock>;
 
 /// Represents the state of the storage engine.
 #[derive(Clone)]
@@ -55,9 +69,30 @@ pub struct LsmStorageState {
     pub sstables: HashMap<usize, Arc<SsTable>>,
 }
 
+/// The largest key that can be written, as the SST index stores key lengths as u16.
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+
+fn check_key_size(key: &[u8]) -> Result<()> {
+    ensure!(
+        key.len() <= MAX_KEY_SIZE,
+        "key of {} bytes is too large, the limit is {} bytes",
+        key.len(),
+        MAX_KEY_SIZE
+    );
+    Ok(())
+}
+
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Put(T, T),
     Del(T),
+    /// A merge operand, combined with the value of the key by the merge operator.
+    Merge(T, T),
+    /// A tombstone for a key put exactly once, see `LsmStorageInner::single_delete`.
+    SingleDel(T),
+    /// A put that expires after the duration, counted from when the batch is written.
+    PutWithTtl(T, T, Duration),
+    /// A put that expires at the given time, in milliseconds since the Unix epoch.
+    PutWithExpiry(T, T, u64),
 }
 
 impl LsmStorageState {
@@ -79,52 +114,244 @@ impl LsmStorageState {
             sstables: Default::default(),
         }
     }
+
+    /// Call `visit` with the entries of `key`, from the newest, as long as it returns true. Each
+    /// memtable and each SST is newer than the ones after it, and has at most one entry for the key,
+    /// which is newer than its range tombstones. The visit stops at the first range tombstone
+    /// covering the key, as it hides the older ones. SSTs whose bloom filter rejects the key are
+    /// skipped without reading any data block.
+    pub(crate) fn visit_entries(
+        &self,
+        key: &[u8],
+        mut visit: impl FnMut(ValueType, Bytes) -> bool,
+    ) -> Result<()> {
+        let memtables = std::iter::once(&self.memtable).chain(self.imm_memtables.iter());
+        for memtable in memtables {
+            memtable.check_wal()?;
+            if let Some((value_type, value)) = memtable.get_typed(key)
+                && !visit(value_type, value)
+            {
+                return Ok(());
+            }
+            if memtable.range_tombstone_covers(key) {
+                return Ok(());
+            }
+        }
+        // find in L0 SSTs, then in the levels, from latest to earliest
+        let sst_ids = self
+            .l0_sstables
+            .iter()
+            .chain(self.levels.iter().flat_map(|(_, ssts)| ssts));
+        for sst_id in sst_ids {
+            let table = &self.sstables[sst_id];
+            if table.may_contain(key)
+                && let Some((value_type, value)) = table.get(KeySlice::from_slice(key))?
+                && !visit(value_type, value)
+            {
+                return Ok(());
+            }
+            if table.range_tombstone_covers(key) {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// The newest entry of `key`. A key deleted by a range tombstone has no entry.
+    pub(crate) fn newest_entry(&self, key: &[u8]) -> Result<Option<(ValueType, Bytes)>> {
+        let mut newest = None;
+        self.visit_entries(key, |value_type, value| {
+            newest = Some((value_type, value));
+            false
+        })?;
+        Ok(newest)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LsmStorageOptions {
     // Block size in bytes
     pub block_size: usize,
+    // Entries between two restart points of each block, whose keys are stored in full
+    pub block_restart_interval: usize,
+    // Append a hash index to each block, so that point lookups skip the binary search of the block
+    pub block_hash_index: bool,
+    // Bits per key of the bloom filter of each SST, 0 to build SSTs without a bloom filter
+    pub bloom_bits_per_key: usize,
+    // The layout of the filter of each SST
+    pub filter_type: FilterType,
+    // Also add key prefixes to the bloom filters, for `scan_prefix`
+    pub prefix_extractor: Option<PrefixExtractor>,
+    // Target size in bytes of the index partitions of each SST, 0 for an unpartitioned index
+    pub index_partition_size: usize,
+    // Codec of the data blocks of the SSTs of each level, starting from L0. Levels past the end use
+    // the last codec, and an empty list disables compression
+    pub compression_per_level: Vec<CompressionType>,
     // SST size in bytes, also the approximate memtable capacity limit
     pub target_sst_size: usize,
     // Maximum number of memtables in memory, flush to L0 when exceeding this limit
     pub num_memtable_limit: usize,
     pub compaction_options: CompactionOptions,
-    pub enable_wal: bool,
+    // Whether memtables are backed by a WAL, and when the WAL is fsynced
+    pub durability: DurabilityMode,
+    // Segment size and preallocation of the WAL files
+    pub wal_options: WalOptions,
+    // Store large values in a value log instead of the LSM tree, None to keep every value inline.
+    // Must be the same every time a directory is opened
+    pub value_log: Option<ValueLogOptions>,
+    // Combines the operands written by `merge`, which fails without one. Must be the same every
+    // time a directory is opened
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
     pub serializable: bool,
+    // How long `Transaction::get_for_update` waits for a row lock held by another transaction
+    pub lock_wait_timeout: Duration,
 }
 
 impl LsmStorageOptions {
     pub fn default_for_week1_test() -> Self {
         Self {
             block_size: 4096,
+            block_restart_interval: DEFAULT_RESTART_INTERVAL,
+            block_hash_index: false,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            filter_type: FilterType::Classic,
+            prefix_extractor: None,
+            index_partition_size: 0,
+            compression_per_level: Vec::new(),
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
-            enable_wal: false,
+            durability: DurabilityMode::NoWal,
+            wal_options: WalOptions::default(),
+            value_log: None,
+            merge_operator: None,
             num_memtable_limit: 50,
             serializable: false,
+            lock_wait_timeout: Duration::from_secs(1),
         }
     }
 
     pub fn default_for_week1_day6_test() -> Self {
         Self {
             block_size: 4096,
+            block_restart_interval: DEFAULT_RESTART_INTERVAL,
+            block_hash_index: false,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            filter_type: FilterType::Classic,
+            prefix_extractor: None,
+            index_partition_size: 0,
+            compression_per_level: Vec::new(),
             target_sst_size: 2 << 20,
             compaction_options: CompactionOptions::NoCompaction,
-            enable_wal: false,
+            durability: DurabilityMode::NoWal,
+            wal_options: WalOptions::default(),
+            value_log: None,
+            merge_operator: None,
             num_memtable_limit: 2,
             serializable: false,
+            lock_wait_timeout: Duration::from_secs(1),
         }
     }
 
     pub fn default_for_week2_test(compaction_options: CompactionOptions) -> Self {
         Self {
             block_size: 4096,
+            block_restart_interval: DEFAULT_RESTART_INTERVAL,
+            block_hash_index: false,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            filter_type: FilterType::Classic,
+            prefix_extractor: None,
+            index_partition_size: 0,
+            compression_per_level: Vec::new(),
             target_sst_size: 1 << 20, // 1MB
             compaction_options,
-            enable_wal: false,
+            durability: DurabilityMode::NoWal,
+            wal_options: WalOptions::default(),
+            value_log: None,
+            merge_operator: None,
             num_memtable_limit: 2,
             serializable: false,
+            lock_wait_timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// The codec of the data blocks of the SSTs of `level`, 0 for L0.
+    pub fn compression_of_level(&self, level: usize) -> CompressionType {
+        self.compression_per_level
+            .get(level)
+            .or(self.compression_per_level.last())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// When writes reach the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// No WAL. Writes not yet flushed to an SST are lost on a crash.
+    NoWal,
+    /// Fsync the WAL before acknowledging every write.
+    SyncEveryWrite,
+    /// Fsync the WAL from a background thread at the given interval.
+    SyncInterval(Duration),
+    /// Fsync the WAL only on `MiniLsm::sync`.
+    SyncManual,
+}
+
+impl DurabilityMode {
+    pub fn enable_wal(&self) -> bool {
+        !matches!(self, DurabilityMode::NoWal)
+    }
+}
+
+/// Extracts the prefix of a key that is added to the bloom filters of SSTs, so that
+/// `MiniLsm::scan_prefix` can skip SSTs without any key under that prefix. All keys starting with
+/// the same extracted prefix must be contiguous, which holds for both variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixExtractor {
+    /// The first `n` bytes. Keys shorter than that have no prefix.
+    FixedLength(u16),
+    /// Everything up to and including the `n`-th delimiter, e.g. `tenant/entity/` for
+    /// `tenant/entity/field` with `Delimited(b'/', 2)`. Keys with fewer delimiters have no prefix.
+    Delimited(u8, u16),
+}
+
+impl PrefixExtractor {
+    pub fn extract<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            PrefixExtractor::FixedLength(n) => key.get(..n as usize),
+            PrefixExtractor::Delimited(delimiter, n) => {
+                if n == 0 {
+                    return Some(&key[..0]);
+                }
+                let (pos, _) = key
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| **c == delimiter)
+                    .nth(n as usize - 1)?;
+                Some(&key[..=pos])
+            }
+        }
+    }
+
+    /// Encode as `| type (u8) | delimiter (u8) | n (u16) |`, with type 0 standing for no
+    /// extractor.
+    pub(crate) fn encode(extractor: Option<&Self>) -> u32 {
+        match extractor {
+            None => 0,
+            Some(PrefixExtractor::FixedLength(n)) => (1 << 24) | *n as u32,
+            Some(PrefixExtractor::Delimited(delimiter, n)) => {
+                (2 << 24) | ((*delimiter as u32) << 16) | *n as u32
+            }
+        }
+    }
+
+    pub(crate) fn decode(raw: u32) -> Result<Option<Self>> {
+        let n = raw as u16;
+        match raw >> 24 {
+            0 => Ok(None),
+            1 => Ok(Some(PrefixExtractor::FixedLength(n))),
+            2 => Ok(Some(PrefixExtractor::Delimited((raw >> 16) as u8, n))),
+            ty => anyhow::bail!("unknown prefix extractor type {}", ty),
         }
     }
 }
@@ -134,6 +361,74 @@ pub enum CompactionFilter {
     Prefix(Bytes),
 }
 
+/// Whether the key range of an SST overlaps with a scan range.
+fn range_overlap(
+    lower: Bound<&[u8]>,
+    upper: Bound<&[u8]>,
+    first_key: &[u8],
+    last_key: &[u8],
+) -> bool {
+    match upper {
+        Bound::Excluded(key) if key <= first_key => return false,
+        Bound::Included(key) if key < first_key => return false,
+        _ => {}
+    }
+    match lower {
+        Bound::Excluded(key) if key >= last_key => return false,
+        Bound::Included(key) if key > last_key => return false,
+        _ => {}
+    }
+    true
+}
+
+/// Create an iterator over an SST from the first key within `lower`.
+fn sst_iter_from_lower(table: Arc<SsTable>, lower: Bound<&[u8]>) -> Result<SsTableIterator> {
+    Ok(match lower {
+        Bound::Included(key) => {
+            SsTableIterator::create_and_seek_to_key(table, KeySlice::from_slice(key))?
+        }
+        Bound::Excluded(key) => {
+            let mut iter =
+                SsTableIterator::create_and_seek_to_key(table, KeySlice::from_slice(key))?;
+            if iter.is_valid() && iter.key().raw_ref() == key {
+                iter.next()?;
+            }
+            iter
+        }
+        Bound::Unbounded => SsTableIterator::create_and_seek_to_first(table)?,
+    })
+}
+
+/// Create a reverse iterator over an SST from the last key within `upper`.
+fn sst_iter_to_upper(table: Arc<SsTable>, upper: Bound<&[u8]>) -> Result<SsTableIterator> {
+    Ok(match upper {
+        Bound::Included(key) => {
+            SsTableIterator::create_and_seek_for_prev(table, KeySlice::from_slice(key))?
+        }
+        Bound::Excluded(key) => {
+            let mut iter =
+                SsTableIterator::create_and_seek_for_prev(table, KeySlice::from_slice(key))?;
+            if iter.is_valid() && iter.key().raw_ref() == key {
+                iter.next()?;
+            }
+            iter
+        }
+        Bound::Unbounded => SsTableIterator::create_and_seek_to_last(table)?,
+    })
+}
+
+/// The smallest key greater than all keys starting with `prefix`, if any.
+fn prefix_upper_bound(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Bound::Excluded(upper);
+        }
+    }
+    Bound::Unbounded
+}
+
 /// The storage interface of the LSM tree.
 pub(crate) struct LsmStorageInner {
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
@@ -146,6 +441,8 @@ pub(crate) struct LsmStorageInner {
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    /// Where large values are stored, if enabled.
+    pub(crate) value_log: Option<Arc<ValueLog>>,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -159,12 +456,17 @@ pub struct MiniLsm {
     compaction_notifier: crossbeam_channel::Sender<()>,
     /// The handle for the compaction thread. (In week 2)
     compaction_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// Notifies the WAL sync thread to stop working.
+    wal_sync_notifier: crossbeam_channel::Sender<()>,
+    /// The handle for the WAL sync thread, with `DurabilityMode::SyncInterval`.
+    wal_sync_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
 impl Drop for MiniLsm {
     fn drop(&mut self) {
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
+        self.wal_sync_notifier.send(()).ok();
     }
 }
 
@@ -181,16 +483,20 @@ impl MiniLsm {
         let compaction_thread = inner.spawn_compaction_thread(rx)?;
         let (tx2, rx) = crossbeam_channel::unbounded();
         let flush_thread = inner.spawn_flush_thread(rx)?;
+        let (tx3, rx) = crossbeam_channel::unbounded();
+        let wal_sync_thread = inner.spawn_wal_sync_thread(rx)?;
         Ok(Arc::new(Self {
             inner,
             flush_notifier: tx2,
             flush_thread: Mutex::new(flush_thread),
             compaction_notifier: tx1,
             compaction_thread: Mutex::new(compaction_thread),
+            wal_sync_notifier: tx3,
+            wal_sync_thread: Mutex::new(wal_sync_thread),
         }))
     }
 
-    pub fn new_txn(&self) -> Result<()> {
+    pub fn new_txn(&self) -> Result<Arc<Transaction>> {
         self.inner.new_txn()
     }
 
@@ -234,6 +540,26 @@ impl MiniLsm {
         self.inner.delete(key)
     }
 
+    /// Remove a key put exactly once, see `LsmStorageInner::single_delete`.
+    pub fn single_delete(&self, key: &[u8]) -> Result<()> {
+        self.inner.single_delete(key)
+    }
+
+    /// Put a key that expires after `ttl`, see `LsmStorageInner::put_with_ttl`.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.inner.put_with_ttl(key, value, ttl)
+    }
+
+    /// Combine `operand` with the value of `key`, see `LsmStorageInner::merge`.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.inner.merge(key, operand)
+    }
+
+    /// Delete all keys in `[lower, upper)`, see `LsmStorageInner::delete_range`.
+    pub fn delete_range(&self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        self.inner.delete_range(lower, upper)
+    }
+
     pub fn sync(&self) -> Result<()> {
         self.inner.sync()
     }
@@ -246,6 +572,21 @@ impl MiniLsm {
         self.inner.scan(lower, upper)
     }
 
+    /// Scan a range of keys in descending order, e.g. to read the latest entries first.
+    pub fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.scan_rev(lower, upper)
+    }
+
+    /// Scan all keys starting with `prefix`. SSTs whose bloom filter has no such prefix are
+    /// skipped when a prefix extractor is configured.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<FusedIterator<LsmIterator>> {
+        self.inner.scan_prefix(prefix)
+    }
+
     /// Only call this in test cases due to race conditions
     pub fn force_flush(&self) -> Result<()> {
         if !self.inner.state.read().memtable.is_empty() {
@@ -261,6 +602,11 @@ impl MiniLsm {
     pub fn force_full_compaction(&self) -> Result<()> {
         self.inner.force_full_compaction()
     }
+
+    /// Garbage-collect the oldest value log file, see `LsmStorageInner::gc_value_log`.
+    pub fn gc_value_log(&self) -> Result<bool> {
+        self.inner.gc_value_log()
+    }
 }
 
 impl LsmStorageInner {
@@ -273,8 +619,15 @@ impl LsmStorageInner {
     /// not exist.
     pub(crate) fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
         let path = path.as_ref();
-        let state = LsmStorageState::create(&options);
-
+        let mut state = LsmStorageState::create(&options);
+        let mut next_sst_id = 1;
+        std::fs::create_dir_all(path)?;
+        let value_log = match &options.value_log {
+            Some(value_log_options) => {
+                Some(Arc::new(ValueLog::open(path, value_log_options.clone())?))
+            }
+            None => None,
+        };
         let compaction_controller = match &options.compaction_options {
             CompactionOptions::Leveled(options) => {
                 CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
@@ -288,24 +641,128 @@ impl LsmStorageInner {
             CompactionOptions::NoCompaction => CompactionController::NoCompaction,
         };
 
+        let block_cache = Arc::new(BlockCache::new(1024));
+        let manifest_path = path.join("MANIFEST");
+        let mut flushed = HashSet::new();
+        let manifest = if manifest_path.exists() {
+            let (manifest, records) = Manifest::recover(&manifest_path)?;
+            for record in records {
+                match record {
+                    ManifestRecord::Flush(id) => {
+                        if compaction_controller.flush_to_l0() {
+                            state.l0_sstables.insert(0, id);
+                        } else {
+                            state.levels.insert(0, (id, vec![id]));
+                        }
+                        flushed.insert(id);
+                        next_sst_id = next_sst_id.max(id + 1);
+                    }
+                    ManifestRecord::NewMemtable(id) => {
+                        next_sst_id = next_sst_id.max(id + 1);
+                    }
+                    ManifestRecord::Compaction(task, output) => {
+                        (state, _) = compaction_controller
+                            .apply_compaction_result(&state, &task, &output, true);
+                        if let Some(max_id) = output.iter().max() {
+                            next_sst_id = next_sst_id.max(max_id + 1);
+                        }
+                    }
+                }
+            }
+            let sst_ids = state
+                .l0_sstables
+                .iter()
+                .chain(state.levels.iter().flat_map(|(_, ids)| ids))
+                .copied()
+                .collect::<Vec<_>>();
+            for id in sst_ids {
+                let table = SsTable::open(
+                    id,
+                    Some(block_cache.clone()),
+                    FileObject::open(&Self::path_of_sst_static(path, id))
+                        .with_context(|| format!("failed to open SST {}", id))?,
+                )?;
+                state.sstables.insert(id, Arc::new(table));
+            }
+            manifest
+        } else {
+            Manifest::create(&manifest_path)?
+        };
+
+        if options.durability.enable_wal() {
+            // The WALs of flushed memtables are removed once the flush is in the manifest, so every
+            // WAL left in the directory belongs to a memtable that has not been flushed yet, unless
+            // the storage stopped in between.
+            let mut wal_ids = Vec::new();
+            for entry in std::fs::read_dir(path)? {
+                let file_name = entry?.file_name();
+                if let Some(id) = file_name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".wal"))
+                    .and_then(|id| id.parse::<usize>().ok())
+                {
+                    wal_ids.push(id);
+                }
+            }
+            wal_ids.sort();
+            for id in wal_ids {
+                let wal_path = Self::path_of_wal_static(path, id);
+                if flushed.contains(&id) {
+                    Wal::remove(&wal_path)?;
+                    continue;
+                }
+                let memtable =
+                    MemTable::recover_from_wal(id, &wal_path, options.wal_options.clone())?;
+                if memtable.is_empty() {
+                    drop(memtable);
+                    Wal::remove(&wal_path)?;
+                } else {
+                    state.imm_memtables.insert(0, Arc::new(memtable));
+                }
+                next_sst_id = next_sst_id.max(id + 1);
+            }
+            state.memtable = Arc::new(
+                MemTable::create_with_wal(
+                    next_sst_id,
+                    Self::path_of_wal_static(path, next_sst_id),
+                    options.wal_options.clone(),
+                )?
+                .with_wal_before_sync(Self::wal_before_sync(&value_log)),
+            );
+            next_sst_id += 1;
+        }
+        File::open(path)?.sync_all()?;
+
         let storage = Self {
             state: Arc::new(RwLock::new(Arc::new(state))),
             state_lock: Mutex::new(()),
             path: path.to_path_buf(),
-            block_cache: Arc::new(BlockCache::new(1024)),
-            next_sst_id: AtomicUsize::new(1),
+            block_cache,
+            next_sst_id: AtomicUsize::new(next_sst_id),
             compaction_controller,
-            manifest: None,
+            manifest: Some(manifest),
             options: options.into(),
-            mvcc: None,
+            mvcc: Some(LsmMvccInner::new(0)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            value_log,
         };
 
         Ok(storage)
     }
 
+    /// Fsync the value log before every fsync of a WAL, as the WAL may point into it.
+    fn wal_before_sync(value_log: &Option<Arc<ValueLog>>) -> Option<BeforeSync> {
+        let value_log = value_log.clone()?;
+        Some(BeforeSync::new(move || value_log.sync()))
+    }
+
+    /// Fsync the WAL of the current memtable. The WALs of immutable memtables are fsynced when they
+    /// get frozen. The value log is fsynced first, as the WAL may point into it.
     pub fn sync(&self) -> Result<()> {
-        unimplemented!()
+        if let Some(value_log) = &self.value_log {
+            value_log.sync()?;
+        }
+        self.state.read().memtable.sync_wal()
     }
 
     pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
@@ -313,55 +770,334 @@ impl LsmStorageInner {
         compaction_filters.push(compaction_filter);
     }
 
-    /// Get a key from the storage. In day 7, this can be further optimized by using a bloom filter.
-    pub fn get(&self, _key: &[u8]) -> Result<Option<Bytes>> {
-        let value = self.state.read().memtable.get(_key);
-        match value {
-            Some(v) => {
-                // Found in the memtable
-                if v.eq(&Bytes::copy_from_slice(b"")) {
-                    // Return the found value, unless it's an empty value indicating deletion
-                    return Result::Ok(None); // If the value is empty, it means the key was deleted;
-                }
-                Result::Ok(Some(v))
-            }
-            None => {
-                // find in imm_memtables
-                for imm_memtable in self.state.read().imm_memtables.iter() {
-                    if let Some(v) = imm_memtable.get(_key) {
-                        if v.eq(&Bytes::copy_from_slice(b"")) {
-                            // Return None for deleted keys
-                            return Result::Ok(None);
-                        }
-                        return Result::Ok(Some(v));
-                    }
-                }
-                Result::Ok(None)
+    /// Get a key from the storage. SSTs whose bloom filter rejects the key are skipped without
+    /// reading any data block.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let value_log = self.pin_value_log();
+        let snapshot = {
+            let guard = self.state.read();
+            Arc::clone(&guard)
+        };
+        match snapshot.newest_entry(key)? {
+            None => Ok(None),
+            Some((ValueType::Merge, _)) => merge_value(
+                &snapshot,
+                key,
+                self.options.merge_operator.as_deref(),
+                value_log.as_deref(),
+            ),
+            Some((value_type, value)) if value_type.hides_key(&value) => Ok(None),
+            Some((value_type, value)) => {
+                resolve_value(value_log.as_deref(), value_type, value).map(Some)
             }
         }
     }
 
+    /// Pin the value log, if enabled, before taking a snapshot whose values are read from it.
+    pub(crate) fn pin_value_log(&self) -> Option<PinnedValueLog> {
+        self.value_log.as_ref().map(ValueLog::pin)
+    }
+
+    /// Get the newest entry of a key as stored in the LSM tree, without resolving value log
+    /// pointers.
+    fn get_stored(&self, key: &[u8]) -> Result<Option<(ValueType, Bytes)>> {
+        let snapshot = {
+            let guard = self.state.read();
+            Arc::clone(&guard)
+        };
+        snapshot.newest_entry(key)
+    }
+
     /// Write a batch of data into the storage. Implement in week 2 day 7.
     pub fn write_batch<T: AsRef<[u8]>>(&self, _batch: &[WriteBatchRecord<T>]) -> Result<()> {
-        unimplemented!()
+        self.write_batch_inner(_batch, CommitDurability::default())?;
+        Ok(())
     }
 
-    /// Put a key-value pair into the storage by writing into the current memtable.
-    pub fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
-        if self.state.read().memtable.approximate_size() >= self.options.num_memtable_limit {
-            let state_lock = self.state_lock.lock(); // lock the state to freeze the memtable
-            if self.state.read().memtable.approximate_size() >= self.options.num_memtable_limit {
-                let _ = self.force_freeze_memtable(&state_lock);
+    /// Apply a batch under the MVCC write lock and return the commit timestamp assigned to it. The
+    /// batch is logged as a single WAL record, which is not waited for if `durability` is
+    /// `Memory`. Fails without writing anything if a key is larger than `MAX_KEY_SIZE`.
+    ///
+    /// The WAL record is only enqueued under the lock, and waited for once it is released, so that
+    /// concurrent batches share the WAL write and fsync. The batch is applied to the memtable
+    /// meanwhile, so concurrent readers may see it before it is durable. If the WAL write fails,
+    /// the memtable is poisoned, see `MemTable::check_wal`.
+    pub(crate) fn write_batch_inner<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        durability: CommitDurability,
+    ) -> Result<u64> {
+        let (commit_ts, ticket) = self.write_batch_locked(batch, durability)?;
+        if let Some(ticket) = ticket {
+            ticket.wait()?;
+        }
+        Ok(commit_ts)
+    }
+
+    /// Apply a batch under the MVCC write lock, and return its commit timestamp and its WAL
+    /// ticket.
+    fn write_batch_locked<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        durability: CommitDurability,
+    ) -> Result<(u64, Option<WalTicket>)> {
+        let mvcc = self.mvcc();
+        let _write_lock = mvcc.write_lock.lock();
+        self.try_freeze_memtable();
+        let now = now_millis();
+        let expiring = batch
+            .iter()
+            .filter_map(|record| match record {
+                WriteBatchRecord::PutWithTtl(_, value, ttl) => {
+                    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+                    Some(with_expiry(now.saturating_add(ttl), value.as_ref()))
+                }
+                WriteBatchRecord::PutWithExpiry(_, value, expires_at) => {
+                    Some(with_expiry(*expires_at, value.as_ref()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut expiring = expiring.iter();
+        let data = batch
+            .iter()
+            .map(|record| match record {
+                WriteBatchRecord::Put(key, value) => (key.as_ref(), ValueType::Put, value.as_ref()),
+                WriteBatchRecord::Del(key) => (key.as_ref(), ValueType::Delete, &b""[..]),
+                WriteBatchRecord::Merge(key, value) => {
+                    (key.as_ref(), ValueType::Merge, value.as_ref())
+                }
+                WriteBatchRecord::SingleDel(key) => {
+                    (key.as_ref(), ValueType::SingleDelete, &b""[..])
+                }
+                WriteBatchRecord::PutWithTtl(key, _, _)
+                | WriteBatchRecord::PutWithExpiry(key, _, _) => (
+                    key.as_ref(),
+                    ValueType::PutWithExpiry,
+                    &expiring.next().unwrap()[..],
+                ),
+            })
+            .collect::<Vec<_>>();
+        for (key, _, _) in &data {
+            check_key_size(key)?;
+        }
+        if cfg!(debug_assertions) {
+            self.validate_single_deletes(&data)?;
+        }
+        if !data
+            .iter()
+            .any(|(_, value_type, _)| *value_type == ValueType::Merge)
+        {
+            return self.write_locked(&data, durability);
+        }
+        let folded = self.fold_merges(&data)?;
+        let data = folded
+            .iter()
+            .map(|(key, value_type, value)| (*key, *value_type, &value[..]))
+            .collect::<Vec<_>>();
+        self.write_locked(&data, durability)
+    }
+
+    /// Check that the single deletes of a batch delete keys whose newest entry is a put, written
+    /// earlier in the batch or already stored. Only called in debug builds, with the MVCC write
+    /// lock held.
+    fn validate_single_deletes(&self, data: &[(&[u8], ValueType, &[u8])]) -> Result<()> {
+        for (idx, &(key, value_type, _)) in data.iter().enumerate() {
+            if value_type != ValueType::SingleDelete {
+                continue;
             }
+            let newest = match data[..idx].iter().rev().find(|(k, _, _)| *k == key) {
+                Some(&(_, value_type, _)) => Some(value_type),
+                None => self.get_stored(key)?.map(|(value_type, _)| value_type),
+            };
+            ensure!(
+                matches!(newest, Some(ValueType::Put | ValueType::PutWithExpiry)),
+                "single delete of {:?}, whose newest entry is {:?} instead of a put",
+                key,
+                newest
+            );
         }
-        self.state
-            .read() // 这里用read，并发安全性由memtable中的跳表来保证
-            .memtable
-            .put(_key, _value)
+        Ok(())
     }
 
-    /// Remove a key from the storage by writing an empty value.
-    pub fn delete(&self, _key: &[u8]) -> Result<()> {
+    /// Combine the merge operands of a batch with the entries of their keys in the memtable, or
+    /// earlier in the batch, as the memtable keeps a single entry per key. Called with the MVCC
+    /// write lock held, so that the memtable entries do not change meanwhile.
+    ///
+    /// Only the current memtable is read, at write time: an operand whose key has no entry there
+    /// is written as is, and combined with the older layers by reads and compaction.
+    fn fold_merges<'a>(
+        &self,
+        data: &[(&'a [u8], ValueType, &[u8])],
+    ) -> Result<Vec<(&'a [u8], ValueType, Bytes)>> {
+        if self.options.merge_operator.is_none() {
+            bail!("no merge operator is configured");
+        }
+        let memtable = self.state.read().memtable.clone();
+        let mut folded: Vec<(&[u8], ValueType, Bytes)> = Vec::with_capacity(data.len());
+        for &(key, value_type, value) in data {
+            let value = Bytes::copy_from_slice(value);
+            if value_type != ValueType::Merge {
+                folded.push((key, value_type, value));
+                continue;
+            }
+            let existing = match folded.iter().rev().find(|(k, _, _)| *k == key) {
+                Some((_, value_type, value)) => Some((*value_type, value.clone())),
+                None => match memtable.get_typed(key) {
+                    Some((value_type, value)) => match &self.value_log {
+                        Some(value_log) => {
+                            Some((value_type, value_log.resolve_entry(value_type, value)?))
+                        }
+                        None => Some((value_type, value)),
+                    },
+                    None => None,
+                },
+            };
+            let (value_type, value) = match existing {
+                Some(existing) => merge_entries(
+                    self.options.merge_operator.as_deref(),
+                    key,
+                    &[(ValueType::Merge, value), existing],
+                    false,
+                )?,
+                None => (ValueType::Merge, value),
+            };
+            folded.push((key, value_type, value));
+        }
+        Ok(folded)
+    }
+
+    /// Apply a batch with the MVCC write lock held, storing large values in the value log.
+    fn write_locked(
+        &self,
+        data: &[(&[u8], ValueType, &[u8])],
+        durability: CommitDurability,
+    ) -> Result<(u64, Option<WalTicket>)> {
+        let sync = durability == CommitDurability::WalSynced
+            || (durability == CommitDurability::Wal
+                && self.options.durability == DurabilityMode::SyncEveryWrite);
+        let stored_values = match &self.value_log {
+            // The value log is written first, so that the WAL never points past its end. It is
+            // fsynced before the WAL, see `wal_before_sync`.
+            Some(value_log) => Some(value_log.separate(data, false)?),
+            None => None,
+        };
+        let data = data
+            .iter()
+            .enumerate()
+            .map(|(idx, (key, value_type, value))| {
+                let value = stored_values.as_ref().map_or(*value, |x| &x[idx][..]);
+                (KeySlice::from_slice(key), *value_type, value)
+            })
+            .collect::<Vec<_>>();
+        let ticket = {
+            let state = self.state.read(); // 这里用read，并发安全性由memtable中的跳表来保证
+            let ticket = state.memtable.enqueue_batch(&data, sync)?;
+            state.memtable.put_batch_skip_wal(&data)?;
+            ticket
+        };
+        // A `Memory` commit is still logged, ahead of the commits that may read it, but nobody
+        // waits for it to be written.
+        let ticket = ticket.filter(|_| durability != CommitDurability::Memory);
+        let mvcc = self.mvcc();
+        let commit_ts = mvcc.latest_commit_ts() + 1;
+        mvcc.update_commit_ts(commit_ts);
+        Ok((commit_ts, ticket))
+    }
+
+    /// Garbage-collect the oldest value log file: the values still referenced by the LSM tree are
+    /// appended to the head of the log and their pointers are updated, then the file is deleted.
+    /// A key whose merge operands or base value are referenced is rewritten as the value they
+    /// amount to, and an expired key as a tombstone. Returns false if there is no file to collect.
+    /// Reads racing the deletion may fail.
+    ///
+    /// Without a WAL, the relinked values are flushed to SSTs before the file is deleted. Files
+    /// whose deletion failed earlier are deleted first.
+    pub fn gc_value_log(&self) -> Result<bool> {
+        let Some(value_log) = &self.value_log else {
+            return Ok(false);
+        };
+        value_log.delete_undeleted()?;
+        let Some(file_id) = value_log.oldest_file() else {
+            return Ok(false);
+        };
+        let records = value_log.records(file_id)?;
+        let (relinked_any, ticket) = {
+            let mvcc = self.mvcc();
+            let _write_lock = mvcc.write_lock.lock();
+            self.try_freeze_memtable();
+            let snapshot = {
+                let guard = self.state.read();
+                Arc::clone(&guard)
+            };
+            // Only relink the values not overwritten since they were read.
+            let mut relinked = HashSet::new();
+            let mut live = Vec::new();
+            for record in &records {
+                if relinked.contains(&record.key[..]) {
+                    continue;
+                }
+                let pointer = record.pointer.encode();
+                let mut entries = Vec::new();
+                let mut referenced = false;
+                snapshot.visit_entries(&record.key, |value_type, value| {
+                    referenced |= match value_type {
+                        ValueType::Delete | ValueType::SingleDelete => false,
+                        ValueType::PutWithExpiry => split_expiry(&value)
+                            .is_ok_and(|(_, stored)| stored == pointer.as_slice()),
+                        ValueType::Put | ValueType::Merge => value == pointer,
+                    };
+                    entries.push((value_type, value));
+                    value_type == ValueType::Merge
+                })?;
+                if !referenced {
+                    continue;
+                }
+                for (value_type, value) in &mut entries {
+                    *value = value_log.resolve_entry(*value_type, value.clone())?;
+                }
+                let (value_type, value) = merge_entries(
+                    self.options.merge_operator.as_deref(),
+                    &record.key,
+                    &entries,
+                    true,
+                )?;
+                relinked.insert(&record.key[..]);
+                if value_type.hides_key(&value) {
+                    live.push((&record.key[..], ValueType::Delete, Bytes::new()));
+                } else {
+                    live.push((&record.key[..], value_type, value));
+                }
+            }
+            if live.is_empty() {
+                (false, None)
+            } else {
+                let live = live
+                    .iter()
+                    .map(|(key, value_type, value)| (*key, *value_type, &value[..]))
+                    .collect::<Vec<_>>();
+                (
+                    true,
+                    self.write_locked(&live, CommitDurability::WalSynced)?.1,
+                )
+            }
+        };
+        if let Some(ticket) = ticket {
+            ticket.wait()?;
+        } else if relinked_any {
+            // Without a WAL, the relinked values are lost on a crash until they are flushed.
+            value_log.sync()?;
+            self.force_freeze_memtable(&self.state_lock.lock())?;
+            while !self.state.read().imm_memtables.is_empty() {
+                self.force_flush_next_imm_memtable()?;
+            }
+        }
+        value_log.retire_file(file_id);
+        Ok(true)
+    }
+
+    /// Freeze the current memtable if it has reached its capacity.
+    fn try_freeze_memtable(&self) {
         if self.state.read().memtable.approximate_size() >= self.options.num_memtable_limit {
             let state_lock = self.state_lock.lock(); // lock the state to freeze the memtable
             // 避免两个线程同时调用force_freeze_memtable，必须保证只能有一个线程获取到锁
@@ -369,7 +1105,75 @@ impl LsmStorageInner {
                 let _ = self.force_freeze_memtable(&state_lock);
             }
         }
-        self.state.read().memtable.put(_key, b"") // Use an empty byte slice to indicate deletion
+    }
+
+    /// Put a key-value pair into the storage by writing into the current memtable.
+    pub fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Put(_key, _value)])
+    }
+
+    /// Remove a key from the storage by writing a tombstone.
+    pub fn delete(&self, _key: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Del(_key)])
+    }
+
+    /// Remove a key put exactly once since it was created or last deleted, and not merged into.
+    /// Compaction drops the tombstone together with that put as soon as they meet, instead of
+    /// keeping it down to the bottom level, so an older version of a key put more than once would
+    /// reappear. Debug builds check that the newest entry of the key is a put.
+    pub fn single_delete(&self, key: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::SingleDel(key)])
+    }
+
+    /// Put a key-value pair that expires after `ttl`. Once expired, reads no longer see it, and
+    /// compaction drops it.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::PutWithTtl(key, value, ttl)])
+    }
+
+    /// Write a merge operand, combined with the value of the key by the merge operator when it is
+    /// read, without reading the key.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        self.write_batch(&[WriteBatchRecord::Merge(key, operand)])
+    }
+
+    /// Delete all keys in `[lower, upper)` with a single range tombstone, instead of a tombstone
+    /// per key. Keys written afterwards are not affected.
+    pub fn delete_range(&self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        if lower >= upper {
+            return Ok(());
+        }
+        check_key_size(lower)?;
+        check_key_size(upper)?;
+        let mvcc = self.mvcc();
+        let _write_lock = mvcc.write_lock.lock();
+        self.try_freeze_memtable();
+        {
+            let state = self.state.read();
+            state.memtable.delete_range(lower, upper)?;
+            if self.options.durability == DurabilityMode::SyncEveryWrite {
+                state.memtable.sync_wal()?;
+            }
+        }
+        mvcc.update_commit_ts(mvcc.latest_commit_ts() + 1);
+        Ok(())
+    }
+
+    /// Create a builder for a new L0 SST, configured by the storage options.
+    pub(crate) fn new_sst_builder(&self) -> SsTableBuilder {
+        self.new_sst_builder_for_level(0)
+    }
+
+    /// Create a builder for a new SST of `level` (0 for L0), configured by the storage options.
+    pub(crate) fn new_sst_builder_for_level(&self, level: usize) -> SsTableBuilder {
+        SsTableBuilder::new(self.options.block_size)
+            .with_block_restart_interval(self.options.block_restart_interval)
+            .with_block_hash_index(self.options.block_hash_index)
+            .with_compression(self.options.compression_of_level(level))
+            .with_bloom_bits_per_key(self.options.bloom_bits_per_key)
+            .with_filter_type(self.options.filter_type)
+            .with_prefix_extractor(self.options.prefix_extractor)
+            .with_index_partition_size(self.options.index_partition_size)
     }
 
     pub(crate) fn path_of_sst_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
@@ -389,12 +1193,25 @@ impl LsmStorageInner {
     }
 
     pub(super) fn sync_dir(&self) -> Result<()> {
-        unimplemented!()
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
     }
 
     /// Force freeze the current memtable to an immutable memtable
     pub fn force_freeze_memtable(&self, _state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
-        let new_memtable = Arc::new(MemTable::create(self.next_sst_id()));
+        let memtable_id = self.next_sst_id();
+        let new_memtable = if self.options.durability.enable_wal() {
+            let memtable = MemTable::create_with_wal(
+                memtable_id,
+                self.path_of_wal(memtable_id),
+                self.options.wal_options.clone(),
+            )?
+            .with_wal_before_sync(Self::wal_before_sync(&self.value_log));
+            self.sync_dir()?;
+            Arc::new(memtable)
+        } else {
+            Arc::new(MemTable::create(memtable_id))
+        };
         {
             let mut guard = self.state.write();
             let mut snapshot = guard.as_ref().clone();
@@ -404,18 +1221,59 @@ impl LsmStorageInner {
             // Update the state
             *guard = Arc::new(snapshot);
             drop(guard);
+            // The frozen memtable is never written again, and `sync` only covers the current one.
+            old_memtable.sync_wal()?;
         }
         Ok(())
     }
 
     /// Force flush the earliest-created immutable memtable to disk
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
-        unimplemented!()
+        let state_lock = self.state_lock.lock();
+        let Some(memtable) = self.state.read().imm_memtables.last().cloned() else {
+            return Ok(());
+        };
+        let id = memtable.id();
+        let sst = if memtable.is_empty() {
+            None
+        } else {
+            let mut builder = self.new_sst_builder();
+            memtable.flush(&mut builder)?;
+            let sst = builder.build(id, Some(self.block_cache.clone()), self.path_of_sst(id))?;
+            self.sync_dir()?;
+            if let Some(manifest) = &self.manifest {
+                manifest.add_record(&state_lock, ManifestRecord::Flush(id))?;
+            }
+            Some(Arc::new(sst))
+        };
+        {
+            let mut guard = self.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            let flushed = snapshot.imm_memtables.pop().unwrap();
+            assert_eq!(flushed.id(), id);
+            if let Some(sst) = sst {
+                if self.compaction_controller.flush_to_l0() {
+                    snapshot.l0_sstables.insert(0, id);
+                } else {
+                    snapshot.levels.insert(0, (id, vec![id]));
+                }
+                snapshot.sstables.insert(id, sst);
+            }
+            *guard = Arc::new(snapshot);
+        }
+        // The memtable is durable in the SST now, or had nothing to flush.
+        if self.options.durability.enable_wal() {
+            Wal::remove(self.path_of_wal(id))?;
+        }
+        Ok(())
     }
 
-    pub fn new_txn(&self) -> Result<()> {
-        // no-op
-        Ok(())
+    pub(crate) fn mvcc(&self) -> &LsmMvccInner {
+        self.mvcc.as_ref().unwrap()
+    }
+
+    pub fn new_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
+        Ok(self.mvcc().new_txn(self.clone(), self.options.serializable))
     }
 
     /// Create an iterator over a range of keys.
@@ -424,18 +1282,142 @@ impl LsmStorageInner {
         _lower: Bound<&[u8]>,
         _upper: Bound<&[u8]>,
     ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan_inner(_lower, _upper, None, false)
+    }
+
+    /// Create an iterator over a range of keys in descending order.
+    pub fn scan_rev(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        self.scan_inner(lower, upper, None, true)
+    }
+
+    /// Scan all keys starting with `prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<FusedIterator<LsmIterator>> {
+        let upper = prefix_upper_bound(prefix);
+        self.scan_inner(
+            Bound::Included(prefix),
+            upper.as_ref().map(|x| x.as_slice()),
+            Some(prefix),
+            false,
+        )
+    }
+
+    /// Create an iterator over a range of keys, in descending order if `reverse`, skipping SSTs
+    /// that do not overlap with the range or whose bloom filter does not contain `prefix`.
+    fn scan_inner(
+        &self,
+        _lower: Bound<&[u8]>,
+        _upper: Bound<&[u8]>,
+        prefix: Option<&[u8]>,
+        reverse: bool,
+    ) -> Result<FusedIterator<LsmIterator>> {
+        let value_log = self.pin_value_log();
         let snapshot = {
             let guard = self.state.read();
             Arc::clone(&guard)
         };
 
-        let mut memtables_iters = Vec::with_capacity(snapshot.imm_memtables.len() + 1);
-        memtables_iters.push(Box::new(snapshot.memtable.scan(_lower, _upper)));
-        for memtable in snapshot.imm_memtables.iter() {
-            memtables_iters.push(Box::new(memtable.scan(_lower, _upper)));
+        // The range tombstones of each layer, from the newest, hide the keys of the older layers.
+        // They are collected from every SST, as they may cover keys outside its key range.
+        let memtables = std::iter::once(&snapshot.memtable)
+            .chain(snapshot.imm_memtables.iter())
+            .collect::<Vec<_>>();
+        // L0 SSTs from latest to earliest, then the levels
+        let tables = snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, ssts)| ssts))
+            .map(|sst_id| snapshot.sstables[sst_id].clone())
+            .collect::<Vec<_>>();
+        let mut range_tombstones = Vec::new();
+        let mut num_newer = Vec::with_capacity(memtables.len() + tables.len());
+        for memtable in &memtables {
+            memtable.check_wal()?;
+            num_newer.push(range_tombstones.len());
+            range_tombstones.extend(memtable.range_tombstones());
+        }
+        for table in &tables {
+            num_newer.push(range_tombstones.len());
+            range_tombstones.extend_from_slice(table.range_tombstones());
+        }
+        let range_tombstones = Arc::new(range_tombstones);
+        let mut num_newer = num_newer.into_iter();
+
+        let mut memtables_iters = Vec::with_capacity(memtables.len());
+        for memtable in memtables {
+            let iter = if reverse {
+                memtable.scan_rev(_lower, _upper)
+            } else {
+                memtable.scan(_lower, _upper)
+            };
+            memtables_iters.push(Box::new(RangeTombstoneFilter::create(
+                iter,
+                range_tombstones.clone(),
+                num_newer.next().unwrap(),
+                reverse,
+            )?));
+        }
+        let mut sst_iters = Vec::new();
+        for (table, num_newer) in tables.into_iter().zip(num_newer) {
+            if !range_overlap(
+                _lower,
+                _upper,
+                table.first_key().raw_ref(),
+                table.last_key().raw_ref(),
+            ) {
+                continue;
+            }
+            if let Some(prefix) = prefix
+                && !table.may_contain_prefix(prefix)
+            {
+                continue;
+            }
+            let iter = if reverse {
+                sst_iter_to_upper(table, _upper)?
+            } else {
+                sst_iter_from_lower(table, _lower)?
+            };
+            sst_iters.push(Box::new(RangeTombstoneFilter::create(
+                iter,
+                range_tombstones.clone(),
+                num_newer,
+                reverse,
+            )?));
         }
         // we need to merge all iterators
-        let merge_iter = MergeIterator::create(memtables_iters);
-        Ok(FusedIterator::new(LsmIterator::new(merge_iter)?))
+        let memtable_iter = if reverse {
+            MergeIterator::create_rev(memtables_iters)
+        } else {
+            MergeIterator::create(memtables_iters)
+        };
+        if reverse {
+            let sst_iter = MergeIterator::create_rev(sst_iters);
+            let iter = TwoMergeIterator::create_rev(memtable_iter, sst_iter)?;
+            return Ok(FusedIterator::new(LsmIterator::new_rev(
+                iter,
+                _lower.map(Bytes::copy_from_slice),
+                _upper.map(Bytes::copy_from_slice),
+                value_log,
+                self.options.merge_operator.clone(),
+                snapshot,
+            )?));
+        }
+        let sst_iter = MergeIterator::create(sst_iters);
+
+        let iter = TwoMergeIterator::create(memtable_iter, sst_iter)?;
+        Ok(FusedIterator::new(LsmIterator::new(
+            iter,
+            _lower.map(Bytes::copy_from_slice),
+            _upper.map(Bytes::copy_from_slice),
+            value_log,
+            self.options.merge_operator.clone(),
+            snapshot,
+        )?))
     }
 }
+
+#[cfg(test)]
+mod tests;
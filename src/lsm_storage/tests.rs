@@ -0,0 +1,569 @@
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::compact::{CompactionTask, TieredCompactionTask};
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::{
+    DurabilityMode, LsmStorageInner, LsmStorageOptions, MiniLsm, PrefixExtractor, WriteBatchRecord,
+};
+use crate::mem_table::decode_value;
+use crate::merge_operator::AppendOperator;
+use crate::table::SsTableIterator;
+use crate::test_harness::{add_l0_sst, collect, key_of};
+use crate::value_log::ValueLogOptions;
+use crate::value_type::{ValueType, now_millis, split_expiry, with_expiry};
+use crate::wal::BeforeSync;
+
+#[test]
+fn test_wal_recover_memtables() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    {
+        let storage = Arc::new(LsmStorageInner::open(dir.path(), options.clone()).unwrap());
+        storage.put(b"1", b"233").unwrap();
+        storage.put(b"2", b"2333").unwrap();
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.delete(b"1").unwrap();
+        storage.put(b"3", b"23333").unwrap();
+    }
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options.clone()).unwrap());
+    assert_eq!(storage.state.read().imm_memtables.len(), 2);
+    assert_eq!(storage.get(b"1").unwrap(), None);
+    assert_eq!(&storage.get(b"2").unwrap().unwrap()[..], b"2333");
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+    storage.put(b"4", b"233333").unwrap();
+    drop(storage);
+
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options).unwrap());
+    assert_eq!(storage.state.read().imm_memtables.len(), 3);
+    assert_eq!(&storage.get(b"4").unwrap().unwrap()[..], b"233333");
+}
+
+#[test]
+fn test_group_commit_shares_sync() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    // Keep every write in the same memtable, whose WAL is checked.
+    options.num_memtable_limit = usize::MAX;
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options).unwrap());
+    // Hold the first fsync until the other writers have enqueued their batches.
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let (entered_tx, entered_rx) = crossbeam_channel::bounded(1);
+    let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(1);
+    {
+        let syncs = syncs.clone();
+        storage
+            .state
+            .read()
+            .memtable
+            .set_wal_before_sync(Some(BeforeSync::new(move || {
+                if syncs.fetch_add(1, Ordering::SeqCst) == 0 {
+                    entered_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                }
+                Ok(())
+            })));
+    }
+    let put = |idx: usize| {
+        let storage = storage.clone();
+        std::thread::spawn(move || storage.put(&key_of(idx), b"value").unwrap())
+    };
+    let mut handles = vec![put(0)];
+    entered_rx.recv().unwrap();
+    // The first writer waits for its fsync without holding the write lock.
+    handles.extend((1..8).map(put));
+    while (1..8).any(|idx| storage.state.read().memtable.get(&key_of(idx)).is_none()) {
+        std::thread::yield_now();
+    }
+    release_tx.send(()).unwrap();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // The seven writers enqueued behind the first fsync share the next one.
+    assert_eq!(syncs.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_failed_wal_write_poisons_memtable() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    storage.put(b"1", b"1").unwrap();
+    storage
+        .state
+        .read()
+        .memtable
+        .set_wal_before_sync(Some(BeforeSync::new(|| {
+            anyhow::bail!("injected fsync failure")
+        })));
+    assert!(storage.put(b"2", b"2").is_err());
+    // The write that may not be durable is neither read nor flushed.
+    assert!(storage.get(b"2").is_err());
+    assert!(storage.scan(Bound::Unbounded, Bound::Unbounded).is_err());
+    let flushed = storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .and_then(|_| storage.force_flush_next_imm_memtable());
+    assert!(flushed.is_err());
+    assert!(storage.state.read().l0_sstables.is_empty());
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    assert_eq!(storage.get(b"1").unwrap().as_deref(), Some(&b"1"[..]));
+}
+
+#[test]
+fn test_flush_removes_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    {
+        let storage = Arc::new(LsmStorageInner::open(dir.path(), options.clone()).unwrap());
+        storage.put(b"1", b"233").unwrap();
+        storage.put(b"2", b"2333").unwrap();
+        storage.delete_range(b"2", b"3").unwrap();
+        let memtable_id = storage.state.read().memtable.id();
+        storage
+            .force_freeze_memtable(&storage.state_lock.lock())
+            .unwrap();
+        storage.put(b"3", b"23333").unwrap();
+        storage.force_flush_next_imm_memtable().unwrap();
+        assert!(!storage.path_of_wal(memtable_id).exists());
+        assert!(storage.path_of_sst(memtable_id).exists());
+        assert_eq!(storage.state.read().l0_sstables, vec![memtable_id]);
+    }
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options).unwrap());
+    assert_eq!(storage.state.read().imm_memtables.len(), 1);
+    assert_eq!(storage.state.read().l0_sstables.len(), 1);
+    assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    assert_eq!(storage.get(b"2").unwrap(), None);
+    assert_eq!(&storage.get(b"3").unwrap().unwrap()[..], b"23333");
+}
+
+#[test]
+fn test_wal_sync_modes() {
+    for durability in [
+        DurabilityMode::SyncInterval(Duration::from_millis(10)),
+        DurabilityMode::SyncManual,
+    ] {
+        let dir = tempdir().unwrap();
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.durability = durability;
+        let storage = MiniLsm::open(dir.path(), options.clone()).unwrap();
+        storage.put(b"1", b"233").unwrap();
+        storage.sync().unwrap();
+        drop(storage);
+        let storage = MiniLsm::open(dir.path(), options).unwrap();
+        assert_eq!(&storage.get(b"1").unwrap().unwrap()[..], b"233");
+    }
+}
+
+fn pairs(data: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    data.iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+#[test]
+fn test_prefix_extractor() {
+    let extractor = PrefixExtractor::Delimited(b'/', 2);
+    assert_eq!(extractor.extract(b"t1/e1/f1"), Some(&b"t1/e1/"[..]));
+    assert_eq!(extractor.extract(b"t1/e1/"), Some(&b"t1/e1/"[..]));
+    assert_eq!(extractor.extract(b"t1/e1"), None);
+    let extractor = PrefixExtractor::FixedLength(3);
+    assert_eq!(extractor.extract(b"t1/e1"), Some(&b"t1/"[..]));
+    assert_eq!(extractor.extract(b"t1"), None);
+    for extractor in [
+        None,
+        Some(PrefixExtractor::FixedLength(3)),
+        Some(PrefixExtractor::Delimited(b'/', 2)),
+    ] {
+        let raw = PrefixExtractor::encode(extractor.as_ref());
+        assert_eq!(PrefixExtractor::decode(raw).unwrap(), extractor);
+    }
+}
+
+#[test]
+fn test_scan_with_ssts() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    add_l0_sst(
+        &storage,
+        [("a", "1"), ("b", "1"), ("c", "1"), ("d", "1")],
+        &[],
+    );
+    add_l0_sst(&storage, [("b", "2"), ("c", "")], &[]);
+    storage.put(b"d", b"3").unwrap();
+    storage.put(b"e", b"3").unwrap();
+
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    assert_eq!(
+        collect(&mut iter),
+        pairs(&[("a", "1"), ("b", "2"), ("d", "3"), ("e", "3")])
+    );
+    let mut iter = storage
+        .scan(Bound::Excluded(b"a"), Bound::Included(b"d"))
+        .unwrap();
+    assert_eq!(collect(&mut iter), pairs(&[("b", "2"), ("d", "3")]));
+    let mut iter = storage
+        .scan(Bound::Included(b"b"), Bound::Excluded(b"d"))
+        .unwrap();
+    assert_eq!(collect(&mut iter), pairs(&[("b", "2")]));
+}
+
+#[test]
+fn test_scan_prefix_skips_ssts() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.prefix_extractor = Some(PrefixExtractor::Delimited(b'/', 2));
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    let sst_id = add_l0_sst(
+        &storage,
+        [("t1/e1/f1", "1"), ("t1/e1/f2", "1"), ("t3/e1/f1", "1")],
+        &[],
+    );
+    add_l0_sst(&storage, [("t2/e1/f1", "2"), ("t2/e2/f1", "2")], &[]);
+    storage.put(b"t2/e1/f2", b"3").unwrap();
+
+    let mut iter = storage.scan_prefix(b"t2/e1/").unwrap();
+    assert_eq!(
+        collect(&mut iter),
+        pairs(&[("t2/e1/f1", "2"), ("t2/e1/f2", "3")])
+    );
+    let mut iter = storage.scan_prefix(b"t1/").unwrap();
+    assert_eq!(
+        collect(&mut iter),
+        pairs(&[("t1/e1/f1", "1"), ("t1/e1/f2", "1")])
+    );
+
+    // Break the first SST: a prefix scan that would read it fails, unless its bloom filter has no
+    // key under the prefix.
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(storage.path_of_sst(sst_id))
+        .unwrap();
+    file.write_all_at(b"garbage", 0).unwrap();
+    assert!(storage.scan_prefix(b"t1/").is_err());
+    assert!(
+        storage
+            .scan(Bound::Included(b"t2/e1/"), Bound::Excluded(b"t2/e10"))
+            .is_err()
+    );
+    let failed = (0..100)
+        .filter(|idx| {
+            storage
+                .put(format!("t2/x{}/f1", idx).as_bytes(), b"4")
+                .unwrap();
+            let prefix = format!("t2/x{}/", idx);
+            match storage.scan_prefix(prefix.as_bytes()) {
+                Ok(mut iter) => {
+                    assert_eq!(collect(&mut iter).len(), 1);
+                    false
+                }
+                Err(_) => true,
+            }
+        })
+        .count();
+    assert!(failed < 10, "{} prefix scans read the SST", failed);
+}
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn scan(storage: &LsmStorageInner) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut result = Vec::new();
+    while iter.is_valid() {
+        result.push((iter.key().to_vec(), iter.value().to_vec()));
+        iter.next().unwrap();
+    }
+    result
+}
+
+fn freeze(storage: &LsmStorageInner) {
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+}
+
+/// Check that `live` and `plain` are visible, while `expired` and `overwritten` are not.
+fn check_storage(storage: &LsmStorageInner) {
+    assert_eq!(storage.get(b"live").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(storage.get(b"plain").unwrap().as_deref(), Some(&b"2"[..]));
+    assert_eq!(storage.get(b"expired").unwrap(), None);
+    assert_eq!(storage.get(b"overwritten").unwrap(), None);
+    assert_eq!(
+        scan(storage),
+        [
+            (b"live".to_vec(), b"1".to_vec()),
+            (b"plain".to_vec(), b"2".to_vec())
+        ]
+    );
+}
+
+#[test]
+fn test_ttl_get_and_scan() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    storage.put(b"overwritten", b"0").unwrap();
+    freeze(&storage);
+    storage.put_with_ttl(b"live", b"1", HOUR).unwrap();
+    storage
+        .put_with_ttl(b"expired", b"1", Duration::ZERO)
+        .unwrap();
+    // An expired put hides the older versions of its key.
+    storage
+        .put_with_ttl(b"overwritten", b"1", Duration::ZERO)
+        .unwrap();
+    storage.put(b"plain", b"2").unwrap();
+    check_storage(&storage);
+    freeze(&storage);
+    check_storage(&storage);
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    check_storage(&storage);
+}
+
+#[test]
+fn test_ttl_in_sst() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    storage.put(b"overwritten", b"0").unwrap();
+    freeze(&storage);
+
+    let mut builder = storage.new_sst_builder();
+    for (key, expires_at) in [
+        (&b"expired"[..], now_millis() - 1),
+        (b"live", now_millis() + 3_600_000),
+        (b"overwritten", 0),
+    ] {
+        builder.add_typed(
+            KeySlice::for_testing_from_slice_no_ts(key),
+            ValueType::PutWithExpiry,
+            &with_expiry(expires_at, b"1"),
+        );
+    }
+    builder.add(KeySlice::for_testing_from_slice_no_ts(b"plain"), b"2");
+    let sst_id = storage.next_sst_id();
+    let sst = builder
+        .build(
+            sst_id,
+            Some(storage.block_cache.clone()),
+            storage.path_of_sst(sst_id),
+        )
+        .unwrap();
+    {
+        let mut guard = storage.state.write();
+        let mut snapshot = guard.as_ref().clone();
+        snapshot.imm_memtables.clear();
+        snapshot.l0_sstables.insert(0, sst_id);
+        snapshot.sstables.insert(sst_id, Arc::new(sst));
+        *guard = Arc::new(snapshot);
+    }
+    check_storage(&storage);
+}
+
+#[test]
+fn test_ttl_expires() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    storage
+        .put_with_ttl(b"key", b"1", Duration::from_millis(50))
+        .unwrap();
+    assert_eq!(storage.get(b"key").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(scan(&storage).len(), 1);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(storage.get(b"key").unwrap(), None);
+    assert!(scan(&storage).is_empty());
+}
+
+#[test]
+fn test_ttl_batch_and_txn() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap(),
+    );
+    storage
+        .write_batch(&[
+            WriteBatchRecord::PutWithTtl(&b"expired"[..], b"1", Duration::ZERO),
+            WriteBatchRecord::PutWithTtl(b"live", b"0", HOUR),
+            WriteBatchRecord::Put(b"plain", b"2"),
+        ])
+        .unwrap();
+    storage.put(b"overwritten", b"0").unwrap();
+
+    let txn = storage.new_txn().unwrap();
+    txn.put_with_ttl(b"live", b"1", HOUR);
+    txn.put_with_ttl(b"overwritten", b"1", Duration::ZERO);
+    assert_eq!(txn.get(b"live").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(txn.get(b"overwritten").unwrap(), None);
+    txn.commit().unwrap();
+    check_storage(&storage);
+}
+
+#[test]
+fn test_txn_ttl_keeps_expiry() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap(),
+    );
+    let txn = storage.new_txn().unwrap();
+    txn.put_with_ttl(b"key", b"1", HOUR);
+    let (_, buffered) = decode_value(txn.local_storage.get(&b"key"[..]).unwrap().value().clone());
+    let (expires_at, _) = split_expiry(&buffered).unwrap();
+    // The expiry is counted from the write, not from the commit.
+    std::thread::sleep(Duration::from_millis(20));
+    txn.commit().unwrap();
+
+    let (value_type, stored) = storage.state.read().memtable.get_typed(b"key").unwrap();
+    assert_eq!(value_type, ValueType::PutWithExpiry);
+    assert_eq!(split_expiry(&stored).unwrap(), (expires_at, &b"1"[..]));
+}
+
+#[test]
+fn test_ttl_full_compaction() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    storage.put(b"overwritten", b"0").unwrap();
+    freeze(&storage);
+    storage
+        .put_with_ttl(b"expired", b"1", Duration::ZERO)
+        .unwrap();
+    storage.put_with_ttl(b"live", b"1", HOUR).unwrap();
+    storage
+        .put_with_ttl(b"overwritten", b"1", Duration::ZERO)
+        .unwrap();
+    storage.put(b"plain", b"2").unwrap();
+    freeze(&storage);
+    while !storage.state.read().imm_memtables.is_empty() {
+        storage.force_flush_next_imm_memtable().unwrap();
+    }
+    storage.force_full_compaction().unwrap();
+
+    // Expired puts are dropped at the bottom level, together with the versions they hide.
+    let snapshot = storage.state.read().clone();
+    let mut keys = Vec::new();
+    for id in &snapshot.levels[0].1 {
+        let sst = snapshot.sstables[id].clone();
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        while iter.is_valid() {
+            keys.push(iter.key().raw_ref().to_vec());
+            iter.next().unwrap();
+        }
+    }
+    assert_eq!(keys, [b"live".to_vec(), b"plain".to_vec()]);
+    check_storage(&storage);
+}
+
+#[test]
+fn test_ttl_value_log_and_merge() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.merge_operator = Some(Arc::new(AppendOperator));
+    options.value_log = Some(ValueLogOptions {
+        threshold: 16,
+        // Each large value is written to a file of its own.
+        file_size: 16,
+    });
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    storage
+        .put_with_ttl(b"list", b"a large value in the value log", HOUR)
+        .unwrap();
+    // Merging into an expiring value keeps its expiry.
+    storage.merge(b"list", b",b").unwrap();
+    assert_eq!(
+        storage
+            .state
+            .read()
+            .memtable
+            .get_typed(b"list")
+            .map(|(value_type, _)| value_type),
+        Some(ValueType::PutWithExpiry)
+    );
+    freeze(&storage);
+    storage.merge(b"list", b",c").unwrap();
+    storage
+        .put_with_ttl(
+            b"gone",
+            b"another large value in the value log",
+            Duration::ZERO,
+        )
+        .unwrap();
+    let expected = b"a large value in the value log,b,c";
+    assert_eq!(
+        storage.get(b"list").unwrap().as_deref(),
+        Some(&expected[..])
+    );
+    assert_eq!(scan(&storage), [(b"list".to_vec(), expected.to_vec())]);
+
+    // The values are still readable once the files holding them are collected.
+    for _ in 0..2 {
+        assert!(storage.gc_value_log().unwrap());
+        assert_eq!(
+            storage.get(b"list").unwrap().as_deref(),
+            Some(&expected[..])
+        );
+        assert_eq!(storage.get(b"gone").unwrap(), None);
+        assert_eq!(scan(&storage), [(b"list".to_vec(), expected.to_vec())]);
+    }
+}
+
+#[test]
+fn test_compact_expired() {
+    let to_bottom = CompactionTask::ForceFullCompaction {
+        l0_sstables: Vec::new(),
+        l1_sstables: Vec::new(),
+    };
+    let not_to_bottom = CompactionTask::Tiered(TieredCompactionTask {
+        tiers: Vec::new(),
+        bottom_tier_included: false,
+    });
+    let expiring = |expires_at| {
+        (
+            ValueType::PutWithExpiry,
+            Bytes::from(with_expiry(expires_at, b"1")),
+        )
+    };
+    let put = (ValueType::Put, Bytes::from_static(b"0"));
+    let expired = [expiring(0), put.clone()];
+    assert_eq!(
+        to_bottom.compact_entries(None, b"key", &expired).unwrap(),
+        None
+    );
+    assert_eq!(
+        not_to_bottom
+            .compact_entries(None, b"key", &expired)
+            .unwrap(),
+        Some((ValueType::Delete, Bytes::new()))
+    );
+    let live = [expiring(u64::MAX), put];
+    assert_eq!(
+        to_bottom.compact_entries(None, b"key", &live).unwrap(),
+        Some(expiring(u64::MAX))
+    );
+    // Operands over an expired put apply to no value.
+    let merged = [(ValueType::Merge, Bytes::from_static(b"2")), expiring(0)];
+    assert_eq!(
+        not_to_bottom
+            .compact_entries(Some(&AppendOperator), b"key", &merged)
+            .unwrap(),
+        Some((ValueType::Put, Bytes::from_static(b"2")))
+    );
+}
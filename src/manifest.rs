@@ -12,24 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
-#![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
-
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use bytes::{Buf, BufMut};
 use parking_lot::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 
 use crate::compact::CompactionTask;
 
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// Records every change to the set of memtables and SSTs, so that the state of the storage can be
+/// rebuilt when it is opened again.
 pub struct Manifest {
     file: Arc<Mutex<File>>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ManifestRecord {
     Flush(usize),
     NewMemtable(usize),
@@ -37,12 +40,50 @@ pub enum ManifestRecord {
 }
 
 impl Manifest {
-    pub fn create(_path: impl AsRef<Path>) -> Result<Self> {
-        unimplemented!()
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .context("failed to create manifest")?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
     }
 
-    pub fn recover(_path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
-        unimplemented!()
+    /// Read back the records of a manifest, and continue appending to it. A torn record at the
+    /// end, left by a crash in the middle of a write, is discarded.
+    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path)
+            .context("failed to recover manifest")?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut rbuf = &buf[..];
+        let mut records = Vec::new();
+        while rbuf.remaining() >= SIZEOF_U32 {
+            let len = (&rbuf[..SIZEOF_U32]).get_u32() as usize;
+            if rbuf.remaining() < SIZEOF_U32 + len + SIZEOF_U32 {
+                break;
+            }
+            rbuf.advance(SIZEOF_U32);
+            let json = &rbuf[..len];
+            rbuf.advance(len);
+            if rbuf.get_u32() != crc32fast::hash(json) {
+                bail!("checksum mismatched in manifest");
+            }
+            records.push(serde_json::from_slice(json)?);
+        }
+        file.set_len((buf.len() - rbuf.len()) as u64)?;
+        Ok((
+            Self {
+                file: Arc::new(Mutex::new(file)),
+            },
+            records,
+        ))
     }
 
     pub fn add_record(
@@ -53,7 +94,18 @@ impl Manifest {
         self.add_record_when_init(record)
     }
 
-    pub fn add_record_when_init(&self, _record: ManifestRecord) -> Result<()> {
-        unimplemented!()
+    /// Append a record and fsync it.
+    ///
+    /// | len (u32) | JSON record | checksum (u32) |
+    pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
+        let json = serde_json::to_vec(&record)?;
+        let mut buf = Vec::with_capacity(SIZEOF_U32 + json.len() + SIZEOF_U32);
+        buf.put_u32(json.len() as u32);
+        buf.put_slice(&json);
+        buf.put_u32(crc32fast::hash(&json));
+        let mut file = self.file.lock();
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        Ok(())
     }
 }
@@ -24,27 +24,47 @@ use std::sync::atomic::AtomicUsize;
 use anyhow::Result;
 use crossbeam_skiplist::SkipMap;
 use crossbeam_skiplist::map::Entry;
+use parking_lot::RwLock;
 
 use ouroboros::self_referencing;
 // use serde::de::value;
 
-use crate::iterators::StorageIterator;
+use crate::iterators::{SeekableIterator, StorageIterator};
 use crate::key::KeySlice;
+use crate::range_tombstone::{self, RangeTombstone};
 use crate::table::SsTableBuilder;
-use crate::wal::Wal;
+use crate::value_type::ValueType;
+use crate::wal::{BeforeSync, Wal, WalEntry, WalOptions, WalTicket};
 
-/// A basic mem-table based on crossbeam-skiplist.
+/// A basic mem-table based on crossbeam-skiplist. Each value in the skipmap is prefixed with its
+/// `ValueType` (u8).
 ///
 /// An initial implementation of memtable is part of week 1, day 1. It will be incrementally implemented in other
 /// chapters of week 1 and week 2.
 #[derive(Debug)]
 pub struct MemTable {
     map: Arc<SkipMap<Bytes, Bytes>>,
+    /// Range tombstones, in the order they were written. They never cover keys of `map`.
+    range_tombstones: Arc<RwLock<Vec<RangeTombstone>>>,
     wal: Option<Wal>,
     id: usize,
     approximate_size: Arc<AtomicUsize>,
 }
 
+/// Prefix `value` with its type, as stored in the skipmap.
+pub(crate) fn encode_value(value_type: ValueType, value: &[u8]) -> Bytes {
+    let mut buf = Vec::with_capacity(1 + value.len());
+    buf.push(value_type.to_u8());
+    buf.extend_from_slice(value);
+    buf.into()
+}
+
+/// Split a value stored in the skipmap into its type and the value.
+pub(crate) fn decode_value(stored: Bytes) -> (ValueType, Bytes) {
+    let value_type = ValueType::from_u8(stored[0]).expect("memtable values are always typed");
+    (value_type, stored.slice(1..))
+}
+
 /// Create a bound of `Bytes` from a bound of `&[u8]`.
 pub(crate) fn map_bound(bound: Bound<&[u8]>) -> Bound<Bytes> {
     match bound {
@@ -97,6 +117,7 @@ impl MemTable {
 
         MemTable {
             map,
+            range_tombstones: Arc::default(),
             wal: None,
             id: _id,
             approximate_size,
@@ -104,13 +125,59 @@ impl MemTable {
     }
 
     /// Create a new mem-table with WAL
-    pub fn create_with_wal(_id: usize, _path: impl AsRef<Path>) -> Result<Self> {
-        unimplemented!()
+    pub fn create_with_wal(
+        _id: usize,
+        _path: impl AsRef<Path>,
+        wal_options: WalOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            wal: Some(Wal::create_with_options(_path, wal_options)?),
+            ..Self::create(_id)
+        })
     }
 
     /// Create a memtable from WAL
-    pub fn recover_from_wal(_id: usize, _path: impl AsRef<Path>) -> Result<Self> {
-        unimplemented!()
+    pub fn recover_from_wal(
+        _id: usize,
+        _path: impl AsRef<Path>,
+        wal_options: WalOptions,
+    ) -> Result<Self> {
+        let map = Arc::new(SkipMap::new());
+        let mut range_tombstones = Vec::new();
+        let wal = Wal::recover_with_replay(_path, wal_options, |entry| match entry {
+            WalEntry::Value(key, value_type, value) => {
+                map.insert(key, encode_value(value_type, &value));
+            }
+            WalEntry::RangeTombstone(tombstone) => {
+                tombstone.remove_covered(&map);
+                range_tombstones.push(tombstone);
+            }
+        })?;
+        let approximate_size = map
+            .iter()
+            .map(|entry| entry.key().len() + entry.value().len() - 1)
+            .chain(range_tombstones.iter().map(RangeTombstone::size))
+            .sum();
+        Ok(Self {
+            map,
+            range_tombstones: Arc::new(RwLock::new(range_tombstones)),
+            wal: Some(wal),
+            id: _id,
+            approximate_size: Arc::new(AtomicUsize::new(approximate_size)),
+        })
+    }
+
+    /// Call `before_sync` before every fsync of the WAL, see `Wal::set_before_sync`.
+    pub(crate) fn with_wal_before_sync(self, before_sync: Option<BeforeSync>) -> Self {
+        self.set_wal_before_sync(before_sync);
+        self
+    }
+
+    /// Like `with_wal_before_sync`, for a memtable already in use.
+    pub(crate) fn set_wal_before_sync(&self, before_sync: Option<BeforeSync>) {
+        if let Some(ref wal) = self.wal {
+            wal.set_before_sync(before_sync);
+        }
     }
 
     pub fn for_testing_put_slice(&self, key: &[u8], value: &[u8]) -> Result<()> {
@@ -133,23 +200,33 @@ impl MemTable {
         self.scan_range(range)
     }
 
-    /// Get a value by key.
+    /// Get a value by key. A deleted key has an empty value.
     pub fn get(&self, _key: &[u8]) -> Option<Bytes> {
-        let key_bytes = Bytes::copy_from_slice(_key); // Convert the key slice to `Bytes`
-        // Use the skipmap to get the value by key.
+        self.get_typed(_key).map(|(_, value)| value)
+    }
+
+    /// Get the type and the value of a key.
+    pub fn get_typed(&self, key: &[u8]) -> Option<(ValueType, Bytes)> {
         self.map
-            .get(key_bytes.as_ref())
-            .map(|value| value.clone().value().clone())
+            .get(key)
+            .map(|entry| decode_value(entry.value().clone()))
     }
 
-    /// Put a key-value pair into the mem-table.
+    /// Put a key-value pair into the mem-table. An empty value is a tombstone.
     ///
     /// In week 1, day 1, simply put the key-value pair into the skipmap.
     /// In week 2, day 6, also flush the data to WAL.
     /// In week 3, day 5, modify the function to use the batch API.
     pub fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.put(_key, _value)?;
+        }
+        self.put_skip_wal(_key, ValueType::of_untyped(_value), _value)
+    }
+
+    fn put_skip_wal(&self, _key: &[u8], value_type: ValueType, _value: &[u8]) -> Result<()> {
         let key_bytes = Bytes::copy_from_slice(_key); // Convert the key slice to `Bytes`
-        let value_bytes = Bytes::copy_from_slice(_value); // Convert the value slice to `Bytes`
+        let value_bytes = encode_value(value_type, _value);
         // Insert the key-value pair into the skipmap.
         let ret = self.map.insert(key_bytes, value_bytes);
         if ret.key() == _key {
@@ -162,9 +239,78 @@ impl MemTable {
         Result::Ok(())
     }
 
-    /// Implement this in week 3, day 5.
+    /// Put a batch of key-value pairs, logged as a single WAL record. Empty values are
+    /// tombstones.
     pub fn put_batch(&self, _data: &[(KeySlice, &[u8])]) -> Result<()> {
-        unimplemented!()
+        let data = _data
+            .iter()
+            .map(|(key, value)| (*key, ValueType::of_untyped(value), *value))
+            .collect::<Vec<_>>();
+        self.put_batch_inner(&data, true)
+    }
+
+    /// Put a batch of typed entries, optionally without logging them to the WAL. Unlogged writes
+    /// are lost on a crash.
+    pub(crate) fn put_batch_inner(
+        &self,
+        data: &[(KeySlice, ValueType, &[u8])],
+        write_wal: bool,
+    ) -> Result<()> {
+        if write_wal && let Some(ticket) = self.enqueue_batch(data, false)? {
+            ticket.wait()?;
+        }
+        self.put_batch_skip_wal(data)
+    }
+
+    /// Enqueue a batch to the WAL, fsynced if `sync`, without waiting for it to be written; see
+    /// `Wal::enqueue_batch`. Returns `None` without a WAL.
+    pub(crate) fn enqueue_batch(
+        &self,
+        data: &[(KeySlice, ValueType, &[u8])],
+        sync: bool,
+    ) -> Result<Option<WalTicket>> {
+        let Some(ref wal) = self.wal else {
+            return Ok(None);
+        };
+        let records = data
+            .iter()
+            .map(|(key, value_type, value)| (key.raw_ref(), *value_type, *value))
+            .collect::<Vec<_>>();
+        Ok(Some(wal.enqueue_batch(&records, sync)?))
+    }
+
+    /// Put a batch of typed entries without logging them to the WAL.
+    pub(crate) fn put_batch_skip_wal(&self, data: &[(KeySlice, ValueType, &[u8])]) -> Result<()> {
+        for (key, value_type, value) in data {
+            self.put_skip_wal(key.raw_ref(), *value_type, value)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the keys in `[start, end)` from this memtable and all older data. The caller must
+    /// serialize it with the other writes to this memtable.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.delete_range(start, end)?;
+        }
+        let tombstone = RangeTombstone::new(start, end);
+        self.approximate_size
+            .fetch_add(tombstone.size(), std::sync::atomic::Ordering::Relaxed);
+        // Hide the older data first, so that a concurrent read never misses the tombstone while
+        // the keys of this memtable are already removed.
+        self.range_tombstones.write().push(tombstone.clone());
+        tombstone.remove_covered(&self.map);
+        Ok(())
+    }
+
+    /// The range tombstones written to this memtable.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().clone()
+    }
+
+    /// Whether a range tombstone of this memtable deletes `key` from older data.
+    pub fn range_tombstone_covers(&self, key: &[u8]) -> bool {
+        range_tombstone::is_covered(&self.range_tombstones.read(), key)
     }
 
     pub fn sync_wal(&self) -> Result<()> {
@@ -176,32 +322,53 @@ impl MemTable {
 
     /// Get an iterator over a range of keys.
     pub fn scan(&self, _lower: Bound<&[u8]>, _upper: Bound<&[u8]>) -> MemTableIterator {
-        let (lower_bound, upper_bound) = (map_bound(_lower), map_bound(_upper));
-        let mut iter = MemTableIteratorBuilder {
-            map: self.map.clone(), // Pass the skipmap
-            iter_builder: |map| map.range((lower_bound, upper_bound)),
-            item: (Bytes::new(), Bytes::new()), // Initialize with empty Bytes for the first entry
-        }
-        .build();
-        iter.next().unwrap();
-        iter
+        self.range_iter((map_bound(_lower), map_bound(_upper)), false)
+    }
+
+    /// Get an iterator over a range of keys in descending order.
+    pub fn scan_rev(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> MemTableIterator {
+        self.range_iter((map_bound(lower), map_bound(upper)), true)
     }
 
     pub fn scan_range<R: ToBounds>(&self, range: R) -> MemTableIterator {
-        let (lower_bound, upper_bound) = range.to_bounds();
+        self.range_iter(range.to_bounds(), false)
+    }
+
+    fn range_iter(&self, bounds: (Bound<Bytes>, Bound<Bytes>), reverse: bool) -> MemTableIterator {
+        let range = bounds.clone();
         let mut iter = MemTableIteratorBuilder {
             map: self.map.clone(), // Pass the skipmap
-            iter_builder: |map| map.range((lower_bound, upper_bound)),
-            item: (Bytes::new(), Bytes::new()), // Initialize with empty Bytes for the first entry
+            iter_builder: |map| map.range(range),
+            item: (Bytes::new(), ValueType::Put, Bytes::new()), // Initialize with empty Bytes for the first entry
+            bounds,
+            reverse,
         }
         .build();
         iter.next().unwrap();
         iter
     }
 
+    /// Fail if a write to the WAL of this memtable failed. The memtable may then hold writes that
+    /// are not in the WAL, so it is poisoned: it is neither read nor flushed anymore, and its
+    /// writes are only recovered from the WAL once the storage is reopened.
+    pub(crate) fn check_wal(&self) -> Result<()> {
+        match self.wal {
+            Some(ref wal) => wal.check(),
+            None => Ok(()),
+        }
+    }
+
     /// Flush the mem-table to SSTable. Implement in week 1 day 6.
-    pub fn flush(&self, _builder: &mut SsTableBuilder) -> Result<()> {
-        unimplemented!()
+    pub fn flush(&self, builder: &mut SsTableBuilder) -> Result<()> {
+        self.check_wal()?;
+        for entry in self.map.iter() {
+            let (value_type, value) = decode_value(entry.value().clone());
+            builder.add_typed(KeySlice::from_slice(entry.key()), value_type, &value);
+        }
+        for tombstone in self.range_tombstones.read().iter() {
+            builder.add_range_tombstone(tombstone.clone());
+        }
+        Ok(())
     }
 
     pub fn id(&self) -> usize {
@@ -215,7 +382,7 @@ impl MemTable {
 
     /// Only use this function when closing the database
     pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+        self.map.is_empty() && self.range_tombstones.read().is_empty()
     }
 }
 
@@ -235,13 +402,63 @@ pub struct MemTableIterator {
     #[not_covariant]
     iter: SkipMapRangeIter<'this>,
     /// Stores the current key-value pair.
-    item: (Bytes, Bytes),
+    item: (Bytes, ValueType, Bytes),
+    /// The range of the scan, which seeks stay within.
+    bounds: (Bound<Bytes>, Bound<Bytes>),
+    /// Whether the range is iterated in descending order.
+    reverse: bool,
 }
 impl MemTableIterator {
-    fn entry_to_item(entry: Option<Entry<'_, Bytes, Bytes>>) -> (Bytes, Bytes) {
+    fn entry_to_item(entry: Option<Entry<'_, Bytes, Bytes>>) -> (Bytes, ValueType, Bytes) {
         entry
-            .map(|x| (x.key().clone(), x.value().clone()))
-            .unwrap_or_else(|| (Bytes::from_static(&[]), Bytes::from_static(&[])))
+            .map(|x| {
+                let (value_type, value) = decode_value(x.value().clone());
+                (x.key().clone(), value_type, value)
+            })
+            .unwrap_or_else(|| (Bytes::new(), ValueType::Put, Bytes::new()))
+    }
+
+    /// Restart the iteration over `range` of the skipmap.
+    fn reposition(&mut self, range: (Bound<Bytes>, Bound<Bytes>), reverse: bool) -> Result<()> {
+        self.with_mut(|fields| {
+            *fields.iter = fields.map.range(range);
+            *fields.reverse = reverse;
+        });
+        self.next()
+    }
+}
+
+impl SeekableIterator for MemTableIterator {
+    fn seek_to_first(&mut self) -> Result<()> {
+        let range = self.borrow_bounds().clone();
+        self.reposition(range, false)
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        let (lower, upper) = self.borrow_bounds().clone();
+        let lower = match &lower {
+            Bound::Included(bound) | Bound::Excluded(bound) if key <= bound.as_ref() => {
+                lower.clone()
+            }
+            _ => Bound::Included(Bytes::copy_from_slice(key)),
+        };
+        self.reposition((lower, upper), false)
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        let range = self.borrow_bounds().clone();
+        self.reposition(range, true)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        let (lower, upper) = self.borrow_bounds().clone();
+        let upper = match &upper {
+            Bound::Included(bound) | Bound::Excluded(bound) if key >= bound.as_ref() => {
+                upper.clone()
+            }
+            _ => Bound::Included(Bytes::copy_from_slice(key)),
+        };
+        self.reposition((lower, upper), true)
     }
 }
 
@@ -250,14 +467,18 @@ impl StorageIterator for MemTableIterator {
 
     fn value(&self) -> &[u8] {
         self.borrow_item()
-            .1 // Get the value from the tuple (Bytes, Bytes)
+            .2 // Get the value from the tuple (Bytes, ValueType, Bytes)
             .as_ref() // Convert to &[u8] 
     }
 
-    fn key(&self) -> KeySlice {
+    fn value_type(&self) -> ValueType {
+        self.borrow_item().1
+    }
+
+    fn key(&self) -> KeySlice<'_> {
         KeySlice::from_slice(
             self.borrow_item()
-                .0 // Get the key from the tuple (Bytes, Bytes)
+                .0 // Get the key from the tuple (Bytes, ValueType, Bytes)
                 .as_ref(), // Convert to &[u8]
         )
     }
@@ -267,8 +488,15 @@ impl StorageIterator for MemTableIterator {
     }
 
     fn next(&mut self) -> Result<()> {
-        // Move to the next entry in the skipmap iterator.
-        let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
+        // Move to the next entry in the skipmap iterator, or the previous one in reverse.
+        let reverse = *self.borrow_reverse();
+        let entry = self.with_iter_mut(|iter| {
+            MemTableIterator::entry_to_item(if reverse {
+                iter.next_back()
+            } else {
+                iter.next()
+            })
+        });
         // Update the current item in the MemTableIterator.
         self.with_mut(|x| {
             *x.item = entry; // Update the item with the new key-value pair
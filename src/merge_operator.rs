@@ -0,0 +1,196 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Merge operators, which let `merge` update a key without reading it first.
+//!
+//! `merge` stores an operand as a `ValueType::Merge` entry. Reads combine the operands of a key,
+//! from the newest layer down to its first put or tombstone, with the value they apply to. Each
+//! memtable and SST keeps a single entry per key, so an operand written to a memtable is combined
+//! right away with the entry of the key already there, and operators must be associative.
+
+use std::fmt::Debug;
+
+use anyhow::{Result, bail, ensure};
+use bytes::Bytes;
+
+use crate::lsm_storage::LsmStorageState;
+use crate::value_log::ValueLog;
+use crate::value_type::{ValueType, split_expiry, split_expiry_bytes, with_expiry};
+
+/// Combines merge operands with the value they apply to. Registered with
+/// `LsmStorageOptions::merge_operator`, and must be the same every time a directory is opened.
+pub trait MergeOperator: Send + Sync + Debug {
+    /// Apply `operands`, from the oldest, to the `existing` value of `key`, or to no value if the
+    /// key does not exist.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Result<Vec<u8>>;
+
+    /// Combine consecutive `operands` of `key`, from the oldest, into a single operand with the
+    /// same effect.
+    fn partial_merge(&self, key: &[u8], operands: &[&[u8]]) -> Result<Vec<u8>>;
+}
+
+/// Adds up u64 counters, encoded as 8 little-endian bytes. A missing value counts as 0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64AddOperator;
+
+impl U64AddOperator {
+    fn decode(value: &[u8]) -> Result<u64> {
+        let Ok(value) = value.try_into() else {
+            bail!("u64 counter has {} bytes", value.len());
+        };
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn sum<'a>(values: impl IntoIterator<Item = &'a [u8]>) -> Result<Vec<u8>> {
+        let mut sum = 0u64;
+        for value in values {
+            sum = sum.wrapping_add(Self::decode(value)?);
+        }
+        Ok(sum.to_le_bytes().to_vec())
+    }
+}
+
+impl MergeOperator for U64AddOperator {
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Result<Vec<u8>> {
+        Self::sum(existing.into_iter().chain(operands.iter().copied()))
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Result<Vec<u8>> {
+        Self::sum(operands.iter().copied())
+    }
+}
+
+/// Appends each operand to the bytes of the value. A missing value counts as empty.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AppendOperator;
+
+impl MergeOperator for AppendOperator {
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Result<Vec<u8>> {
+        Ok(existing
+            .into_iter()
+            .chain(operands.iter().copied())
+            .flatten()
+            .copied()
+            .collect())
+    }
+
+    fn partial_merge(&self, _key: &[u8], operands: &[&[u8]]) -> Result<Vec<u8>> {
+        Ok(operands.concat())
+    }
+}
+
+/// The operator to combine the merge operands of `key` with, failing if there is none.
+pub(crate) fn require_operator<'a>(
+    operator: Option<&'a dyn MergeOperator>,
+    key: &[u8],
+) -> Result<&'a dyn MergeOperator> {
+    let Some(operator) = operator else {
+        bail!(
+            "no merge operator to combine the merge operands of {:?}",
+            key
+        );
+    };
+    Ok(operator)
+}
+
+/// Combine the entries of `key`, from the newest, into the entry they amount to: the operands down
+/// to the first put or tombstone are applied to it, and the entries below it are dropped. Without
+/// a put or tombstone, the operands are combined into a single one, unless `bottom` tells there
+/// is nothing older left, in which case they apply to no value. Operands applied to an expiring
+/// put keep its expiry, unless it has already expired, in which case they apply to no value.
+///
+/// The values are the ones written by the user, resolved from the value log.
+pub(crate) fn merge_entries(
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    entries: &[(ValueType, Bytes)],
+    bottom: bool,
+) -> Result<(ValueType, Bytes)> {
+    ensure!(!entries.is_empty(), "no entry to merge");
+    let num_operands = entries
+        .iter()
+        .take_while(|(value_type, _)| *value_type == ValueType::Merge)
+        .count();
+    if num_operands == 0 {
+        return Ok(entries[0].clone());
+    }
+    let operator = require_operator(operator, key)?;
+    let operands = entries[..num_operands]
+        .iter()
+        .rev()
+        .map(|(_, value)| &value[..])
+        .collect::<Vec<_>>();
+    let merged = match entries.get(num_operands) {
+        Some((ValueType::Put, value)) => (
+            ValueType::Put,
+            operator.full_merge(key, Some(value), &operands)?,
+        ),
+        Some((ValueType::PutWithExpiry, value)) if !ValueType::PutWithExpiry.is_expired(value) => {
+            let (expires_at, value) = split_expiry(value)?;
+            let merged = operator.full_merge(key, Some(value), &operands)?;
+            (ValueType::PutWithExpiry, with_expiry(expires_at, &merged))
+        }
+        Some(_) => (ValueType::Put, operator.full_merge(key, None, &operands)?),
+        None if bottom => (ValueType::Put, operator.full_merge(key, None, &operands)?),
+        None => (ValueType::Merge, operator.partial_merge(key, &operands)?),
+    };
+    Ok((merged.0, merged.1.into()))
+}
+
+/// The value of `key` in `snapshot`, whose newest entry is a merge operand, or `None` if a
+/// concurrent write deleted it or it has expired.
+pub(crate) fn merge_value(
+    snapshot: &LsmStorageState,
+    key: &[u8],
+    operator: Option<&dyn MergeOperator>,
+    value_log: Option<&ValueLog>,
+) -> Result<Option<Bytes>> {
+    let mut entries = Vec::new();
+    snapshot.visit_entries(key, |value_type, value| {
+        entries.push((value_type, value));
+        value_type == ValueType::Merge
+    })?;
+    if let Some(value_log) = value_log {
+        for (value_type, value) in &mut entries {
+            *value = value_log.resolve_entry(*value_type, value.clone())?;
+        }
+    }
+    if entries.is_empty() {
+        return Ok(None);
+    }
+    let (value_type, value) = merge_entries(operator, key, &entries, true)?;
+    match value_type {
+        _ if value_type.hides_key(&value) => Ok(None),
+        ValueType::PutWithExpiry => Ok(Some(split_expiry_bytes(&value)?.1)),
+        _ => Ok(Some(value)),
+    }
+}
+
+#[cfg(test)]
+mod tests;
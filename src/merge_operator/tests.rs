@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::compact::{CompactionTask, TieredCompactionTask};
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::{DurabilityMode, LsmStorageInner, LsmStorageOptions};
+use crate::merge_operator::{AppendOperator, MergeOperator, U64AddOperator};
+use crate::table::SsTableIterator;
+use crate::test_harness::key_of;
+use crate::value_log::ValueLogOptions;
+use crate::value_type::ValueType;
+
+fn options(merge_operator: impl MergeOperator + 'static) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    options.merge_operator = Some(Arc::new(merge_operator));
+    options
+}
+
+fn freeze(storage: &LsmStorageInner) {
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+}
+
+fn check_counters(storage: &LsmStorageInner, expected: &BTreeMap<Vec<u8>, u64>) {
+    for idx in 0..25 {
+        let key = key_of(idx);
+        assert_eq!(
+            storage.get(&key).unwrap(),
+            expected
+                .get(&key)
+                .map(|x| Bytes::copy_from_slice(&x.to_le_bytes())),
+            "get({:?})",
+            key
+        );
+    }
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut scanned = BTreeMap::new();
+    while iter.is_valid() {
+        let value = u64::from_le_bytes(iter.value().try_into().unwrap());
+        scanned.insert(iter.key().to_vec(), value);
+        iter.next().unwrap();
+    }
+    assert_eq!(&scanned, expected);
+}
+
+#[test]
+fn test_u64_add_across_layers() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorageInner::open(dir.path(), options(U64AddOperator)).unwrap();
+    let mut expected = BTreeMap::new();
+
+    // An SST with puts, tombstones and operands.
+    let mut builder = storage.new_sst_builder();
+    for idx in 0..20 {
+        let key = key_of(idx);
+        let (value_type, value) = match idx % 4 {
+            0 => (ValueType::Put, 100),
+            1 => (ValueType::Delete, 0),
+            _ => (ValueType::Merge, 10),
+        };
+        let value = if value_type == ValueType::Delete {
+            Vec::new()
+        } else {
+            expected.insert(key.clone(), value);
+            u64::to_le_bytes(value).to_vec()
+        };
+        builder.add_typed(
+            KeySlice::for_testing_from_slice_no_ts(&key),
+            value_type,
+            &value,
+        );
+    }
+    let sst_id = storage.next_sst_id();
+    let sst = builder
+        .build(
+            sst_id,
+            Some(storage.block_cache.clone()),
+            storage.path_of_sst(sst_id),
+        )
+        .unwrap();
+    {
+        let mut guard = storage.state.write();
+        let mut snapshot = guard.as_ref().clone();
+        snapshot.l0_sstables.insert(0, sst_id);
+        snapshot.sstables.insert(sst_id, Arc::new(sst));
+        *guard = Arc::new(snapshot);
+    }
+    check_counters(&storage, &expected);
+
+    for round in 0..3 {
+        for idx in (round..25).step_by(2) {
+            storage
+                .merge(&key_of(idx), &u64::to_le_bytes(idx as u64))
+                .unwrap();
+            *expected.entry(key_of(idx)).or_default() += idx as u64;
+        }
+        // Several operands of the same key in a batch and in the memtable.
+        storage.merge(&key_of(3), &u64::to_le_bytes(1)).unwrap();
+        *expected.get_mut(&key_of(3)).unwrap() += 1;
+        check_counters(&storage, &expected);
+        freeze(&storage);
+    }
+
+    // Operands on top of a put and a tombstone in the same memtable.
+    storage.put(&key_of(4), &u64::to_le_bytes(7)).unwrap();
+    storage.merge(&key_of(4), &u64::to_le_bytes(1)).unwrap();
+    expected.insert(key_of(4), 8);
+    storage.delete(&key_of(6)).unwrap();
+    storage.merge(&key_of(6), &u64::to_le_bytes(2)).unwrap();
+    expected.insert(key_of(6), 2);
+    storage.delete(&key_of(8)).unwrap();
+    expected.remove(&key_of(8));
+    check_counters(&storage, &expected);
+}
+
+#[test]
+fn test_merge_wal_recovery_and_value_log() {
+    let dir = tempdir().unwrap();
+    let mut options = options(AppendOperator);
+    options.value_log = Some(ValueLogOptions {
+        threshold: 16,
+        file_size: 32 << 10,
+    });
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    storage
+        .put(b"list", b"a large value in the value log")
+        .unwrap();
+    storage.merge(b"list", b",b").unwrap();
+    freeze(&storage);
+    storage.merge(b"list", b",c").unwrap();
+    storage
+        .merge(b"other", b"another large operand in the value log")
+        .unwrap();
+    storage.merge(b"other", b"!").unwrap();
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    assert_eq!(
+        storage.get(b"list").unwrap().as_deref(),
+        Some(&b"a large value in the value log,b,c"[..])
+    );
+    assert_eq!(
+        storage.get(b"other").unwrap().as_deref(),
+        Some(&b"another large operand in the value log!"[..])
+    );
+}
+
+#[test]
+fn test_merge_full_compaction() {
+    let dir = tempdir().unwrap();
+    let mut options = options(AppendOperator);
+    options.value_log = Some(ValueLogOptions {
+        threshold: 16,
+        file_size: 32 << 10,
+    });
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    storage
+        .put(b"list", b"a large value in the value log")
+        .unwrap();
+    freeze(&storage);
+    storage.merge(b"list", b",b").unwrap();
+    storage
+        .merge(b"other", b"another large operand in the value log")
+        .unwrap();
+    freeze(&storage);
+    storage.merge(b"other", b"!").unwrap();
+    storage.merge(b"list", b",c").unwrap();
+    freeze(&storage);
+    while !storage.state.read().imm_memtables.is_empty() {
+        storage.force_flush_next_imm_memtable().unwrap();
+    }
+    storage.force_full_compaction().unwrap();
+
+    // The operands are applied at the bottom level, and the merged values are separated again.
+    let snapshot = storage.state.read().clone();
+    assert!(snapshot.l0_sstables.is_empty());
+    for id in &snapshot.levels[0].1 {
+        let sst = snapshot.sstables[id].clone();
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        while iter.is_valid() {
+            assert_eq!(iter.value_type(), ValueType::Put);
+            iter.next().unwrap();
+        }
+    }
+    drop(snapshot);
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    assert_eq!(
+        storage.get(b"list").unwrap().as_deref(),
+        Some(&b"a large value in the value log,b,c"[..])
+    );
+    assert_eq!(
+        storage.get(b"other").unwrap().as_deref(),
+        Some(&b"another large operand in the value log!"[..])
+    );
+}
+
+#[test]
+fn test_merge_after_delete_range() {
+    let dir = tempdir().unwrap();
+    let storage = LsmStorageInner::open(dir.path(), options(AppendOperator)).unwrap();
+    storage.put(b"a", b"old").unwrap();
+    storage.merge(b"b", b"old").unwrap();
+    freeze(&storage);
+    storage.delete_range(b"a", b"c").unwrap();
+    storage.merge(b"a", b"new").unwrap();
+    freeze(&storage);
+    storage.merge(b"b", b"new").unwrap();
+    assert_eq!(storage.get(b"a").unwrap().as_deref(), Some(&b"new"[..]));
+    assert_eq!(storage.get(b"b").unwrap().as_deref(), Some(&b"new"[..]));
+}
+
+#[test]
+fn test_merge_without_operator() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    assert!(storage.merge(b"key", b"operand").is_err());
+    assert_eq!(storage.get(b"key").unwrap(), None);
+}
+
+#[test]
+fn test_compact_entries() {
+    let to_bottom = CompactionTask::ForceFullCompaction {
+        l0_sstables: Vec::new(),
+        l1_sstables: Vec::new(),
+    };
+    let not_to_bottom = CompactionTask::Tiered(TieredCompactionTask {
+        tiers: Vec::new(),
+        bottom_tier_included: false,
+    });
+    let entry = |value_type, value: u64| (value_type, Bytes::copy_from_slice(&value.to_le_bytes()));
+    let operator: Option<&dyn MergeOperator> = Some(&U64AddOperator);
+    let operands = [entry(ValueType::Merge, 1), entry(ValueType::Merge, 2)];
+    assert_eq!(
+        not_to_bottom
+            .compact_entries(operator, b"key", &operands)
+            .unwrap(),
+        Some(entry(ValueType::Merge, 3))
+    );
+    assert_eq!(
+        to_bottom
+            .compact_entries(operator, b"key", &operands)
+            .unwrap(),
+        Some(entry(ValueType::Put, 3))
+    );
+    let with_put = [
+        entry(ValueType::Merge, 1),
+        entry(ValueType::Put, 5),
+        entry(ValueType::Merge, 9),
+    ];
+    assert_eq!(
+        not_to_bottom
+            .compact_entries(operator, b"key", &with_put)
+            .unwrap(),
+        Some(entry(ValueType::Put, 6))
+    );
+    let deleted = [
+        entry(ValueType::Merge, 1),
+        (ValueType::Delete, Bytes::new()),
+    ];
+    assert_eq!(
+        not_to_bottom
+            .compact_entries(operator, b"key", &deleted)
+            .unwrap(),
+        Some(entry(ValueType::Put, 1))
+    );
+    assert!(
+        not_to_bottom
+            .compact_entries(None, b"key", &operands)
+            .is_err()
+    );
+}
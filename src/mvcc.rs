@@ -15,19 +15,24 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+mod lock;
 pub mod txn;
 mod watermark;
 
 use std::{
     collections::{BTreeMap, HashSet},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
 };
 
+use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
 use crate::lsm_storage::LsmStorageInner;
 
-use self::{txn::Transaction, watermark::Watermark};
+use self::{lock::LockManager, txn::Transaction, watermark::Watermark};
 
 pub(crate) struct CommittedTxnData {
     pub(crate) key_hashes: HashSet<u32>,
@@ -42,6 +47,9 @@ pub(crate) struct LsmMvccInner {
     pub(crate) commit_lock: Mutex<()>,
     pub(crate) ts: Arc<Mutex<(u64, Watermark)>>,
     pub(crate) committed_txns: Arc<Mutex<BTreeMap<u64, CommittedTxnData>>>,
+    /// Row locks taken by `Transaction::get_for_update`.
+    pub(crate) lock_manager: LockManager,
+    next_txn_id: AtomicU64,
 }
 
 impl LsmMvccInner {
@@ -51,6 +59,8 @@ impl LsmMvccInner {
             commit_lock: Mutex::new(()),
             ts: Arc::new(Mutex::new((initial_ts, Watermark::new()))),
             committed_txns: Arc::new(Mutex::new(BTreeMap::new())),
+            lock_manager: LockManager::new(),
+            next_txn_id: AtomicU64::new(0),
         }
     }
 
@@ -69,6 +79,23 @@ impl LsmMvccInner {
     }
 
     pub fn new_txn(&self, inner: Arc<LsmStorageInner>, serializable: bool) -> Arc<Transaction> {
-        unimplemented!()
+        let mut ts = self.ts.lock();
+        let read_ts = ts.0;
+        ts.1.add_reader(read_ts);
+        Arc::new(Transaction {
+            txn_id: self.next_txn_id.fetch_add(1, Ordering::SeqCst),
+            read_ts,
+            inner,
+            local_storage: Arc::new(SkipMap::new()),
+            committed: Arc::new(AtomicBool::new(false)),
+            key_hashes: if serializable {
+                Some(Mutex::new((HashSet::new(), HashSet::new())))
+            } else {
+                None
+            },
+            undo_log: Mutex::new(Vec::new()),
+            next_generation: AtomicU64::new(1),
+            commit_hooks: Mutex::new(Vec::new()),
+        })
     }
 }
@@ -0,0 +1,121 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+
+#[derive(Default)]
+struct LockState {
+    /// The owner transaction of each locked key.
+    owners: HashMap<Bytes, u64>,
+    /// The keys held by each transaction.
+    held: HashMap<u64, HashSet<Bytes>>,
+    /// The wait-for graph. A transaction waits for at most one other transaction at a time.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockState {
+    /// Whether `txn_id` waiting for `owner` would close a cycle in the wait-for graph.
+    fn would_deadlock(&self, txn_id: u64, owner: u64) -> bool {
+        let mut current = owner;
+        // A cycle never forms without going through `txn_id`, so the walk is bounded by the
+        // number of waiters.
+        for _ in 0..=self.waits_for.len() {
+            if current == txn_id {
+                return true;
+            }
+            match self.waits_for.get(&current) {
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+/// In-memory exclusive row locks for pessimistic transactions.
+pub(crate) struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(LockState::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Lock `key` for `txn_id`, waiting at most `timeout` for the current owner to release it.
+    /// Fails immediately if waiting would deadlock.
+    pub fn lock(&self, txn_id: u64, key: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock();
+        loop {
+            let owner = match state.owners.get(key) {
+                None => {
+                    let key = Bytes::copy_from_slice(key);
+                    state.owners.insert(key.clone(), txn_id);
+                    state.held.entry(txn_id).or_default().insert(key);
+                    state.waits_for.remove(&txn_id);
+                    return Ok(());
+                }
+                Some(&owner) if owner == txn_id => {
+                    state.waits_for.remove(&txn_id);
+                    return Ok(());
+                }
+                Some(&owner) => owner,
+            };
+            if state.would_deadlock(txn_id, owner) {
+                state.waits_for.remove(&txn_id);
+                bail!(
+                    "deadlock detected: txn {} waits for txn {} on key {:?}",
+                    txn_id,
+                    owner,
+                    Bytes::copy_from_slice(key)
+                );
+            }
+            state.waits_for.insert(txn_id, owner);
+            if self.released.wait_until(&mut state, deadline).timed_out() {
+                state.waits_for.remove(&txn_id);
+                bail!(
+                    "lock wait timeout: txn {} waits for txn {} on key {:?}",
+                    txn_id,
+                    owner,
+                    Bytes::copy_from_slice(key)
+                );
+            }
+        }
+    }
+
+    /// Release all locks held by `txn_id`.
+    pub fn unlock_all(&self, txn_id: u64) {
+        let mut state = self.state.lock();
+        state.waits_for.remove(&txn_id);
+        if let Some(keys) = state.held.remove(&txn_id) {
+            for key in keys {
+                state.owners.remove(&key);
+            }
+            self.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
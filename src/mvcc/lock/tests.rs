@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tempfile::tempdir;
+
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions};
+
+fn open_storage(dir: &tempfile::TempDir) -> Arc<LsmStorageInner> {
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.lock_wait_timeout = Duration::from_millis(200);
+    Arc::new(LsmStorageInner::open(dir.path(), options).unwrap())
+}
+
+#[test]
+fn test_get_for_update_blocks_until_released() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir);
+    storage.put(b"balance", b"100").unwrap();
+
+    let txn1 = storage.new_txn().unwrap();
+    assert_eq!(
+        txn1.get_for_update(b"balance").unwrap().as_deref(),
+        Some(&b"100"[..])
+    );
+    // Re-locking a key the transaction already holds succeeds immediately.
+    txn1.get_for_update(b"balance").unwrap();
+
+    let txn2 = storage.new_txn().unwrap();
+    let start = Instant::now();
+    assert!(txn2.get_for_update(b"balance").is_err());
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    let handle = {
+        let txn2 = txn2.clone();
+        std::thread::spawn(move || txn2.get_for_update(b"balance"))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    drop(txn1);
+    assert!(handle.join().unwrap().is_ok());
+}
+
+#[test]
+fn test_get_for_update_deadlock() {
+    let dir = tempdir().unwrap();
+    let storage = open_storage(&dir);
+
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn1.get_for_update(b"a").unwrap();
+    txn2.get_for_update(b"b").unwrap();
+
+    let handle = {
+        let txn1 = txn1.clone();
+        std::thread::spawn(move || txn1.get_for_update(b"b"))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    let err = txn2.get_for_update(b"a").unwrap_err();
+    assert!(err.to_string().contains("deadlock"));
+    assert!(start.elapsed() < Duration::from_millis(200));
+
+    drop(txn2);
+    assert!(handle.join().unwrap().is_ok());
+}
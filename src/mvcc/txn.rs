@@ -18,10 +18,14 @@
 use std::{
     collections::HashSet,
     ops::Bound,
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 use bytes::Bytes;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
@@ -30,21 +34,98 @@ use parking_lot::Mutex;
 use crate::{
     iterators::{StorageIterator, two_merge_iterator::TwoMergeIterator},
     lsm_iterator::{FusedIterator, LsmIterator},
-    lsm_storage::LsmStorageInner,
+    lsm_storage::{LsmStorageInner, WriteBatchRecord},
+    mem_table::{decode_value, encode_value},
+    mvcc::CommittedTxnData,
+    value_type::{ValueType, now_millis, split_expiry_bytes, with_expiry},
 };
 
+/// When `Transaction::commit_with` acknowledges a commit. Without a WAL
+/// (`DurabilityMode::NoWal`), every level is the same as `Memory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommitDurability {
+    /// Once the writes are applied to the memtable. They are still logged, in commit order, by the
+    /// next write to the WAL, and may be lost on a crash until then.
+    Memory,
+    /// Once the writes are appended to the WAL, which may still sit in OS buffers.
+    #[default]
+    Wal,
+    /// Once the WAL has been fsynced.
+    WalSynced,
+}
+
+type CommitHook = Box<dyn FnOnce(u64) + Send>;
+
+/// The value of a write buffered by a transaction, or `None` if it deletes the key or has expired.
+fn buffered_value(stored: Bytes) -> Result<Option<Bytes>> {
+    let (value_type, value) = decode_value(stored);
+    if value_type.hides_key(&value) {
+        return Ok(None);
+    }
+    match value_type {
+        ValueType::PutWithExpiry => Ok(Some(split_expiry_bytes(&value)?.1)),
+        _ => Ok(Some(value)),
+    }
+}
+
 pub struct Transaction {
+    /// Identifies the transaction as a lock owner.
+    pub(crate) txn_id: u64,
     pub(crate) read_ts: u64,
     pub(crate) inner: Arc<LsmStorageInner>,
+    /// The buffered writes, with their value type prefixed as in a memtable.
     pub(crate) local_storage: Arc<SkipMap<Bytes, Bytes>>,
     pub(crate) committed: Arc<AtomicBool>,
     /// Write set and read set
     pub(crate) key_hashes: Option<Mutex<(HashSet<u32>, HashSet<u32>)>>,
+    /// The previous local value of every buffered write, in write order, with the generation of
+    /// the write. Used to undo writes when rolling back to a savepoint.
+    pub(crate) undo_log: Mutex<Vec<(Bytes, Option<Bytes>, u64)>>,
+    /// The generation of the next buffered write. Each write gets a new one, so that a savepoint
+    /// cannot match the writes made after rolling back past it.
+    pub(crate) next_generation: AtomicU64,
+    /// Callbacks to run with the commit timestamp once the transaction commits.
+    pub(crate) commit_hooks: Mutex<Vec<CommitHook>>,
+}
+
+/// A position in a transaction's buffered writes, created by `Transaction::savepoint`, with the
+/// generation of the write before it (0 at the start).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint {
+    position: usize,
+    generation: u64,
 }
 
 impl Transaction {
     pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
-        unimplemented!()
+        if self.committed.load(Ordering::SeqCst) {
+            panic!("cannot operate on committed txn!");
+        }
+        if let Some(entry) = self.local_storage.get(key) {
+            return buffered_value(entry.value().clone());
+        }
+        if let Some(key_hashes) = &self.key_hashes {
+            key_hashes.lock().1.insert(farmhash::hash32(key));
+        }
+        self.inner.get(key)
+    }
+
+    /// Lock `key` until the transaction ends and read its latest committed value.
+    ///
+    /// Waits up to `LsmStorageOptions::lock_wait_timeout` for another transaction holding the lock,
+    /// and fails right away if waiting would deadlock. The key is not added to the read set, so a
+    /// locked read never causes a serializable conflict at commit.
+    pub fn get_for_update(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        if self.committed.load(Ordering::SeqCst) {
+            panic!("cannot operate on committed txn!");
+        }
+        let mvcc = self.inner.mvcc();
+        mvcc.lock_manager
+            .lock(self.txn_id, key, self.inner.options.lock_wait_timeout)?;
+        if let Some(entry) = self.local_storage.get(key) {
+            return buffered_value(entry.value().clone());
+        }
+        self.inner.get(key)
     }
 
     pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
@@ -52,20 +133,195 @@ impl Transaction {
     }
 
     pub fn put(&self, key: &[u8], value: &[u8]) {
-        unimplemented!()
+        self.put_typed(key, ValueType::Put, value);
     }
 
     pub fn delete(&self, key: &[u8]) {
-        unimplemented!()
+        self.put_typed(key, ValueType::Delete, b"");
+    }
+
+    /// Put a key-value pair that expires after `ttl`, counted from now rather than from the
+    /// commit, see `LsmStorageInner::put_with_ttl`. The expiry is written as is at commit.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let value = with_expiry(now_millis().saturating_add(ttl), value);
+        self.put_typed(key, ValueType::PutWithExpiry, &value);
+    }
+
+    fn put_typed(&self, key: &[u8], value_type: ValueType, value: &[u8]) {
+        if self.committed.load(Ordering::SeqCst) {
+            panic!("cannot operate on committed txn!");
+        }
+        let key = Bytes::copy_from_slice(key);
+        let prev = self
+            .local_storage
+            .get(&key)
+            .map(|entry| entry.value().clone());
+        let generation = self.next_generation.fetch_add(1, Ordering::SeqCst);
+        self.undo_log.lock().push((key.clone(), prev, generation));
+        if let Some(key_hashes) = &self.key_hashes {
+            key_hashes.lock().0.insert(farmhash::hash32(&key));
+        }
+        self.local_storage
+            .insert(key, encode_value(value_type, value));
+    }
+
+    /// Mark the current state of the buffered writes, so that later writes can be undone with
+    /// `rollback_to`.
+    pub fn savepoint(&self) -> Savepoint {
+        let undo_log = self.undo_log.lock();
+        Savepoint {
+            position: undo_log.len(),
+            generation: undo_log.last().map_or(0, |(_, _, generation)| *generation),
+        }
+    }
+
+    /// Undo all writes made after `savepoint`, which stays valid for further rollbacks, while the
+    /// savepoints taken after it become invalid. Row locks taken after the savepoint are kept until
+    /// the transaction ends.
+    pub fn rollback_to(&self, savepoint: Savepoint) -> Result<()> {
+        if self.committed.load(Ordering::SeqCst) {
+            panic!("cannot operate on committed txn!");
+        }
+        let mut undo_log = self.undo_log.lock();
+        let generation = match savepoint.position.checked_sub(1) {
+            Some(idx) => undo_log.get(idx).map(|(_, _, generation)| *generation),
+            None => Some(0),
+        };
+        if generation != Some(savepoint.generation) {
+            bail!("savepoint has already been rolled back");
+        }
+        for (key, prev, _) in undo_log.drain(savepoint.position..).rev() {
+            match prev {
+                Some(value) => {
+                    self.local_storage.insert(key, value);
+                }
+                None => {
+                    self.local_storage.remove(&key);
+                }
+            }
+        }
+        if let Some(key_hashes) = &self.key_hashes {
+            // The write set is exactly the keys still buffered.
+            key_hashes.lock().0 = self
+                .local_storage
+                .iter()
+                .map(|entry| farmhash::hash32(entry.key()))
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Discard all buffered writes and end the transaction, releasing its row locks.
+    pub fn rollback(&self) {
+        if self.committed.swap(true, Ordering::SeqCst) {
+            panic!("cannot operate on committed txn!");
+        }
+        self.undo_log.lock().clear();
+        self.local_storage.clear();
+        if let Some(key_hashes) = &self.key_hashes {
+            let mut key_hashes = key_hashes.lock();
+            key_hashes.0.clear();
+            key_hashes.1.clear();
+        }
+        self.commit_hooks.lock().clear();
+        self.inner.mvcc().lock_manager.unlock_all(self.txn_id);
+    }
+
+    /// Register a callback that receives the commit timestamp after a successful commit. It is not
+    /// called if the transaction aborts or is rolled back.
+    pub fn on_commit(&self, hook: impl FnOnce(u64) + Send + 'static) {
+        self.commit_hooks.lock().push(Box::new(hook));
     }
 
     pub fn commit(&self) -> Result<()> {
-        unimplemented!()
+        self.commit_with(CommitDurability::default())
+    }
+
+    /// Commit the transaction, returning once the writes reach the given durability level.
+    ///
+    /// A transaction without buffered writes writes nothing and takes no commit timestamp; its
+    /// commit hooks receive its read timestamp.
+    pub fn commit_with(&self, durability: CommitDurability) -> Result<()> {
+        self.committed
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .expect("cannot operate on committed txn!");
+        let mvcc = self.inner.mvcc();
+        let result = (|| {
+            let _commit_lock = mvcc.commit_lock.lock();
+            if let Some(key_hashes) = &self.key_hashes {
+                let key_hashes = key_hashes.lock();
+                let (write_set, read_set) = &*key_hashes;
+                if !write_set.is_empty() {
+                    let committed_txns = mvcc.committed_txns.lock();
+                    for (_, txn_data) in committed_txns.range((self.read_ts + 1)..) {
+                        if read_set
+                            .iter()
+                            .any(|key_hash| txn_data.key_hashes.contains(key_hash))
+                        {
+                            bail!("serializable check failed");
+                        }
+                    }
+                }
+            }
+            if self.local_storage.is_empty() {
+                return Ok(self.read_ts);
+            }
+            let batch = self
+                .local_storage
+                .iter()
+                .map(|entry| {
+                    let key = entry.key().clone();
+                    Ok(match decode_value(entry.value().clone()) {
+                        (ValueType::Put, value) => WriteBatchRecord::Put(key, value),
+                        (ValueType::Delete, _) => WriteBatchRecord::Del(key),
+                        (ValueType::PutWithExpiry, value) => {
+                            let (expires_at, value) = split_expiry_bytes(&value)?;
+                            WriteBatchRecord::PutWithExpiry(key, value, expires_at)
+                        }
+                        (value_type, _) => {
+                            unreachable!("transactions do not buffer {:?} writes", value_type)
+                        }
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let commit_ts = self.inner.write_batch_inner(&batch, durability)?;
+            if let Some(key_hashes) = &self.key_hashes {
+                let mut committed_txns = mvcc.committed_txns.lock();
+                committed_txns.insert(
+                    commit_ts,
+                    CommittedTxnData {
+                        key_hashes: key_hashes.lock().0.clone(),
+                        read_ts: self.read_ts,
+                        commit_ts,
+                    },
+                );
+                let watermark = mvcc.watermark();
+                while let Some(entry) = committed_txns.first_entry() {
+                    if *entry.key() >= watermark {
+                        break;
+                    }
+                    entry.remove();
+                }
+            }
+            Ok(commit_ts)
+        })();
+        mvcc.lock_manager.unlock_all(self.txn_id);
+        let commit_ts = result?;
+        for hook in std::mem::take(&mut *self.commit_hooks.lock()) {
+            hook(commit_ts);
+        }
+        Ok(())
     }
 }
 
 impl Drop for Transaction {
-    fn drop(&mut self) {}
+    fn drop(&mut self) {
+        if let Some(mvcc) = &self.inner.mvcc {
+            mvcc.lock_manager.unlock_all(self.txn_id);
+            mvcc.ts.lock().1.remove_reader(self.read_ts);
+        }
+    }
 }
 
 type SkipMapRangeIter<'a> =
@@ -143,3 +399,6 @@ impl StorageIterator for TxnIterator {
         self.iter.num_active_iterators()
     }
 }
+
+#[cfg(test)]
+mod tests;
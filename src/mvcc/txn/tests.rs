@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+use crate::lsm_storage::{DurabilityMode, LsmStorageInner, LsmStorageOptions};
+use crate::mvcc::txn::CommitDurability;
+
+#[test]
+fn test_txn_rollback_to_savepoint() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.serializable = true;
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options).unwrap());
+    storage.put(b"a", b"0").unwrap();
+
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"a", b"1");
+    let sp1 = txn.savepoint();
+    txn.put(b"a", b"2");
+    txn.put(b"b", b"2");
+    let sp2 = txn.savepoint();
+    txn.delete(b"a");
+    assert_eq!(txn.get(b"a").unwrap(), None);
+
+    txn.rollback_to(sp2).unwrap();
+    assert_eq!(txn.get(b"a").unwrap().as_deref(), Some(&b"2"[..]));
+    assert_eq!(txn.get(b"b").unwrap().as_deref(), Some(&b"2"[..]));
+
+    txn.rollback_to(sp1).unwrap();
+    assert_eq!(txn.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(txn.get(b"b").unwrap(), None);
+    assert_eq!(
+        txn.key_hashes.as_ref().unwrap().lock().0,
+        [farmhash::hash32(b"a")].into_iter().collect()
+    );
+    // Rolling back to a savepoint invalidates the savepoints taken after it.
+    assert!(txn.rollback_to(sp2).is_err());
+
+    txn.rollback();
+    assert!(txn.local_storage.is_empty());
+    assert_eq!(storage.get(b"a").unwrap().as_deref(), Some(&b"0"[..]));
+}
+
+#[test]
+fn test_txn_stale_savepoint() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap(),
+    );
+
+    let txn = storage.new_txn().unwrap();
+    let sp1 = txn.savepoint();
+    txn.put(b"b", b"1");
+    let sp2 = txn.savepoint();
+    txn.rollback_to(sp1).unwrap();
+    txn.put(b"c", b"1");
+    txn.put(b"d", b"1");
+    // sp2 points inside the writes made after rolling back to sp1, but it is stale.
+    assert!(txn.rollback_to(sp2).is_err());
+    assert_eq!(txn.get(b"b").unwrap(), None);
+    assert_eq!(txn.get(b"c").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(txn.get(b"d").unwrap().as_deref(), Some(&b"1"[..]));
+    txn.rollback_to(sp1).unwrap();
+    assert_eq!(txn.get(b"c").unwrap(), None);
+}
+
+#[test]
+fn test_txn_commit_hooks() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.serializable = true;
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options).unwrap());
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn1.put(b"a", b"1");
+    txn1.delete(b"b");
+    {
+        let seen = seen.clone();
+        txn1.on_commit(move |commit_ts| seen.lock().push(commit_ts));
+    }
+    txn1.commit_with(CommitDurability::WalSynced).unwrap();
+    assert_eq!(*seen.lock(), vec![1]);
+    assert_eq!(storage.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+
+    // txn2 read a key written by txn1 after txn2 started, so it cannot commit.
+    assert_eq!(txn2.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    txn2.put(b"c", b"1");
+    {
+        let seen = seen.clone();
+        txn2.on_commit(move |commit_ts| seen.lock().push(commit_ts));
+    }
+    assert!(txn2.commit().is_err());
+    assert_eq!(*seen.lock(), vec![1]);
+    assert_eq!(storage.get(b"c").unwrap(), None);
+}
+
+#[test]
+fn test_read_only_txn_commit() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.serializable = true;
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options).unwrap());
+    storage.put(b"a", b"1").unwrap();
+    let latest_commit_ts = storage.mvcc().latest_commit_ts();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let txn = storage.new_txn().unwrap();
+    assert_eq!(txn.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    {
+        let seen = seen.clone();
+        txn.on_commit(move |commit_ts| seen.lock().push(commit_ts));
+    }
+    txn.commit().unwrap();
+    // No commit timestamp is taken for a transaction without writes.
+    assert_eq!(storage.mvcc().latest_commit_ts(), latest_commit_ts);
+    assert_eq!(*seen.lock(), vec![txn.read_ts]);
+
+    // A transaction whose writes were all rolled back writes nothing either.
+    let txn = storage.new_txn().unwrap();
+    let savepoint = txn.savepoint();
+    txn.put(b"b", b"1");
+    txn.rollback_to(savepoint).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(storage.mvcc().latest_commit_ts(), latest_commit_ts);
+    assert_eq!(storage.get(b"b").unwrap(), None);
+}
+
+#[test]
+fn test_memory_commit_logged() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncManual;
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options.clone()).unwrap());
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"a", b"1");
+    txn.commit_with(CommitDurability::Memory).unwrap();
+    // A commit that read the first one is synced together with it.
+    let txn = storage.new_txn().unwrap();
+    assert_eq!(txn.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    txn.put(b"b", b"1");
+    txn.commit_with(CommitDurability::WalSynced).unwrap();
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    assert_eq!(storage.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(storage.get(b"b").unwrap().as_deref(), Some(&b"1"[..]));
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Range tombstones, written by `delete_range` to delete all keys in `[start, end)` at once.
+//!
+//! The storage is made of layers, from the newest to the oldest: the memtable, the immutable
+//! memtables, each L0 SST, then the SSTs of each level. A range tombstone hides the keys of the
+//! layers older than the one it is stored in. Keys of its own layer are always newer than it:
+//! `delete_range` removes the keys of the memtable it is written to, and later writes to that
+//! memtable are not covered.
+
+use std::sync::Arc;
+
+use anyhow::{Result, bail, ensure};
+use bytes::{Buf, BufMut, Bytes};
+use crossbeam_skiplist::SkipMap;
+
+use crate::iterators::{SeekableIterator, StorageIterator};
+use crate::key::{KeyBytes, KeySlice};
+use crate::value_type::ValueType;
+
+const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// Deletes every key in `[start, end)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Bytes,
+    pub end: Bytes,
+}
+
+impl RangeTombstone {
+    pub fn new(start: &[u8], end: &[u8]) -> Self {
+        Self {
+            start: Bytes::copy_from_slice(start),
+            end: Bytes::copy_from_slice(end),
+        }
+    }
+
+    /// Whether `key` is deleted by this tombstone.
+    pub fn covers(&self, key: &[u8]) -> bool {
+        self.start.as_ref() <= key && key < self.end.as_ref()
+    }
+
+    /// Remove the keys covered by this tombstone from a memtable, before it is added to it.
+    pub(crate) fn remove_covered(&self, map: &SkipMap<Bytes, Bytes>) {
+        for entry in map.range(self.start.clone()..self.end.clone()) {
+            entry.remove();
+        }
+    }
+
+    /// The size of the tombstone, counted in the size of a memtable.
+    pub(crate) fn size(&self) -> usize {
+        self.start.len() + self.end.len()
+    }
+
+    /// Encode a list of tombstones as an SST meta block.
+    ///
+    /// | num (u32) | start_len (u16) | start | end_len (u16) | end | ... | checksum (u32) |
+    pub(crate) fn encode_list(tombstones: &[RangeTombstone], buf: &mut Vec<u8>) {
+        let offset = buf.len();
+        buf.put_u32(tombstones.len() as u32);
+        for tombstone in tombstones {
+            buf.put_u16(tombstone.start.len() as u16);
+            buf.put_slice(&tombstone.start);
+            buf.put_u16(tombstone.end.len() as u16);
+            buf.put_slice(&tombstone.end);
+        }
+        let checksum = crc32fast::hash(&buf[offset..]);
+        buf.put_u32(checksum);
+    }
+
+    /// Decode a list of tombstones encoded by `encode_list`.
+    pub(crate) fn decode_list(data: &[u8]) -> Result<Vec<RangeTombstone>> {
+        ensure!(
+            data.len() >= SIZEOF_U32 * 2,
+            "range tombstones are truncated"
+        );
+        let (mut buf, mut checksum) = data.split_at(data.len() - SIZEOF_U32);
+        if checksum.get_u32() != crc32fast::hash(buf) {
+            bail!("checksum mismatched in range tombstones");
+        }
+        let num = buf.get_u32() as usize;
+        let mut tombstones = Vec::with_capacity(num);
+        for _ in 0..num {
+            let read_key = |buf: &mut &[u8]| {
+                ensure!(
+                    buf.remaining() >= SIZEOF_U16,
+                    "range tombstones are truncated"
+                );
+                let len = buf.get_u16() as usize;
+                ensure!(buf.remaining() >= len, "range tombstones are truncated");
+                let key = Bytes::copy_from_slice(&buf[..len]);
+                buf.advance(len);
+                Ok(key)
+            };
+            let start = read_key(&mut buf)?;
+            let end = read_key(&mut buf)?;
+            tombstones.push(RangeTombstone { start, end });
+        }
+        ensure!(!buf.has_remaining(), "range tombstones have trailing bytes");
+        Ok(tombstones)
+    }
+}
+
+/// The smallest start and the largest end of `tombstones`, which bound the keys they cover.
+fn bounds(tombstones: &[RangeTombstone]) -> Option<(Bytes, Bytes)> {
+    let start = tombstones.iter().map(|tombstone| &tombstone.start).min()?;
+    let end = tombstones.iter().map(|tombstone| &tombstone.end).max()?;
+    Some((start.clone(), end.clone()))
+}
+
+/// Extend the key range `first..=last` of an SST, if it has data, to the keys covered by its
+/// `tombstones`, so that the SSTs of a level cannot delete each other's keys. The end of a
+/// tombstone is not covered by it, but is taken as the last key of the SST if it is the largest.
+pub(crate) fn extend_key_range(
+    range: Option<(KeyBytes, KeyBytes)>,
+    tombstones: &[RangeTombstone],
+) -> Option<(KeyBytes, KeyBytes)> {
+    let Some((start, end)) = bounds(tombstones) else {
+        return range;
+    };
+    let (start, end) = (KeyBytes::from_bytes(start), KeyBytes::from_bytes(end));
+    Some(match range {
+        Some((first, last)) => (first.min(start), last.max(end)),
+        None => (start, end),
+    })
+}
+
+/// Whether any of `tombstones` deletes `key`.
+pub(crate) fn is_covered(tombstones: &[RangeTombstone], key: &[u8]) -> bool {
+    tombstones.iter().any(|tombstone| tombstone.covers(key))
+}
+
+/// Sort `tombstones` and merge those that overlap or touch, so that each key is covered by at most
+/// one of them.
+fn coalesce(tombstones: &[RangeTombstone]) -> Vec<RangeTombstone> {
+    let mut sorted = tombstones
+        .iter()
+        .filter(|tombstone| tombstone.start < tombstone.end)
+        .cloned()
+        .collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.start.cmp(&b.start));
+    let mut coalesced: Vec<RangeTombstone> = Vec::with_capacity(sorted.len());
+    for tombstone in sorted {
+        match coalesced.last_mut() {
+            Some(last) if tombstone.start <= last.end => {
+                last.end = last.end.clone().max(tombstone.end);
+            }
+            _ => coalesced.push(tombstone),
+        }
+    }
+    coalesced
+}
+
+/// Skips the keys of an iterator over one layer that are deleted by the range tombstones of the
+/// newer layers, seeking past each covered range rather than stepping through its keys.
+pub struct RangeTombstoneFilter<I> {
+    iter: I,
+    /// The tombstones of the newer layers, sorted and coalesced, see `coalesce`.
+    tombstones: Vec<RangeTombstone>,
+    /// Whether `iter` moves to smaller keys, in which case covered ranges are skipped backwards.
+    reverse: bool,
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>> + SeekableIterator>
+    RangeTombstoneFilter<I>
+{
+    /// Filter `iter` with the first `num_newer` of `tombstones`, the tombstones of all layers from
+    /// the newest. `reverse` tells whether `iter` moves to smaller keys.
+    pub fn create(
+        iter: I,
+        tombstones: Arc<Vec<RangeTombstone>>,
+        num_newer: usize,
+        reverse: bool,
+    ) -> Result<Self> {
+        let mut iter = Self {
+            iter,
+            tombstones: coalesce(&tombstones[..num_newer]),
+            reverse,
+        };
+        iter.skip_covered()?;
+        Ok(iter)
+    }
+
+    /// The tombstone covering `key`, if any.
+    fn covering(&self, key: &[u8]) -> Option<&RangeTombstone> {
+        let idx = self
+            .tombstones
+            .partition_point(|tombstone| tombstone.start.as_ref() <= key);
+        let tombstone = &self.tombstones[idx.checked_sub(1)?];
+        tombstone.covers(key).then_some(tombstone)
+    }
+
+    fn skip_covered(&mut self) -> Result<()> {
+        while self.iter.is_valid() {
+            let Some(tombstone) = self.covering(self.iter.key().raw_ref()).cloned() else {
+                break;
+            };
+            if self.reverse {
+                self.iter.seek_for_prev(&tombstone.start)?;
+                if self.iter.is_valid() && self.iter.key().raw_ref() == tombstone.start {
+                    self.iter.next()?;
+                }
+            } else {
+                self.iter.seek(&tombstone.end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>> + SeekableIterator>
+    StorageIterator for RangeTombstoneFilter<I>
+{
+    type KeyType<'a>
+        = KeySlice<'a>
+    where
+        Self: 'a;
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn key(&self) -> KeySlice<'_> {
+        self.iter.key()
+    }
+
+    fn value_type(&self) -> ValueType {
+        self.iter.value_type()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.iter.next()?;
+        self.skip_covered()
+    }
+}
+
+impl<I: 'static + for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>> + SeekableIterator>
+    SeekableIterator for RangeTombstoneFilter<I>
+{
+    fn seek_to_first(&mut self) -> Result<()> {
+        self.iter.seek_to_first()?;
+        self.reverse = false;
+        self.skip_covered()
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.iter.seek(key)?;
+        self.reverse = false;
+        self.skip_covered()
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        self.iter.seek_to_last()?;
+        self.reverse = true;
+        self.skip_covered()
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        self.iter.seek_for_prev(key)?;
+        self.reverse = true;
+        self.skip_covered()
+    }
+}
+
+#[cfg(test)]
+mod tests;
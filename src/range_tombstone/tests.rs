@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use tempfile::tempdir;
+
+use crate::compact::{CompactionTask, SimpleLeveledCompactionTask};
+use crate::iterators::{SeekableIterator, StorageIterator};
+use crate::key::KeySlice;
+use crate::lsm_storage::{DurabilityMode, LsmStorageInner, LsmStorageOptions};
+use crate::range_tombstone::{RangeTombstone, RangeTombstoneFilter, coalesce};
+use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::test_harness::{add_l0_sst, collect, key_of};
+
+/// A random range of about 40 keys.
+fn random_range(rng: &mut impl Rng) -> (Vec<u8>, Vec<u8>) {
+    let start = rng.gen_range(0..300);
+    (key_of(start), key_of(start + rng.gen_range(1..80)))
+}
+
+fn delete_range_from(expected: &mut BTreeMap<Vec<u8>, Vec<u8>>, start: &[u8], end: &[u8]) {
+    expected.retain(|key, _| !(start <= &key[..] && &key[..] < end));
+}
+
+/// Fill a storage with two SSTs, an immutable memtable and the memtable, each with range
+/// tombstones hiding the keys of the older ones, and return the expected content.
+fn generate_storage(storage: &LsmStorageInner) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut expected = BTreeMap::new();
+    for round in 0..4 {
+        if round < 2 {
+            // The keys of an SST are newer than its range tombstones.
+            let tombstones = (0..2)
+                .map(|_| {
+                    let (start, end) = random_range(&mut rng);
+                    delete_range_from(&mut expected, &start, &end);
+                    RangeTombstone::new(&start, &end)
+                })
+                .collect::<Vec<_>>();
+            let mut data = BTreeMap::new();
+            for _ in 0..150 {
+                let value = format!("value_{}", round).into_bytes();
+                data.insert(key_of(rng.gen_range(0..300)), value);
+            }
+            add_l0_sst(storage, &data, &tombstones);
+            expected.extend(data);
+            continue;
+        }
+        for _ in 0..150 {
+            let key = key_of(rng.gen_range(0..300));
+            if rng.gen_bool(0.02) {
+                let (start, end) = random_range(&mut rng);
+                storage.delete_range(&start, &end).unwrap();
+                delete_range_from(&mut expected, &start, &end);
+            } else if rng.gen_bool(0.2) {
+                storage.delete(&key).unwrap();
+                expected.remove(&key);
+            } else {
+                let value = format!("value_{}", round).into_bytes();
+                storage.put(&key, &value).unwrap();
+                expected.insert(key, value);
+            }
+        }
+        if round == 2 {
+            storage
+                .force_freeze_memtable(&storage.state_lock.lock())
+                .unwrap();
+        }
+    }
+    expected
+}
+
+#[test]
+fn test_range_tombstone_get_and_scan() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 128;
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    let expected = generate_storage(&storage);
+
+    for idx in 0..400 {
+        let key = key_of(idx);
+        assert_eq!(
+            storage.get(&key).unwrap().as_deref(),
+            expected.get(&key).map(|x| &x[..]),
+            "get({:?})",
+            key
+        );
+    }
+
+    let bounds = [
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Included(key_of(50)), Bound::Excluded(key_of(250))),
+        (Bound::Excluded(key_of(120)), Bound::Included(key_of(180))),
+    ];
+    for (lower, upper) in bounds {
+        let lower = lower.as_ref().map(|x| x.as_slice());
+        let upper = upper.as_ref().map(|x| x.as_slice());
+        let mut expected = expected
+            .range::<[u8], _>((lower, upper))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        let mut iter = storage.scan(lower, upper).unwrap();
+        assert_eq!(
+            collect(&mut iter),
+            expected,
+            "scan({:?}, {:?})",
+            lower,
+            upper
+        );
+        expected.reverse();
+        let mut iter = storage.scan_rev(lower, upper).unwrap();
+        assert_eq!(collect(&mut iter), expected);
+    }
+
+    let expected = expected.into_iter().collect::<Vec<_>>();
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in (0..300).step_by(7) {
+        let key = key_of(idx);
+        iter.seek(&key).unwrap();
+        let start = expected.partition_point(|(k, _)| k < &key);
+        if start < expected.len() {
+            assert_eq!(iter.key(), &expected[start].0[..], "seek({:?})", key);
+        } else {
+            assert!(!iter.is_valid());
+        }
+    }
+}
+
+#[test]
+fn test_delete_range_then_put() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    for idx in 0..10 {
+        storage.put(&key_of(idx), b"old").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    storage.put(&key_of(3), b"old").unwrap();
+    storage.delete_range(&key_of(2), &key_of(8)).unwrap();
+    storage.put(&key_of(5), b"new").unwrap();
+    // An empty range deletes nothing.
+    storage.delete_range(&key_of(9), &key_of(0)).unwrap();
+
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let keys = collect(&mut iter)
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        keys,
+        [0, 1, 5, 8, 9].map(key_of),
+        "the end of the range is not deleted"
+    );
+    assert_eq!(storage.get(&key_of(3)).unwrap(), None);
+    assert_eq!(&storage.get(&key_of(5)).unwrap().unwrap()[..], b"new");
+    assert_eq!(&storage.get(&key_of(8)).unwrap().unwrap()[..], b"old");
+}
+
+#[test]
+fn test_range_tombstone_wal_recovery() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    for idx in 0..10 {
+        storage.put(&key_of(idx), b"old").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    storage.put(&key_of(4), b"old").unwrap();
+    storage.delete_range(&key_of(3), &key_of(6)).unwrap();
+    storage.put(&key_of(5), b"new").unwrap();
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let keys = collect(&mut iter)
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, [0, 1, 2, 5, 6, 7, 8, 9].map(key_of));
+    assert_eq!(storage.get(&key_of(4)).unwrap(), None);
+    assert_eq!(&storage.get(&key_of(5)).unwrap().unwrap()[..], b"new");
+    assert_eq!(
+        storage.state.read().imm_memtables[0].range_tombstones(),
+        [RangeTombstone::new(&key_of(3), &key_of(6))]
+    );
+}
+
+#[test]
+fn test_sst_range_tombstones() {
+    let dir = tempdir().unwrap();
+    let tombstones = [
+        RangeTombstone::new(b"a", b"c"),
+        RangeTombstone::new(&key_of(10), &key_of(20)),
+    ];
+    for (name, tombstones) in [("none.sst", &tombstones[..0]), ("some.sst", &tombstones)] {
+        let path = dir.path().join(name);
+        let mut builder = SsTableBuilder::new(128);
+        for idx in 0..50 {
+            builder.add(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)), b"v");
+        }
+        for tombstone in tombstones {
+            builder.add_range_tombstone(tombstone.clone());
+        }
+        let built = builder.build_for_test(&path).unwrap();
+        assert_eq!(built.range_tombstones(), tombstones);
+        drop(built);
+
+        let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+        assert_eq!(sst.range_tombstones(), tombstones);
+        assert_eq!(sst.range_tombstone_covers(b"b"), !tombstones.is_empty());
+        assert!(!sst.range_tombstone_covers(&key_of(20)));
+        // The keys of the SST itself are not hidden.
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        let mut count = 0;
+        while iter.is_valid() {
+            count += 1;
+            iter.next().unwrap();
+        }
+        assert_eq!(count, 50);
+    }
+}
+
+#[test]
+fn test_range_tombstone_filter() {
+    let dir = tempdir().unwrap();
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..100 {
+        builder.add(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)), b"v");
+    }
+    let sst = Arc::new(builder.build_for_test(dir.path().join("1.sst")).unwrap());
+    let tombstones = Arc::new(vec![
+        RangeTombstone::new(&key_of(50), &key_of(51)),
+        RangeTombstone::new(&key_of(15), &key_of(30)),
+        RangeTombstone::new(&key_of(10), &key_of(20)),
+        RangeTombstone::new(&key_of(30), &key_of(35)),
+        RangeTombstone::new(&key_of(90), &key_of(80)),
+        RangeTombstone::new(&key_of(95), b"z"),
+    ]);
+    assert_eq!(
+        coalesce(&tombstones),
+        [
+            RangeTombstone::new(&key_of(10), &key_of(35)),
+            RangeTombstone::new(&key_of(50), &key_of(51)),
+            RangeTombstone::new(&key_of(95), b"z"),
+        ]
+    );
+    let mut expected = (0..10)
+        .chain(35..50)
+        .chain(51..95)
+        .map(key_of)
+        .collect::<Vec<_>>();
+
+    let keys = |iter: &mut RangeTombstoneFilter<SsTableIterator>| {
+        let mut keys = Vec::new();
+        while iter.is_valid() {
+            keys.push(iter.key().raw_ref().to_vec());
+            iter.next().unwrap();
+        }
+        keys
+    };
+    let iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    let mut iter = RangeTombstoneFilter::create(iter, tombstones.clone(), 6, false).unwrap();
+    assert_eq!(keys(&mut iter), expected);
+    iter.seek(&key_of(12)).unwrap();
+    assert_eq!(iter.key().raw_ref(), key_of(35));
+
+    expected.reverse();
+    let iter = SsTableIterator::create_and_seek_to_last(sst).unwrap();
+    let mut iter = RangeTombstoneFilter::create(iter, tombstones.clone(), 6, true).unwrap();
+    assert_eq!(keys(&mut iter), expected);
+    iter.seek_for_prev(&key_of(34)).unwrap();
+    assert_eq!(iter.key().raw_ref(), key_of(9));
+}
+
+#[test]
+fn test_sst_with_only_range_tombstones() {
+    let dir = tempdir().unwrap();
+    let tombstones = [
+        RangeTombstone::new(&key_of(10), &key_of(20)),
+        RangeTombstone::new(&key_of(5), &key_of(8)),
+    ];
+    for index_partition_size in [0, 64] {
+        let path = dir.path().join(format!("{index_partition_size}.sst"));
+        let mut builder = SsTableBuilder::new(128).with_index_partition_size(index_partition_size);
+        for tombstone in &tombstones {
+            builder.add_range_tombstone(tombstone.clone());
+        }
+        let built = builder.build_for_test(&path).unwrap();
+        assert_eq!(built.num_of_blocks(), 0);
+        assert_eq!(built.first_key().raw_ref(), key_of(5));
+        assert_eq!(built.last_key().raw_ref(), key_of(20));
+        drop(built);
+
+        let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+        assert_eq!(sst.range_tombstones(), tombstones);
+        assert_eq!(sst.num_of_blocks(), 0);
+        assert_eq!(sst.first_key().raw_ref(), key_of(5));
+        assert_eq!(sst.last_key().raw_ref(), key_of(20));
+        let key = key_of(12);
+        assert_eq!(
+            sst.get(KeySlice::for_testing_from_slice_no_ts(&key))
+                .unwrap(),
+            None
+        );
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+        assert!(!iter.is_valid());
+        iter.seek(&key).unwrap();
+        assert!(!iter.is_valid());
+        iter.seek_to_last().unwrap();
+        assert!(!iter.is_valid());
+        iter.seek_for_prev(KeySlice::for_testing_from_slice_no_ts(&key))
+            .unwrap();
+        assert!(!iter.is_valid());
+    }
+
+    // Building an SST with neither keys nor range tombstones fails.
+    assert!(
+        SsTableBuilder::new(128)
+            .build_for_test(dir.path().join("empty.sst"))
+            .is_err()
+    );
+
+    // The SST hides the keys of older layers.
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    let data = (0..30)
+        .map(|idx| (key_of(idx), b"v".to_vec()))
+        .collect::<BTreeMap<_, _>>();
+    add_l0_sst(&storage, &data, &[]);
+    add_l0_sst(&storage, &BTreeMap::<Vec<u8>, Vec<u8>>::new(), &tombstones);
+    assert_eq!(storage.get(&key_of(12)).unwrap(), None);
+    assert_eq!(storage.get(&key_of(4)).unwrap().as_deref(), Some(&b"v"[..]));
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    assert_eq!(collect(&mut iter).len(), 30 - 10 - 3);
+}
+
+#[test]
+fn test_compact_range_tombstones() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 64;
+    options.target_sst_size = 256;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    let older = (0..100)
+        .map(|idx| (key_of(idx), b"v1".to_vec()))
+        .collect::<BTreeMap<_, _>>();
+    let newer = (50..60)
+        .map(|idx| (key_of(idx), b"v2".to_vec()))
+        .collect::<BTreeMap<_, _>>();
+    add_l0_sst(&storage, &older, &[]);
+    add_l0_sst(
+        &storage,
+        &newer,
+        &[
+            RangeTombstone::new(&key_of(10), &key_of(20)),
+            RangeTombstone::new(&key_of(55), &key_of(70)),
+            // Beyond the keys of the output, for the older levels.
+            RangeTombstone::new(&key_of(100), &key_of(120)),
+        ],
+    );
+    let expected = |idx: usize| match idx {
+        10..20 | 60..70 => None,
+        50..60 => Some(&b"v2"[..]),
+        _ => Some(&b"v1"[..]),
+    };
+
+    // Above the bottom level, the tombstones are kept for the older levels, split between the
+    // output SSTs within their key ranges, which do not overlap.
+    let task = CompactionTask::Simple(SimpleLeveledCompactionTask {
+        upper_level: None,
+        upper_level_sst_ids: storage.state.read().l0_sstables.clone(),
+        lower_level: 1,
+        lower_level_sst_ids: Vec::new(),
+        is_lower_level_bottom_level: false,
+    });
+    let output = storage.compact(&task).unwrap();
+    assert!(output.len() > 1);
+    let mut keys = BTreeMap::new();
+    for (idx, sst) in output.iter().enumerate() {
+        for tombstone in sst.range_tombstones() {
+            assert!(tombstone.start.as_ref() >= sst.first_key().raw_ref());
+            assert!(tombstone.end.as_ref() <= sst.last_key().raw_ref());
+        }
+        if let Some(next) = output.get(idx + 1) {
+            assert!(sst.last_key() <= next.first_key());
+        }
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+        while iter.is_valid() {
+            keys.insert(iter.key().raw_ref().to_vec(), iter.value().to_vec());
+            iter.next().unwrap();
+        }
+    }
+    for idx in 0..100 {
+        let key = key_of(idx);
+        assert_eq!(keys.get(&key).map(|value| &value[..]), expected(idx));
+        let covered = output.iter().any(|sst| sst.range_tombstone_covers(&key));
+        if expected(idx).is_none() {
+            assert!(covered, "{idx} is not covered");
+        }
+    }
+    assert!(
+        output
+            .iter()
+            .any(|sst| sst.range_tombstone_covers(&key_of(110)))
+    );
+    assert_eq!(output.last().unwrap().last_key().raw_ref(), key_of(120));
+
+    // At the bottom level, nothing older is left for them to hide.
+    storage.force_full_compaction().unwrap();
+    let snapshot = storage.state.read().clone();
+    assert!(snapshot.l0_sstables.is_empty());
+    assert!(
+        snapshot.levels[0]
+            .1
+            .iter()
+            .all(|id| snapshot.sstables[id].range_tombstones().is_empty())
+    );
+    drop(snapshot);
+    drop(storage);
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    for idx in 0..100 {
+        assert_eq!(storage.get(&key_of(idx)).unwrap().as_deref(), expected(idx));
+    }
+}
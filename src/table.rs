@@ -15,23 +15,113 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
-pub(crate) mod bloom;
+pub mod bloom;
 mod builder;
+mod compression;
 mod iterator;
 
+use std::fmt::Display;
 use std::fs::File;
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::Result;
-pub use builder::SsTableBuilder;
-use bytes::{Buf, BufMut};
+use anyhow::{Result, ensure};
+pub use bloom::FilterType;
+pub use builder::{DEFAULT_BLOOM_BITS_PER_KEY, SsTableBuilder};
+use bytes::{Buf, BufMut, Bytes};
+pub use compression::CompressionType;
 pub use iterator::SsTableIterator;
 
 use self::bloom::Bloom;
-use crate::block::Block;
+use crate::block::{Block, BlockFormat, BlockIterator};
 use crate::key::{KeyBytes, KeySlice};
-use crate::lsm_storage::BlockCache;
+use crate::lsm_storage::{BlockCache, PrefixExtractor};
+use crate::range_tombstone::{self, RangeTombstone};
+use crate::value_type::ValueType;
+
+pub(crate) const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// The size of the codec (u8) and checksum (u32) following each data block.
+const BLOCK_TRAILER_SIZE: usize = 1 + SIZEOF_U32;
+
+/// The size of the extra section at the end of an SST.
+const EXTRA_SIZE: usize = SIZEOF_U32 * 3 + 2;
+/// The size of the extra section of SSTs written before range tombstones, without their offset.
+const V3_EXTRA_SIZE: usize = SIZEOF_U32 * 2 + 2;
+/// The size of the extra section of SSTs written before the format version, without it.
+const V1_EXTRA_SIZE: usize = SIZEOF_U32 * 2 + 1;
+
+/// The version of the SST format, recorded in the last byte of an SST. SSTs written before it end
+/// with the index type instead, which is always smaller, and have `BlockFormat::V1` blocks.
+const SST_FORMAT_VERSION: u8 = 5;
+/// The version of SSTs with `BlockFormat::V3` blocks, without value types.
+const SST_FORMAT_VERSION_V4: u8 = 4;
+/// The version of SSTs without range tombstones.
+const SST_FORMAT_VERSION_V3: u8 = 3;
+/// The version of SSTs with `BlockFormat::V2` blocks, without restart points.
+const SST_FORMAT_VERSION_V2: u8 = 2;
+
+/// How the index of the data blocks is stored, recorded in the last byte of an SST.
+const INDEX_TYPE_FULL: u8 = 0;
+const INDEX_TYPE_PARTITIONED: u8 = 1;
+
+/// An entry of the block cache, keyed by `(sst_id, offset in the SST)`.
+#[derive(Clone)]
+pub enum CachedBlock {
+    Data(Arc<Block>),
+    /// The block metas of a partition of a partitioned index.
+    IndexPartition(Arc<Vec<BlockMeta>>),
+    /// The filter of a partition of a partitioned index.
+    FilterPartition(Arc<Bloom>),
+}
+
+/// Returned when the bytes of an SST do not match what was written, e.g. because of a bad disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptionError {
+    pub sst_id: usize,
+    /// The corrupted data block, or `None` if the SST metadata is corrupted.
+    pub block_idx: Option<usize>,
+    pub reason: String,
+}
+
+impl CorruptionError {
+    fn meta(sst_id: usize, reason: impl Display) -> anyhow::Error {
+        Self {
+            sst_id,
+            block_idx: None,
+            reason: reason.to_string(),
+        }
+        .into()
+    }
+
+    fn block(sst_id: usize, block_idx: usize, reason: impl Display) -> anyhow::Error {
+        Self {
+            sst_id,
+            block_idx: Some(block_idx),
+            reason: reason.to_string(),
+        }
+        .into()
+    }
+}
+
+impl Display for CorruptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.block_idx {
+            Some(block_idx) => write!(
+                f,
+                "SST {} block {} is corrupted: {}",
+                self.sst_id, block_idx, self.reason
+            ),
+            None => write!(
+                f,
+                "SST {} metadata is corrupted: {}",
+                self.sst_id, self.reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CorruptionError {}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
@@ -56,7 +146,7 @@ impl BlockMeta {
         let mut estimated_size = std::mem::size_of::<u32>();
         for meta in block_meta {
             // The size of offset
-            estimated_size += std::mem::size_of::<u32>();
+            estimated_size += SIZEOF_U32;
             // The size of first key length
             estimated_size += std::mem::size_of::<u16>();
             // The size of actual key
@@ -68,8 +158,10 @@ impl BlockMeta {
         }
         // Reserve the space to improve performance, especially when the size of incoming data is
         // large
+        // The size of checksum
+        estimated_size += SIZEOF_U32;
         buf.reserve(estimated_size);
-        // let original_len = buf.len();
+        let original_len = buf.len();
         buf.put_u32(block_meta.len() as u32);
         for meta in block_meta {
             buf.put_u32(meta.offset as u32);
@@ -78,17 +170,31 @@ impl BlockMeta {
             buf.put_u16(meta.last_key.len() as u16);
             buf.put_slice(meta.last_key.raw_ref());
         }
+        let checksum = crc32fast::hash(&buf[original_len..]);
+        buf.put_u32(checksum);
     }
 
-    /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: impl Buf) -> Vec<BlockMeta> {
+    /// Decode block meta from a buffer, verifying its checksum.
+    pub fn decode_block_meta(buf: &[u8]) -> Result<Vec<BlockMeta>> {
+        ensure!(buf.len() >= SIZEOF_U32 * 2, "block meta is truncated");
+        let (mut buf, mut checksum) = buf.split_at(buf.len() - SIZEOF_U32);
+        ensure!(
+            checksum.get_u32() == crc32fast::hash(buf),
+            "block meta checksum mismatched"
+        );
         let mut block_meta = Vec::new();
         let num = buf.get_u32() as usize;
         for _ in 0..num {
+            ensure!(buf.remaining() >= SIZEOF_U32 + 2, "block meta is truncated");
             let offset = buf.get_u32() as usize;
             let first_key_len = buf.get_u16() as usize;
+            ensure!(
+                buf.remaining() >= first_key_len + 2,
+                "block meta is truncated"
+            );
             let first_key = buf.copy_to_bytes(first_key_len);
             let last_key_len = buf.get_u16() as usize;
+            ensure!(buf.remaining() >= last_key_len, "block meta is truncated");
             let last_key = buf.copy_to_bytes(last_key_len);
             block_meta.push(BlockMeta {
                 offset,
@@ -96,7 +202,89 @@ impl BlockMeta {
                 last_key: KeyBytes::from_bytes(last_key),
             });
         }
-        block_meta
+        Ok(block_meta)
+    }
+}
+
+/// Locates a partition of a partitioned index, and the filter partition of the same keys. Only
+/// these top-level entries are kept in memory; the partitions are read on demand.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexPartitionMeta {
+    /// Offset and length of the block metas of the partition.
+    pub offset: usize,
+    pub len: usize,
+    /// Offset and length of the filter of the partition, 0 if there is no filter.
+    pub filter_offset: usize,
+    pub filter_len: usize,
+    /// The index of the first data block of the partition in the SST, not encoded.
+    pub first_block_idx: usize,
+    pub num_blocks: usize,
+    /// The end of the last data block of the partition.
+    pub blocks_end: usize,
+    pub first_key: KeyBytes,
+    pub last_key: KeyBytes,
+}
+
+impl IndexPartitionMeta {
+    /// Encode the top-level index into a buffer, followed by its checksum (u32).
+    pub fn encode_index(partitions: &[IndexPartitionMeta], buf: &mut Vec<u8>) {
+        let original_len = buf.len();
+        buf.put_u32(partitions.len() as u32);
+        for partition in partitions {
+            buf.put_u32(partition.offset as u32);
+            buf.put_u32(partition.len as u32);
+            buf.put_u32(partition.filter_offset as u32);
+            buf.put_u32(partition.filter_len as u32);
+            buf.put_u32(partition.num_blocks as u32);
+            buf.put_u32(partition.blocks_end as u32);
+            buf.put_u16(partition.first_key.len() as u16);
+            buf.put_slice(partition.first_key.raw_ref());
+            buf.put_u16(partition.last_key.len() as u16);
+            buf.put_slice(partition.last_key.raw_ref());
+        }
+        let checksum = crc32fast::hash(&buf[original_len..]);
+        buf.put_u32(checksum);
+    }
+
+    /// Decode the top-level index from a buffer, verifying its checksum.
+    pub fn decode_index(buf: &[u8]) -> Result<Vec<IndexPartitionMeta>> {
+        ensure!(buf.len() >= SIZEOF_U32 * 2, "index is truncated");
+        let (mut buf, mut checksum) = buf.split_at(buf.len() - SIZEOF_U32);
+        ensure!(
+            checksum.get_u32() == crc32fast::hash(buf),
+            "index checksum mismatched"
+        );
+        let mut partitions = Vec::new();
+        let num = buf.get_u32() as usize;
+        let mut first_block_idx = 0;
+        for _ in 0..num {
+            ensure!(buf.remaining() >= SIZEOF_U32 * 6 + 2, "index is truncated");
+            let offset = buf.get_u32() as usize;
+            let len = buf.get_u32() as usize;
+            let filter_offset = buf.get_u32() as usize;
+            let filter_len = buf.get_u32() as usize;
+            let num_blocks = buf.get_u32() as usize;
+            let blocks_end = buf.get_u32() as usize;
+            let first_key_len = buf.get_u16() as usize;
+            ensure!(buf.remaining() >= first_key_len + 2, "index is truncated");
+            let first_key = buf.copy_to_bytes(first_key_len);
+            let last_key_len = buf.get_u16() as usize;
+            ensure!(buf.remaining() >= last_key_len, "index is truncated");
+            let last_key = buf.copy_to_bytes(last_key_len);
+            partitions.push(IndexPartitionMeta {
+                offset,
+                len,
+                filter_offset,
+                filter_len,
+                first_block_idx,
+                num_blocks,
+                blocks_end,
+                first_key: KeyBytes::from_bytes(first_key),
+                last_key: KeyBytes::from_bytes(last_key),
+            });
+            first_block_idx += num_blocks;
+        }
+        Ok(partitions)
     }
 }
 
@@ -139,15 +327,24 @@ impl FileObject {
 pub struct SsTable {
     /// The actual storage unit of SsTable, the format is as above.
     pub(crate) file: FileObject,
-    /// The meta blocks that hold info for data blocks.
+    /// The meta blocks that hold info for data blocks. Empty if the index is partitioned.
     pub(crate) block_meta: Vec<BlockMeta>,
+    /// The top-level index of a partitioned index, or empty.
+    pub(crate) index_partitions: Vec<IndexPartitionMeta>,
     /// The offset that indicates the start point of meta blocks in `file`.
     pub(crate) block_meta_offset: usize,
     id: usize,
     block_cache: Option<Arc<BlockCache>>,
     first_key: KeyBytes,
     last_key: KeyBytes,
+    /// The filter of all keys, if the index is not partitioned.
     pub(crate) bloom: Option<Bloom>,
+    /// The extractor of the prefixes added to the bloom filter.
+    pub(crate) prefix_extractor: Option<PrefixExtractor>,
+    /// The layout of the data blocks.
+    pub(crate) block_format: BlockFormat,
+    /// The range tombstones, within `first_key..=last_key`, see `range_tombstone::extend_key_range`.
+    pub(crate) range_tombstones: Vec<RangeTombstone>,
     /// The maximum timestamp stored in this SST, implemented in week 3.
     max_ts: u64,
 }
@@ -165,21 +362,144 @@ impl SsTable {
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
         // 与 sstable builder 的 build 写入的结构相对应
         let len = file.size();
-        let raw_meta_offset = file.read(len - 4, 4)?;
-        let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
-        let raw_meta = file.read(block_meta_offset, len - 4 - block_meta_offset)?;
-        let block_meta = BlockMeta::decode_block_meta(&raw_meta[..]);
-        Ok(Self {
+        if len < V1_EXTRA_SIZE as u64 {
+            return Err(CorruptionError::meta(id, "file is too small"));
+        }
+        let (extra_size, block_format) = match file.read(len - 1, 1)?[0] {
+            INDEX_TYPE_FULL | INDEX_TYPE_PARTITIONED => (V1_EXTRA_SIZE, BlockFormat::V1),
+            SST_FORMAT_VERSION_V2 if len >= V3_EXTRA_SIZE as u64 => {
+                (V3_EXTRA_SIZE, BlockFormat::V2)
+            }
+            SST_FORMAT_VERSION_V3 if len >= V3_EXTRA_SIZE as u64 => {
+                (V3_EXTRA_SIZE, BlockFormat::V3)
+            }
+            SST_FORMAT_VERSION_V4 if len >= EXTRA_SIZE as u64 => (EXTRA_SIZE, BlockFormat::V3),
+            SST_FORMAT_VERSION if len >= EXTRA_SIZE as u64 => (EXTRA_SIZE, BlockFormat::V4),
+            _ => return Err(CorruptionError::meta(id, "unknown format version")),
+        };
+        let extra_offset = len - extra_size as u64;
+        let raw_extra = file.read(extra_offset, extra_size as u64)?;
+        let mut raw_extra = &raw_extra[..];
+        let block_meta_offset = raw_extra.get_u32() as u64;
+        let bloom_offset = raw_extra.get_u32() as u64;
+        // The range tombstones are written between the bloom filter and the extra section.
+        let range_tombstones_offset = if extra_size == EXTRA_SIZE {
+            raw_extra.get_u32() as u64
+        } else {
+            extra_offset
+        };
+        let index_type = raw_extra.get_u8();
+        if block_meta_offset > bloom_offset
+            || bloom_offset > range_tombstones_offset
+            || range_tombstones_offset > extra_offset
+        {
+            return Err(CorruptionError::meta(id, "meta offset is out of range"));
+        }
+        let range_tombstones = if range_tombstones_offset < extra_offset {
+            let raw = file.read(
+                range_tombstones_offset,
+                extra_offset - range_tombstones_offset,
+            )?;
+            RangeTombstone::decode_list(&raw).map_err(|e| CorruptionError::meta(id, e))?
+        } else {
+            Vec::new()
+        };
+        let (raw_bloom, prefix_extractor) = if bloom_offset < range_tombstones_offset {
+            let raw_bloom = file.read(bloom_offset, range_tombstones_offset - bloom_offset)?;
+            if raw_bloom.len() < SIZEOF_U32 {
+                return Err(CorruptionError::meta(id, "bloom filter is truncated"));
+            }
+            let prefix_extractor = PrefixExtractor::decode((&raw_bloom[..]).get_u32())
+                .map_err(|e| CorruptionError::meta(id, e))?;
+            (raw_bloom[SIZEOF_U32..].to_vec(), prefix_extractor)
+        } else {
+            (Vec::new(), None)
+        };
+        let raw_meta = file.read(block_meta_offset, bloom_offset - block_meta_offset)?;
+        let mut table = Self {
             file,
-            first_key: block_meta.first().unwrap().first_key.clone(),
-            last_key: block_meta.last().unwrap().last_key.clone(),
-            block_meta,
+            first_key: KeyBytes::default(),
+            last_key: KeyBytes::default(),
+            block_meta: Vec::new(),
+            index_partitions: Vec::new(),
             block_meta_offset: block_meta_offset as usize,
             id,
             block_cache,
             bloom: None,
+            prefix_extractor,
+            block_format,
+            range_tombstones,
             max_ts: 0,
-        })
+        };
+        match index_type {
+            INDEX_TYPE_FULL => {
+                let block_meta = BlockMeta::decode_block_meta(&raw_meta[..])
+                    .map_err(|e| CorruptionError::meta(id, e))?;
+                // An SST with only range tombstones has no data block.
+                if !block_meta.is_empty() || table.range_tombstones.is_empty() {
+                    table.check_block_offsets(&block_meta, table.block_meta_offset)?;
+                }
+                let data_range = block_meta
+                    .first()
+                    .zip(block_meta.last())
+                    .map(|(first, last)| (first.first_key.clone(), last.last_key.clone()));
+                (table.first_key, table.last_key) =
+                    range_tombstone::extend_key_range(data_range, &table.range_tombstones).unwrap();
+                table.block_meta = block_meta;
+                if !raw_bloom.is_empty() {
+                    table.bloom =
+                        Some(Bloom::decode(&raw_bloom).map_err(|e| CorruptionError::meta(id, e))?);
+                }
+            }
+            INDEX_TYPE_PARTITIONED => {
+                let partitions = IndexPartitionMeta::decode_index(&raw_meta[..])
+                    .map_err(|e| CorruptionError::meta(id, e))?;
+                let in_range =
+                    |offset: usize, len: usize| offset + len <= block_meta_offset as usize;
+                let partitions_valid = !partitions.is_empty()
+                    && partitions.iter().all(|partition| {
+                        partition.num_blocks > 0
+                            && in_range(partition.offset, partition.len)
+                            && in_range(partition.filter_offset, partition.filter_len)
+                            && in_range(partition.blocks_end, 0)
+                    });
+                if !partitions_valid {
+                    return Err(CorruptionError::meta(
+                        id,
+                        "index partitions are out of range",
+                    ));
+                }
+                let data_range = (
+                    partitions.first().unwrap().first_key.clone(),
+                    partitions.last().unwrap().last_key.clone(),
+                );
+                (table.first_key, table.last_key) =
+                    range_tombstone::extend_key_range(Some(data_range), &table.range_tombstones)
+                        .unwrap();
+                table.index_partitions = partitions;
+            }
+            _ => return Err(CorruptionError::meta(id, "unknown index type")),
+        }
+        Ok(table)
+    }
+
+    /// Check that the blocks of `block_meta` are in order and end before `blocks_end`, so that
+    /// reading them cannot underflow.
+    fn check_block_offsets(&self, block_meta: &[BlockMeta], blocks_end: usize) -> Result<()> {
+        if block_meta.is_empty() {
+            return Err(CorruptionError::meta(self.id, "no data block"));
+        }
+        let offsets_in_order = block_meta
+            .windows(2)
+            .all(|pair| pair[0].offset + BLOCK_TRAILER_SIZE <= pair[1].offset);
+        if !offsets_in_order || block_meta.last().unwrap().offset + BLOCK_TRAILER_SIZE > blocks_end
+        {
+            return Err(CorruptionError::meta(
+                self.id,
+                "block offsets are out of range",
+            ));
+        }
+        Ok(())
     }
 
     /// Create a mock SST with only first key + last key metadata
@@ -192,64 +512,274 @@ impl SsTable {
         Self {
             file: FileObject(None, file_size),
             block_meta: vec![],
+            index_partitions: vec![],
             block_meta_offset: 0,
             id,
             block_cache: None,
             first_key,
             last_key,
             bloom: None,
+            prefix_extractor: None,
+            block_format: BlockFormat::V4,
+            range_tombstones: Vec::new(),
             max_ts: 0,
         }
     }
 
-    /// Read a block from the disk.
+    /// Read through the block cache the entry at `offset`, reading it with `read` on a miss.
+    fn read_cached(
+        &self,
+        offset: usize,
+        read: impl FnOnce() -> Result<CachedBlock>,
+    ) -> Result<CachedBlock> {
+        let Some(cache) = &self.block_cache else {
+            return read();
+        };
+        if let Some(entry) = cache.get(&(self.id, offset)) {
+            return Ok(entry);
+        }
+        let entry = read()?;
+        cache.insert((self.id, offset), entry.clone());
+        Ok(entry)
+    }
+
+    /// Read the bytes at `offset` followed by their checksum, and verify it.
+    fn read_checked(&self, offset: usize, len_with_checksum: usize) -> Result<Option<Vec<u8>>> {
+        let mut data = self.file.read(offset as u64, len_with_checksum as u64)?;
+        let len = len_with_checksum - SIZEOF_U32;
+        let checksum = (&data[len..]).get_u32();
+        if checksum != crc32fast::hash(&data[..len]) {
+            return Ok(None);
+        }
+        data.truncate(len);
+        Ok(Some(data))
+    }
+
+    /// The block metas of the `partition_idx`-th partition of a partitioned index.
+    fn index_partition(&self, partition_idx: usize) -> Result<Arc<Vec<BlockMeta>>> {
+        let partition = &self.index_partitions[partition_idx];
+        let entry = self.read_cached(partition.offset, || {
+            let raw = self
+                .file
+                .read(partition.offset as u64, partition.len as u64)?;
+            let block_meta = BlockMeta::decode_block_meta(&raw)
+                .map_err(|e| CorruptionError::meta(self.id, e))?;
+            self.check_block_offsets(&block_meta, partition.blocks_end)?;
+            if block_meta.len() != partition.num_blocks {
+                return Err(CorruptionError::meta(
+                    self.id,
+                    "index partition has a wrong number of blocks",
+                ));
+            }
+            Ok(CachedBlock::IndexPartition(Arc::new(block_meta)))
+        })?;
+        match entry {
+            CachedBlock::IndexPartition(block_meta) => Ok(block_meta),
+            _ => unreachable!("index partition cached as another kind of block"),
+        }
+    }
+
+    /// The filter of the `partition_idx`-th partition of a partitioned index, if any.
+    fn filter_partition(&self, partition_idx: usize) -> Result<Option<Arc<Bloom>>> {
+        let partition = &self.index_partitions[partition_idx];
+        if partition.filter_len == 0 {
+            return Ok(None);
+        }
+        let entry = self.read_cached(partition.filter_offset, || {
+            let raw = self
+                .file
+                .read(partition.filter_offset as u64, partition.filter_len as u64)?;
+            let bloom = Bloom::decode(&raw).map_err(|e| CorruptionError::meta(self.id, e))?;
+            Ok(CachedBlock::FilterPartition(Arc::new(bloom)))
+        })?;
+        match entry {
+            CachedBlock::FilterPartition(bloom) => Ok(Some(bloom)),
+            _ => unreachable!("filter partition cached as another kind of block"),
+        }
+    }
+
+    /// The partition that may contain `key`.
+    fn find_partition_idx(&self, key: &[u8]) -> usize {
+        self.index_partitions
+            .partition_point(|partition| partition.first_key.raw_ref() <= key)
+            .saturating_sub(1)
+    }
+
+    /// The offset and the end of the `block_idx`-th block, including its codec and checksum.
+    fn block_range(&self, block_idx: usize) -> Result<(usize, usize)> {
+        if self.index_partitions.is_empty() {
+            let offset = self.block_meta[block_idx].offset;
+            let offset_end = self
+                .block_meta
+                .get(block_idx + 1)
+                .map_or(self.block_meta_offset, |x| x.offset);
+            return Ok((offset, offset_end));
+        }
+        let partition_idx = self
+            .index_partitions
+            .partition_point(|partition| partition.first_block_idx <= block_idx)
+            - 1;
+        let partition = &self.index_partitions[partition_idx];
+        let block_meta = self.index_partition(partition_idx)?;
+        let idx = block_idx - partition.first_block_idx;
+        let offset_end = block_meta
+            .get(idx + 1)
+            .map_or(partition.blocks_end, |x| x.offset);
+        Ok((block_meta[idx].offset, offset_end))
+    }
+
+    /// Read a block from the disk, verifying its checksum and decompressing it.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let offset = self.block_meta[block_idx].offset;
-        let offset_end = self
-            .block_meta
-            .get(block_idx + 1)
-            .map_or(self.block_meta_offset, |x| x.offset);
-        let block_len: usize = offset_end - offset;
-        let block_data: Vec<u8> = self
-            .file
-            .read(offset as u64, (offset_end - offset) as u64)?;
-        // let block_data = &block_data_with_chksum[0..block_len];
+        let (offset, offset_end) = self.block_range(block_idx)?;
+        self.read_block_at(block_idx, offset, offset_end)
+    }
+
+    fn read_block_at(
+        &self,
+        block_idx: usize,
+        offset: usize,
+        offset_end: usize,
+    ) -> Result<Arc<Block>> {
+        let Some(mut block_data) = self.read_checked(offset, offset_end - offset)? else {
+            return Err(CorruptionError::block(
+                self.id,
+                block_idx,
+                "checksum mismatched",
+            ));
+        };
+        let codec = block_data.pop().unwrap();
+        let block_data = CompressionType::from_u8(codec)
+            .and_then(|compression| compression.decompress(block_data))
+            .map_err(|e| CorruptionError::block(self.id, block_idx, e))?;
+        if !Block::is_well_formed(&block_data, self.block_format) {
+            return Err(CorruptionError::block(
+                self.id,
+                block_idx,
+                "malformed block",
+            ));
+        }
 
-        Ok(Arc::new(Block::decode(&block_data)))
+        Ok(Arc::new(Block::decode_with_format(
+            &block_data,
+            self.block_format,
+        )))
     }
 
     /// Read a block from disk, with block cache. (Day 4)
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
-        if let Some(cache) = &self.block_cache {
-            if let Ok(block) =
-                cache.try_get_with((self.sst_id(), block_idx), || -> Result<Arc<Block>> {
-                    let block = self.read_block(block_idx)?;
-                    self.block_cache
-                        .as_ref()
-                        .unwrap()
-                        .insert((self.sst_id(), block_idx), block.clone());
-                    Ok(block)
-                })
-            {
-                return Ok(block);
-            }
+        let (offset, offset_end) = self.block_range(block_idx)?;
+        let entry = self.read_cached(offset, || {
+            Ok(CachedBlock::Data(
+                self.read_block_at(block_idx, offset, offset_end)?,
+            ))
+        })?;
+        match entry {
+            CachedBlock::Data(block) => Ok(block),
+            _ => unreachable!("data block cached as another kind of block"),
         }
-        // If we do not have block cache, just read block from disk.
-        self.read_block(block_idx)
     }
 
     /// Find the block that may contain `key`.
     /// Note: You may want to make use of the `first_key` stored in `BlockMeta`.
     /// You may also assume the key-value pairs stored in each consecutive block are sorted.
-    pub fn find_block_idx(&self, key: KeySlice) -> usize {
-        self.block_meta
+    pub fn find_block_idx(&self, key: KeySlice) -> Result<usize> {
+        if self.index_partitions.is_empty() {
+            return Ok(self
+                .block_meta
+                .partition_point(|meta| meta.first_key.as_key_slice() <= key)
+                .saturating_sub(1));
+        }
+        let partition_idx = self.find_partition_idx(key.raw_ref());
+        let block_meta = self.index_partition(partition_idx)?;
+        let idx = block_meta
             .partition_point(|meta| meta.first_key.as_key_slice() <= key)
-            .saturating_sub(1)
+            .saturating_sub(1);
+        Ok(self.index_partitions[partition_idx].first_block_idx + idx)
+    }
+
+    /// Look up `key` for a point read, through the hash index of its data block if it has one.
+    /// Returns `None` if the SST does not contain `key`, and the type of the entry with its value
+    /// otherwise.
+    pub fn get(&self, key: KeySlice) -> Result<Option<(ValueType, Bytes)>> {
+        if self.num_of_blocks() == 0 {
+            return Ok(None);
+        }
+        let block = self.read_block_cached(self.find_block_idx(key)?)?;
+        let iter = BlockIterator::create_and_lookup_key(block, key);
+        Ok(iter
+            .is_valid()
+            .then(|| (iter.value_type(), Bytes::copy_from_slice(iter.value()))))
+    }
+
+    /// Whether the SST may contain `key`, judging from its key range and bloom filter, without
+    /// reading any data block.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if key < self.first_key.raw_ref() || key > self.last_key.raw_ref() {
+            return false;
+        }
+        let key_hash = farmhash::fingerprint32(key);
+        if self.index_partitions.is_empty() {
+            return self
+                .bloom
+                .as_ref()
+                .is_none_or(|bloom| bloom.may_contain(key_hash));
+        }
+        // Failing to read the filter is not an answer; reading the data block will report it.
+        match self.filter_partition(self.find_partition_idx(key)) {
+            Ok(Some(bloom)) => bloom.may_contain(key_hash),
+            _ => true,
+        }
+    }
+
+    /// Whether the SST may contain keys starting with `prefix`, judging from the prefixes in its
+    /// bloom filter. Always true if `prefix` is shorter than the prefixes the filter contains.
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        let Some(extracted) = self
+            .prefix_extractor
+            .as_ref()
+            .and_then(|extractor| extractor.extract(prefix))
+        else {
+            return true;
+        };
+        let prefix_hash = farmhash::fingerprint32(extracted);
+        if self.index_partitions.is_empty() {
+            return self
+                .bloom
+                .as_ref()
+                .is_none_or(|bloom| bloom.may_contain(prefix_hash));
+        }
+        // Check the filter of every partition that may have keys starting with the prefix.
+        let first = self.find_partition_idx(prefix);
+        (first..self.index_partitions.len())
+            .take_while(|&idx| {
+                idx == first
+                    || self.index_partitions[idx]
+                        .first_key
+                        .raw_ref()
+                        .starts_with(prefix)
+            })
+            .any(|idx| match self.filter_partition(idx) {
+                Ok(Some(bloom)) => bloom.may_contain(prefix_hash),
+                _ => true,
+            })
+    }
+
+    /// The range tombstones of this SST, which hide the keys of older SSTs.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Whether a range tombstone of this SST deletes `key` from older SSTs.
+    pub fn range_tombstone_covers(&self, key: &[u8]) -> bool {
+        range_tombstone::is_covered(&self.range_tombstones, key)
     }
 
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
-        self.block_meta.len()
+        match self.index_partitions.last() {
+            Some(partition) => partition.first_block_idx + partition.num_blocks,
+            None => self.block_meta.len(),
+        }
     }
 
     pub fn first_key(&self) -> &KeyBytes {
@@ -272,3 +802,6 @@ impl SsTable {
         self.max_ts
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -14,17 +14,61 @@
 
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
-use anyhow::Result;
-use bytes::{BufMut, Bytes, BytesMut};
+use anyhow::{Result, bail, ensure};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// The layout of a filter, stored in its encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterType {
+    /// A classic bloom filter, probing `k` bits anywhere in the filter.
+    #[default]
+    Classic,
+    /// A bloom filter probing `k` bits within a single cache line picked by the key, so that a
+    /// probe costs at most one cache miss. Slightly less accurate than `Classic` with the same
+    /// size.
+    Blocked,
+    /// A standard ribbon filter: a solution to a banded linear system over GF(2), storing a `k`-bit
+    /// fingerprint per key in about `1.1 * k` bits per key, i.e. about 30% less than a bloom
+    /// filter with the same false positive rate.
+    Ribbon,
+}
+
+impl FilterType {
+    fn from_u8(ty: u8) -> Result<Self> {
+        match ty {
+            0 => Ok(FilterType::Classic),
+            1 => Ok(FilterType::Blocked),
+            2 => Ok(FilterType::Ribbon),
+            _ => bail!("unknown filter type {}", ty),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            FilterType::Classic => 0,
+            FilterType::Blocked => 1,
+            FilterType::Ribbon => 2,
+        }
+    }
+}
 
 /// Implements a bloom filter
 pub struct Bloom {
     /// data of filter in bits
     pub(crate) filter: Bytes,
-    /// number of hash functions
+    /// number of hash functions, or bits per fingerprint for a ribbon filter
     pub(crate) k: u8,
+    pub(crate) filter_type: FilterType,
+    /// The hash seed the ribbon filter was solved with. Always 0 for other filter types.
+    pub(crate) seed: u32,
 }
 
+/// Bits in a cache line, the size of each block of a blocked bloom filter.
+const CACHE_LINE_BITS: usize = 512;
+
+/// Width of the coefficient rows of a ribbon filter.
+const RIBBON_WIDTH: usize = 64;
+
 pub trait BitSlice {
     fn get_bit(&self, idx: usize) -> bool;
     fn bit_len(&self) -> usize;
@@ -59,30 +103,66 @@ impl<T: AsMut<[u8]>> BitSliceMut for T {
 }
 
 impl Bloom {
-    /// Decode a bloom filter
+    /// Decode a bloom filter, verifying its checksum.
     pub fn decode(buf: &[u8]) -> Result<Self> {
-        let filter = &buf[..buf.len() - 1];
-        let k = buf[buf.len() - 1];
+        ensure!(buf.len() > 10, "bloom filter is truncated");
+        let (buf, mut checksum) = buf.split_at(buf.len() - 4);
+        ensure!(
+            checksum.get_u32() == crc32fast::hash(buf),
+            "bloom filter checksum mismatched"
+        );
+        let (filter, mut extra) = buf.split_at(buf.len() - 6);
+        let k = extra.get_u8();
+        let seed = extra.get_u32();
+        let filter_type = FilterType::from_u8(extra.get_u8())?;
+        if filter_type == FilterType::Ribbon {
+            ensure!(
+                k > 0 && filter.len() % k as usize == 0 && filter.len() / k as usize * 8 >= 64,
+                "ribbon filter is malformed"
+            );
+        }
         Ok(Self {
             filter: filter.to_vec().into(),
             k,
+            filter_type,
+            seed,
         })
     }
 
-    /// Encode a bloom filter
+    /// Encode a bloom filter, followed by its checksum (u32).
+    ///
+    /// | filter | k (u8) | seed (u32) | filter type (u8) | checksum (u32) |
     pub fn encode(&self, buf: &mut Vec<u8>) {
+        let original_len = buf.len();
         buf.extend(&self.filter);
         buf.put_u8(self.k);
+        buf.put_u32(self.seed);
+        buf.put_u8(self.filter_type.to_u8());
+        let checksum = crc32fast::hash(&buf[original_len..]);
+        buf.put_u32(checksum);
     }
 
     /// Get bloom filter bits per key from entries count and FPR
     pub fn bloom_bits_per_key(entries: usize, false_positive_rate: f64) -> usize {
-        let size =
-            -1.0 * (entries as f64) * false_positive_rate.ln() / std::f64::consts::LN_2.powi(2);
+        let size = -(entries as f64) * false_positive_rate.ln() / std::f64::consts::LN_2.powi(2);
         let locs = (size / (entries as f64)).ceil();
         locs as usize
     }
 
+    /// Build a filter of the given type from key hashes. `bits_per_key` is the size of a bloom
+    /// filter; a ribbon filter is built with about the same false positive rate instead.
+    pub fn build_from_key_hashes_with_type(
+        keys: &[u32],
+        bits_per_key: usize,
+        filter_type: FilterType,
+    ) -> Self {
+        match filter_type {
+            FilterType::Classic => Self::build_from_key_hashes(keys, bits_per_key),
+            FilterType::Blocked => Self::build_blocked(keys, bits_per_key),
+            FilterType::Ribbon => Self::build_ribbon(keys, bits_per_key),
+        }
+    }
+
     /// Build bloom filter from key hashes
     pub fn build_from_key_hashes(keys: &[u32], bits_per_key: usize) -> Self {
         let k = (bits_per_key as f64 * 0.69) as u32;
@@ -93,26 +173,210 @@ impl Bloom {
         let mut filter = BytesMut::with_capacity(nbytes);
         filter.resize(nbytes, 0);
 
-        // TODO: build the bloom filter
+        for h in keys {
+            let mut h = *h;
+            let delta = h.rotate_left(15);
+            for _ in 0..k {
+                let bit_pos = (h as usize) % nbits;
+                filter.set_bit(bit_pos, true);
+                h = h.wrapping_add(delta);
+            }
+        }
 
         Self {
             filter: filter.freeze(),
             k: k as u8,
+            filter_type: FilterType::Classic,
+            seed: 0,
+        }
+    }
+
+    fn build_blocked(keys: &[u32], bits_per_key: usize) -> Self {
+        let k = (bits_per_key as f64 * 0.69) as u32;
+        let k = k.clamp(1, 30);
+        let nblocks = (keys.len() * bits_per_key).div_ceil(CACHE_LINE_BITS).max(1);
+        let nbytes = nblocks * CACHE_LINE_BITS / 8;
+        let mut filter = BytesMut::with_capacity(nbytes);
+        filter.resize(nbytes, 0);
+
+        for h in keys {
+            let (base, mut h, delta) = blocked_probe(*h, nblocks);
+            for _ in 0..k {
+                filter.set_bit(base + (h as usize) % CACHE_LINE_BITS, true);
+                h = h.wrapping_add(delta);
+            }
+        }
+
+        Self {
+            filter: filter.freeze(),
+            k: k as u8,
+            filter_type: FilterType::Blocked,
+            seed: 0,
+        }
+    }
+
+    fn build_ribbon(keys: &[u32], bits_per_key: usize) -> Self {
+        // A k-bit fingerprint has a false positive rate of 2^-k, which a bloom filter reaches with
+        // k / ln(2) bits per key.
+        let r = ((bits_per_key as f64 * 0.69).round() as usize).clamp(1, 8);
+        // Rows of the system; the overhead over one row per key is needed to solve it.
+        let mut nrows = (keys.len() + keys.len() / 10 + RIBBON_WIDTH).next_multiple_of(8);
+        let mut seed = 0;
+        let mut coeffs = vec![0u64; nrows];
+        let mut results = vec![0u8; nrows];
+        // Banding: Gaussian elimination keeping every row in the band starting at its pivot.
+        'solve: loop {
+            coeffs.clear();
+            coeffs.resize(nrows, 0);
+            results.clear();
+            results.resize(nrows, 0);
+            for h in keys {
+                let (mut start, mut coeff, mut result) = ribbon_hash(*h, seed, nrows, r);
+                loop {
+                    if coeffs[start] == 0 {
+                        coeffs[start] = coeff;
+                        results[start] = result;
+                        break;
+                    }
+                    coeff ^= coeffs[start];
+                    result ^= results[start];
+                    if coeff == 0 {
+                        if result == 0 {
+                            // The same equation as a previous key
+                            break;
+                        }
+                        // Unsolvable: retry with another seed, and with more rows from time to time.
+                        seed += 1;
+                        if seed % 4 == 0 {
+                            nrows = (nrows + nrows / 8).next_multiple_of(8);
+                        }
+                        continue 'solve;
+                    }
+                    let shift = coeff.trailing_zeros();
+                    start += shift as usize;
+                    coeff >>= shift;
+                }
+            }
+            break;
+        }
+
+        // Back substitution, column by column of the fingerprints. Column `j` holds bit `j` of the
+        // solution of every row.
+        let column_bytes = nrows / 8;
+        let mut filter = BytesMut::with_capacity(column_bytes * r);
+        filter.resize(column_bytes * r, 0);
+        for j in 0..r {
+            let mut column = &mut filter[j * column_bytes..(j + 1) * column_bytes];
+            // The solution of the next 64 rows, the current row at bit 0.
+            let mut state = 0u64;
+            for row in (0..nrows).rev() {
+                state <<= 1;
+                let bit = ((state & coeffs[row]).count_ones() as u8 ^ (results[row] >> j)) & 1;
+                if coeffs[row] != 0 && bit == 1 {
+                    state |= 1;
+                    column.set_bit(row, true);
+                }
+            }
+        }
+
+        Self {
+            filter: filter.freeze(),
+            k: r as u8,
+            filter_type: FilterType::Ribbon,
+            seed,
         }
     }
 
     /// Check if a bloom filter may contain some data
     pub fn may_contain(&self, h: u32) -> bool {
+        match self.filter_type {
+            FilterType::Classic => self.may_contain_classic(h),
+            FilterType::Blocked => self.may_contain_blocked(h),
+            FilterType::Ribbon => self.may_contain_ribbon(h),
+        }
+    }
+
+    fn may_contain_classic(&self, h: u32) -> bool {
         if self.k > 30 {
             // potential new encoding for short bloom filters
             true
         } else {
             let nbits = self.filter.bit_len();
+            let mut h = h;
             let delta = h.rotate_left(15);
 
-            // TODO: probe the bloom filter
+            for _ in 0..self.k {
+                let bit_pos = (h as usize) % nbits;
+                if !self.filter.get_bit(bit_pos) {
+                    return false;
+                }
+                h = h.wrapping_add(delta);
+            }
 
             true
         }
     }
+
+    fn may_contain_blocked(&self, h: u32) -> bool {
+        let (base, mut h, delta) = blocked_probe(h, self.filter.bit_len() / CACHE_LINE_BITS);
+        for _ in 0..self.k {
+            if !self.filter.get_bit(base + (h as usize) % CACHE_LINE_BITS) {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+
+    fn may_contain_ribbon(&self, h: u32) -> bool {
+        let r = self.k as usize;
+        let column_bytes = self.filter.len() / r;
+        let (start, coeff, result) = ribbon_hash(h, self.seed, column_bytes * 8, r);
+        for j in 0..r {
+            let column = &self.filter[j * column_bytes..(j + 1) * column_bytes];
+            let solution = read_u64_at_bit(column, start);
+            if (solution & coeff).count_ones() as u8 & 1 != (result >> j) & 1 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The first bit of the cache line picked by `h` among `nblocks`, and the start and step of the
+/// probes within it.
+fn blocked_probe(h: u32, nblocks: usize) -> (usize, u32, u32) {
+    // The block comes from the high bits and the probes mostly from the low bits.
+    let block = ((h as u64 * nblocks as u64) >> 32) as usize;
+    let h = h.wrapping_mul(0x9e37_79b9);
+    (block * CACHE_LINE_BITS, h, h.rotate_left(15))
 }
+
+/// The equation of a key in a ribbon filter with `nrows` rows: the first row of its band, its
+/// coefficients over the band (bit 0 always set), and its `r`-bit fingerprint.
+fn ribbon_hash(h: u32, seed: u32, nrows: usize, r: usize) -> (usize, u64, u8) {
+    let x = splitmix64(((seed as u64) << 32) | h as u64);
+    let start = ((x >> 32) * (nrows - RIBBON_WIDTH + 1) as u64) >> 32;
+    let coeff = splitmix64(x) | 1;
+    let result = (x as u8) & (u8::MAX >> (8 - r));
+    (start as usize, coeff, result)
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Read 64 bits of `bits` starting at bit `start`, with bits past the end read as 0.
+fn read_u64_at_bit(bits: &[u8], start: usize) -> u64 {
+    let mut buf = [0u8; 16];
+    let first = start / 8;
+    let end = bits.len().min(first + 9);
+    buf[..end - first].copy_from_slice(&bits[first..end]);
+    (u128::from_le_bytes(buf) >> (start % 8)) as u64
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,167 @@
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::key::KeySlice;
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions};
+use crate::table::bloom::{Bloom, FilterType};
+use crate::table::{FileObject, SsTable, SsTableBuilder};
+use crate::test_harness::key_of;
+
+#[test]
+fn test_bloom_false_positive_rate() {
+    let hashes = (0..1000)
+        .map(|idx| farmhash::fingerprint32(&key_of(idx * 2)))
+        .collect::<Vec<_>>();
+    let bloom = Bloom::build_from_key_hashes(&hashes, 10);
+    for hash in &hashes {
+        assert!(bloom.may_contain(*hash));
+    }
+    let false_positives = (0..1000)
+        .filter(|idx| bloom.may_contain(farmhash::fingerprint32(&key_of(idx * 2 + 1))))
+        .count();
+    assert!(false_positives < 30, "{} false positives", false_positives);
+
+    let mut buf = Vec::new();
+    bloom.encode(&mut buf);
+    let decoded = Bloom::decode(&buf).unwrap();
+    assert_eq!(decoded.filter, bloom.filter);
+    assert_eq!(decoded.k, bloom.k);
+    buf[0] ^= 1;
+    assert!(Bloom::decode(&buf).is_err());
+}
+
+#[test]
+fn test_sst_bloom_persisted() {
+    let dir = tempdir().unwrap();
+    for bits_per_key in [0, 10] {
+        let path = dir.path().join(format!("{}.sst", bits_per_key));
+        let mut builder = SsTableBuilder::new(128).with_bloom_bits_per_key(bits_per_key);
+        for idx in 0..100 {
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx * 2)),
+                b"v",
+            );
+        }
+        let sst = builder.build_for_test(&path).unwrap();
+        assert_eq!(sst.bloom.is_some(), bits_per_key > 0);
+        let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+        assert_eq!(sst.bloom.is_some(), bits_per_key > 0);
+        for idx in 0..100 {
+            assert!(sst.may_contain(&key_of(idx * 2)));
+        }
+        assert!(!sst.may_contain(b"a"));
+        assert!(!sst.may_contain(b"z"));
+        let rejected = (0..99)
+            .filter(|idx| !sst.may_contain(&key_of(idx * 2 + 1)))
+            .count();
+        if bits_per_key == 0 {
+            assert_eq!(rejected, 0);
+        } else {
+            assert!(rejected > 90, "only {} keys rejected", rejected);
+        }
+    }
+}
+
+#[test]
+fn test_get_skips_sst_by_bloom() {
+    let dir = tempdir().unwrap();
+    for bits_per_key in [0, 10] {
+        let mut options = LsmStorageOptions::default_for_week1_test();
+        options.bloom_bits_per_key = bits_per_key;
+        let storage = Arc::new(
+            LsmStorageInner::open(dir.path().join(bits_per_key.to_string()), options).unwrap(),
+        );
+        let mut builder = storage.new_sst_builder();
+        for idx in 0..100 {
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(idx * 2)),
+                b"v",
+            );
+        }
+        let sst_id = storage.next_sst_id();
+        let path = storage.path_of_sst(sst_id);
+        let sst = builder.build(sst_id, None, &path).unwrap();
+        {
+            let mut guard = storage.state.write();
+            let mut snapshot = guard.as_ref().clone();
+            snapshot.l0_sstables.insert(0, sst_id);
+            snapshot.sstables.insert(sst_id, Arc::new(sst));
+            *guard = Arc::new(snapshot);
+        }
+        assert_eq!(&storage.get(&key_of(0)).unwrap().unwrap()[..], b"v");
+        assert_eq!(storage.get(&key_of(1)).unwrap(), None);
+
+        // Break the only data block, so that any lookup reading it fails.
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"garbage", 0).unwrap();
+        assert!(storage.get(&key_of(0)).is_err());
+        let failed = (0..99)
+            .filter(|idx| storage.get(&key_of(idx * 2 + 1)).is_err())
+            .count();
+        if bits_per_key == 0 {
+            assert_eq!(failed, 99);
+        } else {
+            assert!(failed < 9, "{} lookups read the SST", failed);
+        }
+    }
+}
+
+#[test]
+fn test_filter_types() {
+    let hashes = (0..10000)
+        .map(|idx| farmhash::fingerprint32(&key_of(idx * 2)))
+        .collect::<Vec<_>>();
+    let classic_size = Bloom::build_from_key_hashes(&hashes, 10).filter.len();
+    for filter_type in [FilterType::Classic, FilterType::Blocked, FilterType::Ribbon] {
+        let bloom = Bloom::build_from_key_hashes_with_type(&hashes, 10, filter_type);
+        let mut buf = Vec::new();
+        bloom.encode(&mut buf);
+        let bloom = Bloom::decode(&buf).unwrap();
+        assert_eq!(bloom.filter_type, filter_type);
+        for hash in &hashes {
+            assert!(
+                bloom.may_contain(*hash),
+                "false negative in {:?}",
+                filter_type
+            );
+        }
+        let false_positives = (0..10000)
+            .filter(|idx| bloom.may_contain(farmhash::fingerprint32(&key_of(idx * 2 + 1))))
+            .count();
+        assert!(
+            false_positives < 200,
+            "{} false positives in {:?}",
+            false_positives,
+            filter_type
+        );
+        if filter_type == FilterType::Ribbon {
+            assert!(bloom.filter.len() * 10 < classic_size * 8);
+        }
+    }
+    // Tiny filters are solvable too.
+    for len in [0, 1, 2, 100] {
+        let bloom = Bloom::build_from_key_hashes_with_type(&hashes[..len], 10, FilterType::Ribbon);
+        assert!(hashes[..len].iter().all(|hash| bloom.may_contain(*hash)));
+    }
+}
+
+#[test]
+fn test_sst_filter_type_persisted() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let mut builder = SsTableBuilder::new(128).with_filter_type(FilterType::Ribbon);
+    for idx in 0..100 {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(&key_of(idx * 2)),
+            b"v",
+        );
+    }
+    builder.build_for_test(&path).unwrap();
+    let sst = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert_eq!(sst.bloom.as_ref().unwrap().filter_type, FilterType::Ribbon);
+    for idx in 0..100 {
+        assert!(sst.may_contain(&key_of(idx * 2)));
+    }
+}
@@ -18,11 +18,22 @@
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Result, ensure};
 use bytes::BufMut;
 
-use super::{BlockMeta, FileObject, SsTable};
-use crate::{block::BlockBuilder, key::KeySlice, key::KeyVec, lsm_storage::BlockCache};
+use super::bloom::{Bloom, FilterType};
+use super::compression::CompressionType;
+use super::{
+    BlockMeta, FileObject, INDEX_TYPE_FULL, INDEX_TYPE_PARTITIONED, IndexPartitionMeta, SIZEOF_U32,
+    SST_FORMAT_VERSION, SsTable,
+};
+use crate::{
+    block::{BlockBuilder, BlockFormat, DEFAULT_RESTART_INTERVAL, SIZEOF_U16},
+    key::{KeySlice, KeyVec},
+    lsm_storage::{BlockCache, PrefixExtractor},
+    range_tombstone::{self, RangeTombstone},
+    value_type::ValueType,
+};
 
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
@@ -32,8 +43,28 @@ pub struct SsTableBuilder {
     data: Vec<u8>,
     pub(crate) meta: Vec<BlockMeta>,
     block_size: usize,
+    /// Entries between two restart points of each block.
+    block_restart_interval: usize,
+    /// Whether to append a hash index to each block.
+    block_hash_index: bool,
+    range_tombstones: Vec<RangeTombstone>,
+    /// Fingerprints of all keys added, to build the bloom filter.
+    key_hashes: Vec<u32>,
+    /// The end of the fingerprints of each completed block in `key_hashes`.
+    block_hash_ends: Vec<usize>,
+    bloom_bits_per_key: usize,
+    filter_type: FilterType,
+    prefix_extractor: Option<PrefixExtractor>,
+    /// Fingerprint of the prefix of the last key added, to add each prefix only once.
+    last_prefix_hash: Option<u32>,
+    /// Target size of each partition of the index, or 0 for a single index.
+    index_partition_size: usize,
+    compression: CompressionType,
 }
 
+/// Bits per key of the bloom filter built by default, for a false positive rate of about 1%.
+pub const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+
 impl SsTableBuilder {
     /// Create a builder based on target block size.
     pub fn new(block_size: usize) -> Self {
@@ -44,31 +75,120 @@ impl SsTableBuilder {
             last_key: KeyVec::new(),
             builder: BlockBuilder::new(block_size),
             block_size,
+            block_restart_interval: DEFAULT_RESTART_INTERVAL,
+            block_hash_index: false,
+            range_tombstones: Vec::new(),
+            key_hashes: Vec::new(),
+            block_hash_ends: Vec::new(),
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            filter_type: FilterType::default(),
+            prefix_extractor: None,
+            last_prefix_hash: None,
+            index_partition_size: 0,
+            compression: CompressionType::None,
         }
     }
 
+    /// Set the bits per key of the bloom filter. 0 builds the SST without a bloom filter.
+    pub fn with_bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    /// Set the layout of the filter.
+    pub fn with_filter_type(mut self, filter_type: FilterType) -> Self {
+        self.filter_type = filter_type;
+        self
+    }
+
+    /// Split the index into partitions of about `index_partition_size` bytes, each with its own
+    /// filter, so that opening the SST only loads a small top-level index. 0 keeps a single index
+    /// and filter.
+    pub fn with_index_partition_size(mut self, index_partition_size: usize) -> Self {
+        self.index_partition_size = index_partition_size;
+        self
+    }
+
+    /// Set the number of entries between two restart points of each block, see
+    /// `BlockBuilder::with_restart_interval`.
+    pub fn with_block_restart_interval(mut self, block_restart_interval: usize) -> Self {
+        self.block_restart_interval = block_restart_interval;
+        self.builder = self.new_block_builder();
+        self
+    }
+
+    /// Append a hash index to each block for point lookups, see `BlockBuilder::with_hash_index`.
+    pub fn with_block_hash_index(mut self, block_hash_index: bool) -> Self {
+        self.block_hash_index = block_hash_index;
+        self.builder = self.new_block_builder();
+        self
+    }
+
+    fn new_block_builder(&self) -> BlockBuilder {
+        BlockBuilder::new(self.block_size)
+            .with_restart_interval(self.block_restart_interval)
+            .with_hash_index(self.block_hash_index)
+    }
+
+    /// Set the codec of the data blocks.
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Also add the prefix of every key to the bloom filter.
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Option<PrefixExtractor>) -> Self {
+        self.prefix_extractor = prefix_extractor;
+        self
+    }
+
     /// Adds a key-value pair to SSTable.
     ///
     /// Note: You should split a new block when the current block is full.(`std::mem::replace` may
     /// be helpful here)
+    ///
+    /// An empty value is a tombstone, see `add_typed` to add entries of other types.
     pub fn add(&mut self, key: KeySlice, value: &[u8]) {
+        self.add_typed(key, ValueType::of_untyped(value), value);
+    }
+
+    /// Adds an entry of any type to SSTable.
+    pub fn add_typed(&mut self, key: KeySlice, value_type: ValueType, value: &[u8]) {
         if self.first_key.is_empty() {
             self.first_key.set_from_slice(key);
         }
-        if self.builder.add(key, value) {
-            self.last_key.set_from_slice(key);
-            return;
+        if !self.builder.add_typed(key, value_type, value) {
+            // the current block is full, we need to create a new block
+            self.complete_current_block();
+            // add the key-value pair to the new block
+            assert!(self.builder.add_typed(key, value_type, value));
+            self.first_key.set_from_slice(key);
         }
-        // the current block is full, we need to create a new block
-        self.complete_current_block();
-        // add the key-value pair to the new block
-        assert!(self.builder.add(key, value));
-        self.first_key.set_from_slice(key);
         self.last_key.set_from_slice(key);
+        if self.bloom_bits_per_key > 0 {
+            self.key_hashes.push(farmhash::fingerprint32(key.raw_ref()));
+            if let Some(prefix) = self
+                .prefix_extractor
+                .and_then(|extractor| extractor.extract(key.raw_ref()))
+            {
+                let prefix_hash = farmhash::fingerprint32(prefix);
+                if self.last_prefix_hash != Some(prefix_hash) {
+                    self.key_hashes.push(prefix_hash);
+                    self.last_prefix_hash = Some(prefix_hash);
+                }
+            }
+        }
+    }
+
+    /// Adds a range tombstone, hiding the keys in `[start, end)` of older SSTs. It does not cover
+    /// the keys added to this SST.
+    pub fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.range_tombstones.push(tombstone);
     }
 
     fn complete_current_block(&mut self) {
-        let builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.block_size));
+        let new_builder = self.new_block_builder();
+        let builder = std::mem::replace(&mut self.builder, new_builder);
         let encoded_block = builder.build().encode();
         self.meta.push(BlockMeta {
             offset: self.data.len(),
@@ -76,7 +196,72 @@ impl SsTableBuilder {
             last_key: std::mem::take(&mut self.last_key).into_key_bytes(),
         });
         // 把数据加入进来
-        self.data.extend(encoded_block);
+        let offset = self.data.len();
+        let codec = self.compression.compress(&encoded_block, &mut self.data);
+        self.data.put_u8(codec.to_u8());
+        let checksum = crc32fast::hash(&self.data[offset..]);
+        self.data.put_u32(checksum);
+        self.block_hash_ends.push(self.key_hashes.len());
+    }
+
+    fn build_filter(&self, key_hashes: &[u32]) -> Bloom {
+        Bloom::build_from_key_hashes_with_type(
+            key_hashes,
+            self.bloom_bits_per_key,
+            self.filter_type,
+        )
+    }
+
+    /// Write the index partitions of the blocks, each followed by its filter, and return the
+    /// top-level index.
+    fn write_index_partitions(&self, buf: &mut Vec<u8>) -> Vec<IndexPartitionMeta> {
+        let blocks_end = buf.len();
+        let mut partitions = Vec::new();
+        let mut start = 0;
+        while start < self.meta.len() {
+            let mut end = start;
+            let mut size = 0;
+            while end < self.meta.len() && (end == start || size < self.index_partition_size) {
+                let meta = &self.meta[end];
+                size += SIZEOF_U32 + SIZEOF_U16 * 2 + meta.first_key.len() + meta.last_key.len();
+                end += 1;
+            }
+            let offset = buf.len();
+            BlockMeta::encode_block_meta(&self.meta[start..end], buf);
+            let len = buf.len() - offset;
+            let (filter_offset, filter_len) = if self.bloom_bits_per_key > 0 {
+                let hashes_start = start
+                    .checked_sub(1)
+                    .map_or(0, |idx| self.block_hash_ends[idx]);
+                let mut key_hashes =
+                    self.key_hashes[hashes_start..self.block_hash_ends[end - 1]].to_vec();
+                // The prefix of the first key may have been added with an earlier partition.
+                if let Some(prefix) = self
+                    .prefix_extractor
+                    .and_then(|extractor| extractor.extract(self.meta[start].first_key.raw_ref()))
+                {
+                    key_hashes.push(farmhash::fingerprint32(prefix));
+                }
+                let filter_offset = buf.len();
+                self.build_filter(&key_hashes).encode(buf);
+                (filter_offset, buf.len() - filter_offset)
+            } else {
+                (0, 0)
+            };
+            partitions.push(IndexPartitionMeta {
+                offset,
+                len,
+                filter_offset,
+                filter_len,
+                first_block_idx: start,
+                num_blocks: end - start,
+                blocks_end: self.meta.get(end).map_or(blocks_end, |meta| meta.offset),
+                first_key: self.meta[start].first_key.clone(),
+                last_key: self.meta[end - 1].last_key.clone(),
+            });
+            start = end;
+        }
+        partitions
     }
 
     /// Get the estimated size of the SSTable.
@@ -91,9 +276,24 @@ impl SsTableBuilder {
     /// -------------------------------------------------------------------------------------------
     /// |         Block Section         |          Meta Section         |          Extra          |
     /// -------------------------------------------------------------------------------------------
-    /// | data block | ... | data block |            metadata           | meta block offset (u32) |
+    /// | data block | ... | data block |  metadata  | checksum (u32)  | meta block offset (u32) |
     /// -------------------------------------------------------------------------------------------
-
+    ///
+    /// Each data block is compressed, unless that does not make it smaller, and followed by its
+    /// codec (u8) and the checksum (u32) of both. The bloom filter, if any, is written between the
+    /// meta section and the extra section, preceded by the encoded prefix extractor (u32) whose
+    /// prefixes it contains. The range tombstones, if any, are written after it. Their offsets
+    /// (u32 each), the index type (u8) and the format version (u8) follow the meta offset in the
+    /// extra section.
+    ///
+    /// With a partitioned index, each partition of the metadata and its filter are written after
+    /// the blocks, the meta section holds the top-level index, and only the prefix extractor is
+    /// written in place of the bloom filter.
+    ///
+    /// An SST with only range tombstones has no data block, and its key range is the range they
+    /// cover.
+    ///
+    /// Fails if the SST would be larger than 4GiB, since the offsets are u32.
     pub fn build(
         mut self,
         id: usize,
@@ -101,22 +301,82 @@ impl SsTableBuilder {
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
         // 需要把最后一个块的 meta 和 data 也加入进来，所以需要执行 complete_current_block
-        self.complete_current_block();
-        let mut buf = self.data;
-        let meta_offset = buf.len();
-        BlockMeta::encode_block_meta(&self.meta, &mut buf);
+        if !self.builder.is_empty() {
+            self.complete_current_block();
+        }
+        ensure!(
+            !self.meta.is_empty() || !self.range_tombstones.is_empty(),
+            "SST has neither keys nor range tombstones"
+        );
+        let mut buf = std::mem::take(&mut self.data);
+        let meta_offset;
+        let mut bloom = None;
+        let mut index_partitions = Vec::new();
+        if self.index_partition_size == 0 || self.meta.is_empty() {
+            meta_offset = buf.len();
+            BlockMeta::encode_block_meta(&self.meta, &mut buf);
+            bloom = (self.bloom_bits_per_key > 0).then(|| self.build_filter(&self.key_hashes));
+        } else {
+            index_partitions = self.write_index_partitions(&mut buf);
+            meta_offset = buf.len();
+            IndexPartitionMeta::encode_index(&index_partitions, &mut buf);
+        }
+        let bloom_offset = buf.len();
+        if self.bloom_bits_per_key > 0 {
+            buf.put_u32(PrefixExtractor::encode(self.prefix_extractor.as_ref()));
+        }
+        if let Some(bloom) = &bloom {
+            bloom.encode(&mut buf);
+        }
+        let range_tombstones_offset = buf.len();
+        if !self.range_tombstones.is_empty() {
+            RangeTombstone::encode_list(&self.range_tombstones, &mut buf);
+        }
+        // All offsets, including those of the blocks in the metadata, are encoded as u32.
+        ensure!(
+            buf.len() <= u32::MAX as usize,
+            "SST of {} bytes is too large, the limit is {} bytes",
+            buf.len(),
+            u32::MAX
+        );
         buf.put_u32(meta_offset as u32);
+        buf.put_u32(bloom_offset as u32);
+        buf.put_u32(range_tombstones_offset as u32);
+        buf.put_u8(if index_partitions.is_empty() {
+            INDEX_TYPE_FULL
+        } else {
+            INDEX_TYPE_PARTITIONED
+        });
+        buf.put_u8(SST_FORMAT_VERSION);
 
         let file = FileObject::create(path.as_ref(), buf)?;
+        let data_range = self
+            .meta
+            .first()
+            .zip(self.meta.last())
+            .map(|(first, last)| (first.first_key.clone(), last.last_key.clone()));
+        let (first_key, last_key) =
+            range_tombstone::extend_key_range(data_range, &self.range_tombstones).unwrap();
+        let block_meta = if index_partitions.is_empty() {
+            self.meta
+        } else {
+            Vec::new()
+        };
         Ok(SsTable {
             id,
             file,
-            first_key: self.meta.first().unwrap().first_key.clone(),
-            last_key: self.meta.last().unwrap().last_key.clone(),
-            block_meta: self.meta,
+            first_key,
+            last_key,
+            block_meta,
+            index_partitions,
             block_meta_offset: meta_offset,
             block_cache,
-            bloom: None,
+            bloom,
+            prefix_extractor: self
+                .prefix_extractor
+                .filter(|_| self.bloom_bits_per_key > 0),
+            block_format: BlockFormat::V4,
+            range_tombstones: self.range_tombstones,
             max_ts: 0,
         })
     }
@@ -126,3 +386,6 @@ impl SsTableBuilder {
         self.build(0, None, path)
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,133 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::{BlockCache, LsmStorageInner, LsmStorageOptions, PrefixExtractor};
+use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::test_harness::value_of;
+
+fn key_of(idx: usize) -> Vec<u8> {
+    format!("t{}/key_{:05}", idx % 7, idx).into_bytes()
+}
+
+fn build_partitioned(path: &std::path::Path, num_keys: usize) -> SsTable {
+    let mut keys = (0..num_keys).map(key_of).collect::<Vec<_>>();
+    keys.sort();
+    let mut builder = SsTableBuilder::new(128)
+        .with_index_partition_size(128)
+        .with_prefix_extractor(Some(PrefixExtractor::Delimited(b'/', 1)));
+    for key in &keys {
+        let idx = String::from_utf8_lossy(&key[7..]).parse::<usize>().unwrap();
+        builder.add(KeySlice::for_testing_from_slice_no_ts(key), &value_of(idx));
+    }
+    builder.build_for_test(path).unwrap()
+}
+
+#[test]
+fn test_partitioned_index() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let num_keys = 1000;
+    let built = build_partitioned(&path, num_keys);
+    let num_blocks = built.num_of_blocks();
+    assert!(built.index_partitions.len() > 10);
+    assert!(built.block_meta.is_empty());
+
+    let cache = Arc::new(BlockCache::new(1024));
+    let sst =
+        Arc::new(SsTable::open(1, Some(cache.clone()), FileObject::open(&path).unwrap()).unwrap());
+    assert_eq!(sst.index_partitions, built.index_partitions);
+    assert_eq!(sst.num_of_blocks(), num_blocks);
+    assert_eq!(sst.first_key(), built.first_key());
+    assert_eq!(sst.last_key(), built.last_key());
+
+    // Nothing but the top-level index is loaded until the SST is read.
+    assert_eq!(cache.iter().count(), 0);
+
+    let mut keys = (0..num_keys).collect::<Vec<_>>();
+    keys.sort_by_key(|idx| key_of(*idx));
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for idx in &keys {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), &key_of(*idx)[..]);
+        assert_eq!(iter.value(), &value_of(*idx)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+
+    for idx in keys.iter().step_by(37) {
+        let key = key_of(*idx);
+        assert!(sst.may_contain(&key));
+        let iter = SsTableIterator::create_and_seek_to_key(
+            sst.clone(),
+            KeySlice::for_testing_from_slice_no_ts(&key),
+        )
+        .unwrap();
+        assert_eq!(iter.key().for_testing_key_ref(), &key[..]);
+    }
+    let rejected = (num_keys..num_keys * 2)
+        .filter(|idx| !sst.may_contain(&key_of(*idx)))
+        .count();
+    assert!(rejected > num_keys * 9 / 10, "only {} rejected", rejected);
+    for tenant in 0..7 {
+        assert!(sst.may_contain_prefix(format!("t{}/", tenant).as_bytes()));
+    }
+}
+
+#[test]
+fn test_partitioned_index_storage() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.block_size = 128;
+    options.index_partition_size = 128;
+    options.prefix_extractor = Some(PrefixExtractor::Delimited(b'/', 1));
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    let mut keys = (0..500).map(key_of).collect::<Vec<_>>();
+    keys.sort();
+    let mut builder = storage.new_sst_builder();
+    for key in &keys {
+        builder.add(KeySlice::for_testing_from_slice_no_ts(key), b"v");
+    }
+    let sst_id = storage.next_sst_id();
+    let sst = builder
+        .build(
+            sst_id,
+            Some(storage.block_cache.clone()),
+            storage.path_of_sst(sst_id),
+        )
+        .unwrap();
+    assert!(!sst.index_partitions.is_empty());
+    {
+        let mut guard = storage.state.write();
+        let mut snapshot = guard.as_ref().clone();
+        snapshot.l0_sstables.insert(0, sst_id);
+        snapshot.sstables.insert(sst_id, Arc::new(sst));
+        *guard = Arc::new(snapshot);
+    }
+
+    for key in keys.iter().step_by(13) {
+        assert_eq!(&storage.get(key).unwrap().unwrap()[..], b"v");
+    }
+    assert_eq!(storage.get(b"t3/key_99999").unwrap(), None);
+
+    let mut iter = storage.scan_prefix(b"t3/").unwrap();
+    let mut count = 0;
+    while iter.is_valid() {
+        assert!(iter.key().starts_with(b"t3/"));
+        count += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(count, (0..500).filter(|idx| idx % 7 == 3).count());
+
+    let mut iter = storage
+        .scan(Bound::Excluded(&keys[100]), Bound::Included(&keys[300]))
+        .unwrap();
+    for key in &keys[101..=300] {
+        assert_eq!(iter.key(), &key[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
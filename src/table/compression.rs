@@ -0,0 +1,85 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{Result, bail};
+
+/// The codec of a data block, recorded in the byte following the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionType {
+    #[default]
+    None,
+    /// Fast compression, suited to the upper levels that are rewritten soon.
+    Lz4,
+    Snappy,
+    /// Strong compression, suited to the bottom level.
+    Zstd,
+}
+
+/// The zstd level used for compression, the default of the zstd library.
+const ZSTD_LEVEL: i32 = 3;
+
+impl CompressionType {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Lz4 => 1,
+            CompressionType::Snappy => 2,
+            CompressionType::Zstd => 3,
+        }
+    }
+
+    pub(crate) fn from_u8(codec: u8) -> Result<Self> {
+        Ok(match codec {
+            0 => CompressionType::None,
+            1 => CompressionType::Lz4,
+            2 => CompressionType::Snappy,
+            3 => CompressionType::Zstd,
+            _ => bail!("unknown compression type {}", codec),
+        })
+    }
+
+    /// Compress `data` and append it to `buf`, returning the codec actually used: blocks that do
+    /// not get smaller are stored uncompressed.
+    pub(crate) fn compress(self, data: &[u8], buf: &mut Vec<u8>) -> CompressionType {
+        let compressed = match self {
+            CompressionType::None => None,
+            CompressionType::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+            CompressionType::Snappy => snap::raw::Encoder::new().compress_vec(data).ok(),
+            CompressionType::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok(),
+        };
+        match compressed {
+            Some(compressed) if compressed.len() < data.len() => {
+                buf.extend(compressed);
+                self
+            }
+            _ => {
+                buf.extend_from_slice(data);
+                CompressionType::None
+            }
+        }
+    }
+
+    /// Decompress `data` compressed with this codec.
+    pub(crate) fn decompress(self, data: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(match self {
+            CompressionType::None => data,
+            CompressionType::Lz4 => lz4_flex::decompress_size_prepended(&data)?,
+            CompressionType::Snappy => snap::raw::Decoder::new().decompress_vec(&data)?,
+            CompressionType::Zstd => zstd::stream::decode_all(&data[..])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests;
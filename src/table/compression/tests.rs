@@ -0,0 +1,113 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use tempfile::tempdir;
+
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::LsmStorageOptions;
+use crate::table::{CompressionType, FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::test_harness::key_of;
+
+const CODECS: [CompressionType; 4] = [
+    CompressionType::None,
+    CompressionType::Lz4,
+    CompressionType::Snappy,
+    CompressionType::Zstd,
+];
+
+fn json_value_of(idx: usize) -> Vec<u8> {
+    format!(
+        r#"{{"id":{},"name":"user_{}","email":"user_{}@example.com","active":true,"tags":["a","b"]}}"#,
+        idx, idx, idx
+    )
+    .into_bytes()
+}
+
+fn build_sst(path: &Path, compression: CompressionType, values: &[Vec<u8>]) -> (u64, Arc<SsTable>) {
+    let mut builder = SsTableBuilder::new(4096).with_compression(compression);
+    for (idx, value) in values.iter().enumerate() {
+        builder.add(KeySlice::for_testing_from_slice_no_ts(&key_of(idx)), value);
+    }
+    drop(builder.build_for_test(path).unwrap());
+    let sst = SsTable::open_for_test(FileObject::open(path).unwrap()).unwrap();
+    (sst.table_size(), Arc::new(sst))
+}
+
+fn check_sst(sst: Arc<SsTable>, values: &[Vec<u8>]) {
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for (idx, value) in values.iter().enumerate() {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().for_testing_key_ref(), &key_of(idx)[..]);
+        assert_eq!(iter.value(), &value[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    let iter = SsTableIterator::create_and_seek_to_key(
+        sst,
+        KeySlice::for_testing_from_slice_no_ts(&key_of(values.len() / 2)),
+    )
+    .unwrap();
+    assert_eq!(iter.value(), &values[values.len() / 2][..]);
+}
+
+#[test]
+fn test_compressed_blocks() {
+    let dir = tempdir().unwrap();
+    let values = (0..1000).map(json_value_of).collect::<Vec<_>>();
+    let mut uncompressed_size = 0;
+    for compression in CODECS {
+        let (size, sst) = build_sst(
+            &dir.path().join(format!("{:?}.sst", compression)),
+            compression,
+            &values,
+        );
+        if compression == CompressionType::None {
+            uncompressed_size = size;
+        } else {
+            assert!(
+                size * 2 < uncompressed_size,
+                "{:?} only compresses {} bytes to {}",
+                compression,
+                uncompressed_size,
+                size
+            );
+        }
+        check_sst(sst, &values);
+    }
+}
+
+#[test]
+fn test_incompressible_blocks_stored_uncompressed() {
+    let dir = tempdir().unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let values = (0..200)
+        .map(|_| (0..100).map(|_| rng.r#gen::<u8>()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let (uncompressed_size, _) =
+        build_sst(&dir.path().join("none.sst"), CompressionType::None, &values);
+    for compression in CODECS {
+        let (size, sst) = build_sst(
+            &dir.path().join(format!("{:?}.sst", compression)),
+            compression,
+            &values,
+        );
+        // Compressing random values can only grow the blocks.
+        assert!(size <= uncompressed_size, "{:?} grows the SST", compression);
+        check_sst(sst, &values);
+    }
+}
+
+#[test]
+fn test_compression_per_level() {
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    assert_eq!(options.compression_of_level(0), CompressionType::None);
+    assert_eq!(options.compression_of_level(3), CompressionType::None);
+    options.compression_per_level = vec![CompressionType::None, CompressionType::Lz4];
+    options.compression_per_level.push(CompressionType::Zstd);
+    assert_eq!(options.compression_of_level(0), CompressionType::None);
+    assert_eq!(options.compression_of_level(1), CompressionType::Lz4);
+    assert_eq!(options.compression_of_level(2), CompressionType::Zstd);
+    assert_eq!(options.compression_of_level(6), CompressionType::Zstd);
+}
@@ -20,17 +20,37 @@ use std::sync::Arc;
 use anyhow::Result;
 
 use super::SsTable;
-use crate::{block::BlockIterator, iterators::StorageIterator, key::KeySlice};
+use crate::{
+    block::{Block, BlockIterator},
+    iterators::{SeekableIterator, StorageIterator},
+    key::KeySlice,
+    value_type::ValueType,
+};
 
 /// An iterator over the contents of an SSTable.
 pub struct SsTableIterator {
     table: Arc<SsTable>,
     blk_iter: BlockIterator,
     blk_idx: usize,
+    /// Whether `next` moves to smaller keys.
+    reverse: bool,
+}
+
+/// An invalid block iterator, for an SST without data blocks.
+fn empty_block_iter() -> BlockIterator {
+    BlockIterator::create_and_seek_to_last(Arc::new(Block {
+        data: Vec::new(),
+        offsets: Vec::new(),
+        hash_index: Vec::new(),
+        typed: true,
+    }))
 }
 
 impl SsTableIterator {
     fn seek_to_first_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok((0, empty_block_iter()));
+        }
         Ok((
             0,
             BlockIterator::create_and_seek_to_first(table.read_block_cached(0)?),
@@ -43,6 +63,7 @@ impl SsTableIterator {
             table,
             blk_iter,
             blk_idx,
+            reverse: false,
         })
     }
 
@@ -51,10 +72,14 @@ impl SsTableIterator {
         let (blk_idx, blk_iter) = Self::seek_to_first_inner(&self.table)?;
         self.blk_idx = blk_idx;
         self.blk_iter = blk_iter;
+        self.reverse = false;
         Ok(())
     }
     fn seek_to_key_inner(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
-        let mut blk_idx = table.find_block_idx(key);
+        if table.num_of_blocks() == 0 {
+            return Ok((0, empty_block_iter()));
+        }
+        let mut blk_idx = table.find_block_idx(key)?;
         let mut blk_iter =
             BlockIterator::create_and_seek_to_key(table.read_block_cached(blk_idx)?, key);
         if !blk_iter.is_valid() {
@@ -74,6 +99,7 @@ impl SsTableIterator {
             table,
             blk_iter,
             blk_idx,
+            reverse: false,
         })
     }
 
@@ -84,6 +110,69 @@ impl SsTableIterator {
         let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
         self.blk_iter = blk_iter;
         self.blk_idx = blk_idx;
+        self.reverse = false;
+        Ok(())
+    }
+
+    fn seek_to_last_inner(table: &Arc<SsTable>) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok((0, empty_block_iter()));
+        }
+        let blk_idx = table.num_of_blocks() - 1;
+        Ok((
+            blk_idx,
+            BlockIterator::create_and_seek_to_last(table.read_block_cached(blk_idx)?),
+        ))
+    }
+
+    /// Create a new reverse iterator and seek to the last key-value pair in the last data block.
+    pub fn create_and_seek_to_last(table: Arc<SsTable>) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_last_inner(&table)?;
+        Ok(Self {
+            table,
+            blk_iter,
+            blk_idx,
+            reverse: true,
+        })
+    }
+
+    /// Seek to the last key-value pair, after which `next` moves to smaller keys.
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_to_last_inner(&self.table)?;
+        self.blk_idx = blk_idx;
+        self.blk_iter = blk_iter;
+        self.reverse = true;
+        Ok(())
+    }
+
+    fn seek_for_prev_inner(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
+        if table.num_of_blocks() == 0 {
+            return Ok((0, empty_block_iter()));
+        }
+        // The first key of the block is <= `key`, unless `key` is before the first block.
+        let blk_idx = table.find_block_idx(key)?;
+        let blk_iter =
+            BlockIterator::create_and_seek_for_prev(table.read_block_cached(blk_idx)?, key);
+        Ok((blk_idx, blk_iter))
+    }
+
+    /// Create a new reverse iterator and seek to the last key-value pair which <= `key`.
+    pub fn create_and_seek_for_prev(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_for_prev_inner(&table, key)?;
+        Ok(Self {
+            table,
+            blk_iter,
+            blk_idx,
+            reverse: true,
+        })
+    }
+
+    /// Seek to the last key-value pair which <= `key`, after which `next` moves to smaller keys.
+    pub fn seek_for_prev(&mut self, key: KeySlice) -> Result<()> {
+        let (blk_idx, blk_iter) = Self::seek_for_prev_inner(&self.table, key)?;
+        self.blk_idx = blk_idx;
+        self.blk_iter = blk_iter;
+        self.reverse = true;
         Ok(())
     }
 }
@@ -92,7 +181,7 @@ impl StorageIterator for SsTableIterator {
     type KeyType<'a> = KeySlice<'a>;
 
     /// Return the `key` that's held by the underlying block iterator.
-    fn key(&self) -> KeySlice {
+    fn key(&self) -> KeySlice<'_> {
         self.blk_iter.key()
     }
 
@@ -101,16 +190,28 @@ impl StorageIterator for SsTableIterator {
         self.blk_iter.value()
     }
 
+    fn value_type(&self) -> ValueType {
+        self.blk_iter.value_type()
+    }
+
     /// Return whether the current block iterator is valid or not.
     fn is_valid(&self) -> bool {
         self.blk_iter.is_valid()
     }
 
-    /// Move to the next `key` in the block.
+    /// Move to the next `key` in the block, or the previous one for a reverse iterator.
     /// Note: You may want to check if the current block iterator is valid after the move.
     fn next(&mut self) -> Result<()> {
         self.blk_iter.next();
-        if !self.blk_iter.is_valid() {
+        if !self.blk_iter.is_valid() && self.reverse {
+            // move to the previous block
+            if self.blk_idx > 0 {
+                self.blk_idx -= 1;
+                self.blk_iter = BlockIterator::create_and_seek_to_last(
+                    self.table.read_block_cached(self.blk_idx)?,
+                );
+            }
+        } else if !self.blk_iter.is_valid() {
             // move to the next block
             self.blk_idx += 1;
             if self.blk_idx < self.table.num_of_blocks() {
@@ -122,3 +223,21 @@ impl StorageIterator for SsTableIterator {
         Ok(())
     }
 }
+
+impl SeekableIterator for SsTableIterator {
+    fn seek_to_first(&mut self) -> Result<()> {
+        SsTableIterator::seek_to_first(self)
+    }
+
+    fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.seek_to_key(KeySlice::from_slice(key))
+    }
+
+    fn seek_to_last(&mut self) -> Result<()> {
+        SsTableIterator::seek_to_last(self)
+    }
+
+    fn seek_for_prev(&mut self, key: &[u8]) -> Result<()> {
+        SsTableIterator::seek_for_prev(self, KeySlice::from_slice(key))
+    }
+}
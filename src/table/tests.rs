@@ -0,0 +1,98 @@
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use tempfile::tempdir;
+
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::table::{CorruptionError, FileObject, SsTable, SsTableBuilder, SsTableIterator};
+
+fn build_sst(path: &Path) -> SsTable {
+    let mut builder = SsTableBuilder::new(128);
+    for idx in 0..100 {
+        let key = format!("key_{:03}", idx);
+        let value = format!("value_{:03}", idx);
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_bytes()),
+            value.as_bytes(),
+        );
+    }
+    builder.build(7, None, path).unwrap()
+}
+
+fn flip_byte(path: &Path, offset: u64) {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut byte = [0; 1];
+    file.read_exact_at(&mut byte, offset).unwrap();
+    byte[0] ^= 0xff;
+    file.write_all_at(&byte, offset).unwrap();
+}
+
+#[test]
+fn test_sst_block_corruption() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("7.sst");
+    let sst = build_sst(&path);
+    assert!(sst.num_of_blocks() > 2);
+    let offset = sst.block_meta[1].offset as u64;
+    drop(sst);
+    flip_byte(&path, offset + 1);
+
+    let sst = SsTable::open(7, None, FileObject::open(&path).unwrap()).unwrap();
+    sst.read_block(0).unwrap();
+    let Err(err) = sst.read_block(1) else {
+        panic!("corrupted block is read");
+    };
+    let err = err.downcast_ref::<CorruptionError>().unwrap();
+    assert_eq!(err.sst_id, 7);
+    assert_eq!(err.block_idx, Some(1));
+
+    // A scan reaching the corrupted block fails instead of returning garbage.
+    let sst = std::sync::Arc::new(sst);
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    let err = loop {
+        match iter.next() {
+            Ok(()) => assert!(iter.is_valid()),
+            Err(err) => break err,
+        }
+    };
+    assert!(err.downcast_ref::<CorruptionError>().is_some());
+}
+
+#[test]
+fn test_sst_meta_corruption() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("7.sst");
+    let sst = build_sst(&path);
+    let meta_offset = sst.block_meta_offset as u64;
+    drop(sst);
+    flip_byte(&path, meta_offset + 6);
+
+    let Err(err) = SsTable::open(7, None, FileObject::open(&path).unwrap()) else {
+        panic!("corrupted SST is opened");
+    };
+    let err = err.downcast_ref::<CorruptionError>().unwrap();
+    assert_eq!(err.sst_id, 7);
+    assert_eq!(err.block_idx, None);
+}
+
+#[test]
+fn test_sst_truncated() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("7.sst");
+    drop(build_sst(&path));
+    let len = std::fs::metadata(&path).unwrap().len();
+    for new_len in [0, 3, len / 2, len - 5] {
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(new_len).unwrap();
+        drop(file);
+        let Err(err) = SsTable::open(7, None, FileObject::open(&path).unwrap()) else {
+            panic!("corrupted SST is opened");
+        };
+        assert!(err.downcast_ref::<CorruptionError>().is_some());
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers shared by the tests of the modules. The course tests in `tests/` have their own
+//! harness, which is rewritten by the copy-test command.
+
+use std::sync::Arc;
+
+use crate::iterators::StorageIterator;
+use crate::key::KeySlice;
+use crate::lsm_storage::LsmStorageInner;
+use crate::range_tombstone::RangeTombstone;
+
+pub(crate) fn key_of(idx: usize) -> Vec<u8> {
+    format!("key_{:04}", idx).into_bytes()
+}
+
+pub(crate) fn value_of(idx: usize) -> Vec<u8> {
+    format!("value_{}", idx).into_bytes()
+}
+
+/// Drain `iter` into its `(key, value)` pairs.
+pub(crate) fn collect(
+    iter: &mut impl for<'a> StorageIterator<KeyType<'a> = &'a [u8]>,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut result = Vec::new();
+    while iter.is_valid() {
+        result.push((iter.key().to_vec(), iter.value().to_vec()));
+        iter.next().unwrap();
+    }
+    result
+}
+
+/// Build an SST from `data`, sorted by key, and `tombstones`, and add it as the newest L0 SST of
+/// `storage`. Its blocks are not cached, so that the tests can corrupt the file. Returns the id
+/// of the SST.
+pub(crate) fn add_l0_sst(
+    storage: &LsmStorageInner,
+    data: impl IntoIterator<Item = (impl AsRef<[u8]>, impl AsRef<[u8]>)>,
+    tombstones: &[RangeTombstone],
+) -> usize {
+    let mut builder = storage.new_sst_builder();
+    for (key, value) in data {
+        builder.add(
+            KeySlice::for_testing_from_slice_no_ts(key.as_ref()),
+            value.as_ref(),
+        );
+    }
+    for tombstone in tombstones {
+        builder.add_range_tombstone(tombstone.clone());
+    }
+    let sst_id = storage.next_sst_id();
+    let sst = builder
+        .build(sst_id, None, storage.path_of_sst(sst_id))
+        .unwrap();
+    let mut guard = storage.state.write();
+    let mut snapshot = guard.as_ref().clone();
+    snapshot.l0_sstables.insert(0, sst_id);
+    snapshot.sstables.insert(sst_id, Arc::new(sst));
+    *guard = Arc::new(snapshot);
+    sst_id
+}
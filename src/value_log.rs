@@ -0,0 +1,547 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key-value separation: values of at least `ValueLogOptions::threshold` bytes are appended to
+//! value log files, and the LSM tree stores a pointer to them instead.
+//!
+//! While the value log is enabled, every value stored in the LSM tree other than a tombstone is
+//! prefixed with a tag: `VALUE_INLINE` followed by the value, or `VALUE_POINTER` followed by an
+//! encoded `ValuePointer`. A directory must therefore always be opened with the value log enabled,
+//! or always without it.
+//!
+//! A value log file is a sequence of records
+//! `key_len (u16) | key | value_len (u32) | value | checksum (u32)`, where the checksum covers the
+//! rest of the record. Files are never appended to again after the storage is reopened.
+//!
+//! The GC rewrites the live values of a file to the head of the log, and then retires it. A retired
+//! file is deleted once no reader is left that pinned the value log before it was retired, see
+//! `ValueLog::pin`. Only the newest version of a key is rewritten: the older versions still in SSTs
+//! keep pointing into the retired file, and are never read again, until compaction drops them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::ops::Deref;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail, ensure};
+use bytes::{Buf, BufMut, Bytes};
+use parking_lot::{Mutex, RwLock};
+
+use crate::value_type::{ValueType, split_expiry, split_expiry_bytes, with_expiry};
+
+const SIZEOF_U16: usize = std::mem::size_of::<u16>();
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// The tag of a value stored in the LSM tree as is.
+const VALUE_INLINE: u8 = 0;
+/// The tag of a value stored in the value log.
+const VALUE_POINTER: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct ValueLogOptions {
+    /// Values of at least this size are stored in the value log.
+    pub threshold: usize,
+    /// Start a new file once the current one would grow beyond this size.
+    pub file_size: usize,
+}
+
+impl Default for ValueLogOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 4096,
+            file_size: 64 << 20,
+        }
+    }
+}
+
+/// Locates a record in the value log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub file_id: usize,
+    pub offset: u64,
+    /// The length of the whole record.
+    pub len: u32,
+}
+
+/// The size of an encoded `ValuePointer`.
+const SIZEOF_POINTER: usize = SIZEOF_U32 + std::mem::size_of::<u64>() + SIZEOF_U32;
+
+impl ValuePointer {
+    /// Encode the pointer as a value stored in the LSM tree, tag included.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + SIZEOF_POINTER);
+        buf.put_u8(VALUE_POINTER);
+        buf.put_u32(self.file_id as u32);
+        buf.put_u64(self.offset);
+        buf.put_u32(self.len);
+        buf
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        ensure!(buf.len() == SIZEOF_POINTER, "value pointer is truncated");
+        Ok(Self {
+            file_id: buf.get_u32() as usize,
+            offset: buf.get_u64(),
+            len: buf.get_u32(),
+        })
+    }
+}
+
+/// A record read back from a value log file.
+pub(crate) struct ValueLogRecord {
+    pub pointer: ValuePointer,
+    pub key: Bytes,
+    pub value: Bytes,
+}
+
+/// The file being appended to.
+struct ActiveFile {
+    id: usize,
+    file: Arc<File>,
+    offset: u64,
+}
+
+/// The files of the value log.
+#[derive(Default)]
+struct Files {
+    /// Every file that may be read, including the active one and the retired ones not deleted yet.
+    readable: BTreeMap<usize, Arc<File>>,
+    /// The files retired by the GC, but still pinned by readers.
+    retired: BTreeSet<usize>,
+    /// The retired files whose deletion failed or was not synced, deleted again by the next GC.
+    undeleted: BTreeSet<usize>,
+}
+
+/// Delete the value log files `ids` from `path`, some of which may be deleted already, and sync
+/// the directory.
+fn delete_files(path: &Path, ids: &BTreeSet<usize>) -> Result<()> {
+    for &id in ids {
+        match std::fs::remove_file(ValueLog::path_of_file_static(path, id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("failed to delete value log file {}", id));
+            }
+            _ => {}
+        }
+    }
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Readers pin the current epoch, which keeps alive every later one. A file retired by the GC is
+/// deleted when the epoch current at that time is dropped, that is once every reader that pinned
+/// it or an earlier epoch is gone.
+struct Epoch {
+    path: PathBuf,
+    files: Arc<RwLock<Files>>,
+    /// The files retired while this epoch was the current one.
+    retired: Mutex<Vec<usize>>,
+    next: Mutex<Option<Arc<Epoch>>>,
+}
+
+impl Epoch {
+    fn new(path: PathBuf, files: Arc<RwLock<Files>>) -> Self {
+        Self {
+            path,
+            files,
+            retired: Mutex::new(Vec::new()),
+            next: Mutex::new(None),
+        }
+    }
+}
+
+impl Drop for Epoch {
+    fn drop(&mut self) {
+        let retired = std::mem::take(self.retired.get_mut());
+        if !retired.is_empty() {
+            {
+                let mut files = self.files.write();
+                for id in &retired {
+                    files.readable.remove(id);
+                    files.retired.remove(id);
+                }
+            }
+            // The error is reported by the next GC, which deletes the files again.
+            let retired = retired.into_iter().collect();
+            if delete_files(&self.path, &retired).is_err() {
+                self.files.write().undeleted.extend(retired);
+            }
+        }
+        // Drop the following epochs that are no longer pinned one by one, not recursively.
+        let mut next = self.next.get_mut().take();
+        while let Some(epoch) = next {
+            next = match Arc::try_unwrap(epoch) {
+                Ok(mut epoch) => epoch.next.get_mut().take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// The value log, pinned so that every file readable when it was pinned stays readable until it
+/// is dropped, see `ValueLog::pin`.
+pub(crate) struct PinnedValueLog {
+    value_log: Arc<ValueLog>,
+    _epoch: Arc<Epoch>,
+}
+
+impl Deref for PinnedValueLog {
+    type Target = ValueLog;
+
+    fn deref(&self) -> &ValueLog {
+        &self.value_log
+    }
+}
+
+pub struct ValueLog {
+    path: PathBuf,
+    options: ValueLogOptions,
+    active: Mutex<ActiveFile>,
+    files: Arc<RwLock<Files>>,
+    /// The epoch new readers pin.
+    current: Mutex<Arc<Epoch>>,
+}
+
+impl ValueLog {
+    /// Open the value log files in `path`, and start a new file to append to.
+    pub fn open(path: impl AsRef<Path>, options: ValueLogOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(path)? {
+            let file_name = entry?.file_name();
+            if let Some(id) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".vlog"))
+                .and_then(|id| id.parse::<usize>().ok())
+            {
+                let file = File::open(Self::path_of_file_static(path, id))
+                    .context("failed to open value log")?;
+                files.insert(id, Arc::new(file));
+            }
+        }
+        let id = files.last_key_value().map_or(1, |(id, _)| id + 1);
+        let file = Arc::new(Self::create_file(path, id)?);
+        files.insert(id, file.clone());
+        let files = Arc::new(RwLock::new(Files {
+            readable: files,
+            ..Default::default()
+        }));
+        Ok(Self {
+            path: path.to_path_buf(),
+            options,
+            active: Mutex::new(ActiveFile {
+                id,
+                file,
+                offset: 0,
+            }),
+            current: Mutex::new(Arc::new(Epoch::new(path.to_path_buf(), files.clone()))),
+            files,
+        })
+    }
+
+    fn path_of_file_static(path: impl AsRef<Path>, id: usize) -> PathBuf {
+        path.as_ref().join(format!("{:05}.vlog", id))
+    }
+
+    fn create_file(path: &Path, id: usize) -> Result<File> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .append(true)
+            .open(Self::path_of_file_static(path, id))
+            .context("failed to create value log")?;
+        File::open(path)?.sync_all()?;
+        Ok(file)
+    }
+
+    /// Whether `value` is large enough to be stored in the value log.
+    pub(crate) fn should_separate(&self, value: &[u8]) -> bool {
+        value.len() >= self.options.threshold
+    }
+
+    /// Turn the values of `entries` into the values stored in the LSM tree, appending the large
+    /// ones to the log in a single write. Tombstones are kept empty, and the expiry of an expiring
+    /// put stays in front of the tag.
+    pub(crate) fn separate(
+        &self,
+        entries: &[(&[u8], ValueType, &[u8])],
+        sync: bool,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut split = Vec::with_capacity(entries.len());
+        for (key, value_type, value) in entries {
+            split.push(match value_type {
+                ValueType::PutWithExpiry => {
+                    let (expires_at, value) = split_expiry(value)?;
+                    (*key, *value_type, Some(expires_at), value)
+                }
+                _ => (*key, *value_type, None, *value),
+            });
+        }
+        let large = split
+            .iter()
+            .filter(|(_, value_type, _, value)| {
+                !value_type.is_tombstone() && self.should_separate(value)
+            })
+            .map(|(key, _, _, value)| (*key, *value))
+            .collect::<Vec<_>>();
+        let mut pointers = self.append(&large, sync)?.into_iter();
+        Ok(split
+            .iter()
+            .map(|(_, value_type, expires_at, value)| {
+                if value_type.is_tombstone() {
+                    return Vec::new();
+                }
+                let stored = if self.should_separate(value) {
+                    pointers.next().unwrap().encode()
+                } else {
+                    let mut buf = Vec::with_capacity(1 + value.len());
+                    buf.put_u8(VALUE_INLINE);
+                    buf.put_slice(value);
+                    buf
+                };
+                match expires_at {
+                    Some(expires_at) => with_expiry(*expires_at, &stored),
+                    None => stored,
+                }
+            })
+            .collect())
+    }
+
+    /// Append key-value records to the active file, and return where they are.
+    pub(crate) fn append(
+        &self,
+        records: &[(&[u8], &[u8])],
+        sync: bool,
+    ) -> Result<Vec<ValuePointer>> {
+        if records.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut buf = Vec::new();
+        let mut ranges = Vec::with_capacity(records.len());
+        for (key, value) in records {
+            let start = buf.len();
+            buf.put_u16(key.len() as u16);
+            buf.put_slice(key);
+            buf.put_u32(value.len() as u32);
+            buf.put_slice(value);
+            let checksum = crc32fast::hash(&buf[start..]);
+            buf.put_u32(checksum);
+            ranges.push((start as u64, (buf.len() - start) as u32));
+        }
+        let mut active = self.active.lock();
+        if active.offset > 0 && active.offset as usize + buf.len() > self.options.file_size {
+            // Make the full file durable before moving on, so that a later sync only needs to
+            // cover the new one.
+            active.file.sync_data()?;
+            let id = active.id + 1;
+            let file = Arc::new(Self::create_file(&self.path, id)?);
+            self.files.write().readable.insert(id, file.clone());
+            *active = ActiveFile {
+                id,
+                file,
+                offset: 0,
+            };
+        }
+        (&*active.file).write_all(&buf)?;
+        if sync {
+            active.file.sync_data()?;
+        }
+        let base = active.offset;
+        active.offset += buf.len() as u64;
+        Ok(ranges
+            .into_iter()
+            .map(|(offset, len)| ValuePointer {
+                file_id: active.id,
+                offset: base + offset,
+                len,
+            })
+            .collect())
+    }
+
+    /// Fsync the file being appended to.
+    pub fn sync(&self) -> Result<()> {
+        self.active.lock().file.sync_data()?;
+        Ok(())
+    }
+
+    /// Turn a value stored in the LSM tree back into the value written by the user.
+    pub(crate) fn resolve(&self, stored: Bytes) -> Result<Bytes> {
+        match stored.first() {
+            None => Ok(stored),
+            Some(&VALUE_INLINE) => Ok(stored.slice(1..)),
+            Some(&VALUE_POINTER) => self.read(ValuePointer::decode(&stored[1..])?),
+            Some(tag) => bail!("unknown value tag {}", tag),
+        }
+    }
+
+    /// Turn the value of an entry stored in the LSM tree back into the value written by the user,
+    /// keeping the expiry of an expiring put.
+    pub(crate) fn resolve_entry(&self, value_type: ValueType, stored: Bytes) -> Result<Bytes> {
+        match value_type {
+            ValueType::Delete | ValueType::SingleDelete => Ok(stored),
+            ValueType::PutWithExpiry => {
+                let (expires_at, stored) = split_expiry_bytes(&stored)?;
+                Ok(with_expiry(expires_at, &self.resolve(stored)?).into())
+            }
+            ValueType::Put | ValueType::Merge => self.resolve(stored),
+        }
+    }
+
+    /// Read the value at `pointer`, verifying its checksum.
+    pub(crate) fn read(&self, pointer: ValuePointer) -> Result<Bytes> {
+        let Some(file) = self.files.read().readable.get(&pointer.file_id).cloned() else {
+            bail!("value log file {} does not exist", pointer.file_id);
+        };
+        let mut data = vec![0; pointer.len as usize];
+        file.read_exact_at(&mut data, pointer.offset)
+            .with_context(|| format!("failed to read value log file {}", pointer.file_id))?;
+        let Some(record) = Self::decode_record(pointer, Bytes::from(data)) else {
+            bail!(
+                "value log file {} is corrupted at offset {}",
+                pointer.file_id,
+                pointer.offset
+            );
+        };
+        Ok(record.value)
+    }
+
+    /// Decode the record at the start of `data`, or `None` if it is truncated or corrupted.
+    fn decode_record(pointer: ValuePointer, data: Bytes) -> Option<ValueLogRecord> {
+        let mut buf = &data[..];
+        if buf.len() < SIZEOF_U16 {
+            return None;
+        }
+        let key_len = buf.get_u16() as usize;
+        if buf.len() < key_len + SIZEOF_U32 {
+            return None;
+        }
+        buf.advance(key_len);
+        let value_len = buf.get_u32() as usize;
+        let len = SIZEOF_U16 + key_len + SIZEOF_U32 + value_len;
+        if data.len() < len + SIZEOF_U32 {
+            return None;
+        }
+        let checksum = (&data[len..]).get_u32();
+        if checksum != crc32fast::hash(&data[..len]) {
+            return None;
+        }
+        Some(ValueLogRecord {
+            pointer: ValuePointer {
+                len: (len + SIZEOF_U32) as u32,
+                ..pointer
+            },
+            key: data.slice(SIZEOF_U16..SIZEOF_U16 + key_len),
+            value: data.slice(len - value_len..len),
+        })
+    }
+
+    /// The oldest file that is no longer appended to nor retired, the next one to
+    /// garbage-collect.
+    pub(crate) fn oldest_file(&self) -> Option<usize> {
+        let active_id = self.active.lock().id;
+        let files = self.files.read();
+        files
+            .readable
+            .keys()
+            .copied()
+            .find(|id| !files.retired.contains(id))
+            .filter(|&id| id != active_id)
+    }
+
+    /// Read every record of a file. A torn record at the end of the file, left by a crash, ends
+    /// the file.
+    pub(crate) fn records(&self, file_id: usize) -> Result<Vec<ValueLogRecord>> {
+        let data = Bytes::from(std::fs::read(Self::path_of_file_static(
+            &self.path, file_id,
+        ))?);
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let pointer = ValuePointer {
+                file_id,
+                offset: offset as u64,
+                len: 0,
+            };
+            let Some(record) = Self::decode_record(pointer, data.slice(offset..)) else {
+                break;
+            };
+            offset += record.pointer.len as usize;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Keep every file readable now until the returned handle is dropped. Readers pin the value
+    /// log before taking a snapshot of the LSM tree, so that the files its pointers refer to are
+    /// not deleted while they read them, even if the GC relinks the values meanwhile.
+    pub(crate) fn pin(self: &Arc<Self>) -> PinnedValueLog {
+        PinnedValueLog {
+            value_log: self.clone(),
+            _epoch: self.current.lock().clone(),
+        }
+    }
+
+    /// Delete a file no longer referenced by the newest versions in the LSM tree, once the readers
+    /// that pinned the value log before are gone. This may be right away.
+    pub(crate) fn retire_file(&self, file_id: usize) {
+        let mut current = self.current.lock();
+        self.files.write().retired.insert(file_id);
+        current.retired.lock().push(file_id);
+        let next = Arc::new(Epoch::new(self.path.clone(), self.files.clone()));
+        *current.next.lock() = Some(next.clone());
+        *current = next;
+    }
+
+    /// Delete again the retired files whose deletion failed, see `Files::undeleted`.
+    pub(crate) fn delete_undeleted(&self) -> Result<()> {
+        let undeleted = std::mem::take(&mut self.files.write().undeleted);
+        if undeleted.is_empty() {
+            return Ok(());
+        }
+        delete_files(&self.path, &undeleted).inspect_err(|_| {
+            self.files.write().undeleted.extend(&undeleted);
+        })
+    }
+
+    /// The total size of the value log files, including the retired files not deleted yet.
+    pub fn disk_usage(&self) -> Result<u64> {
+        let mut size = 0;
+        for file in self.files.read().readable.values() {
+            size += file.metadata()?.len();
+        }
+        Ok(size)
+    }
+}
+
+/// The value written by the user for an entry other than a tombstone, without the expiry of an
+/// expiring put, and resolved from the value log if enabled.
+pub(crate) fn resolve_value(
+    value_log: Option<&ValueLog>,
+    value_type: ValueType,
+    stored: Bytes,
+) -> Result<Bytes> {
+    let stored = match value_type {
+        ValueType::PutWithExpiry => split_expiry_bytes(&stored)?.1,
+        _ => stored,
+    };
+    match value_log {
+        Some(value_log) => value_log.resolve(stored),
+        None => Ok(stored),
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,239 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::iterators::{SeekableIterator, StorageIterator};
+use crate::lsm_storage::{DurabilityMode, LsmStorageInner, LsmStorageOptions};
+use crate::test_harness::key_of;
+use crate::value_log::ValueLogOptions;
+
+/// Values alternate between inline and separated sizes.
+fn value_of(idx: usize, version: usize) -> Vec<u8> {
+    let len = if idx.is_multiple_of(2) {
+        10
+    } else {
+        2000 + idx
+    };
+    format!("{:03}_{}_", idx, version)
+        .into_bytes()
+        .into_iter()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+fn options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    options.value_log = Some(ValueLogOptions {
+        threshold: 1024,
+        file_size: 32 << 10,
+    });
+    options
+}
+
+fn check_storage(
+    storage: &LsmStorageInner,
+    num: usize,
+    version_of: impl Fn(usize) -> Option<usize>,
+) {
+    for idx in 0..num {
+        let expected = version_of(idx).map(|version| value_of(idx, version));
+        assert_eq!(
+            storage.get(&key_of(idx)).unwrap().as_deref(),
+            expected.as_deref(),
+            "get({})",
+            idx
+        );
+    }
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in (0..num).filter(|&idx| version_of(idx).is_some()) {
+        assert_eq!(iter.key(), &key_of(idx)[..]);
+        assert_eq!(iter.value(), &value_of(idx, version_of(idx).unwrap())[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_value_log_read_write() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options()).unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    for idx in (0..100).step_by(3) {
+        storage.put(&key_of(idx), &value_of(idx, 1)).unwrap();
+    }
+    storage.delete(&key_of(7)).unwrap();
+    let version_of = |idx: usize| match idx {
+        7 => None,
+        _ if idx.is_multiple_of(3) => Some(1),
+        _ => Some(0),
+    };
+    check_storage(&storage, 100, version_of);
+    // Only pointers are kept in the memtables.
+    assert!(storage.state.read().memtable.approximate_size() < 34 * 2000);
+    assert!(storage.value_log.as_ref().unwrap().disk_usage().unwrap() > 100 * 1000);
+
+    let mut iter = storage
+        .scan_rev(Bound::Unbounded, Bound::Unbounded)
+        .unwrap();
+    assert_eq!(iter.key(), &key_of(99)[..]);
+    assert_eq!(iter.value(), &value_of(99, 1)[..]);
+    iter.seek(&key_of(41)).unwrap();
+    assert_eq!(iter.value(), &value_of(41, 0)[..]);
+    iter.seek_for_prev(&key_of(7)).unwrap();
+    assert_eq!(iter.value(), &value_of(6, 1)[..]);
+
+    drop(iter);
+    drop(storage);
+    let storage = LsmStorageInner::open(dir.path(), options()).unwrap();
+    check_storage(&storage, 100, version_of);
+}
+
+#[test]
+fn test_value_log_gc() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options()).unwrap());
+    let value_log = storage.value_log.clone().unwrap();
+    // Nothing to collect but the file being appended to.
+    assert!(!storage.gc_value_log().unwrap());
+    for idx in 0..100 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    // Overwrite or delete most of the separated values.
+    for idx in 0..100 {
+        match idx % 5 {
+            0 | 1 => storage.put(&key_of(idx), &value_of(idx, 1)).unwrap(),
+            2 | 3 => storage.delete(&key_of(idx)).unwrap(),
+            _ => {}
+        }
+    }
+    let version_of = |idx: usize| match idx % 5 {
+        0 | 1 => Some(1),
+        2 | 3 => None,
+        _ => Some(0),
+    };
+    check_storage(&storage, 100, version_of);
+
+    let disk_usage = value_log.disk_usage().unwrap();
+    let num_files = std::fs::read_dir(dir.path())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("vlog".as_ref()))
+        .count();
+    assert!(num_files > 3);
+    // Collecting the files written before the GC started relinks the live values to new files.
+    for _ in 0..num_files - 1 {
+        assert!(storage.gc_value_log().unwrap());
+        check_storage(&storage, 100, version_of);
+    }
+    // Mostly the live values are left.
+    assert!(value_log.disk_usage().unwrap() * 2 < disk_usage);
+
+    drop(storage);
+    let storage = LsmStorageInner::open(dir.path(), options()).unwrap();
+    check_storage(&storage, 100, version_of);
+}
+
+#[test]
+fn test_value_log_disabled() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    options.value_log = None;
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    storage.put(b"key", &value_of(1, 0)).unwrap();
+    assert_eq!(
+        storage.state.read().memtable.get(b"key").as_deref(),
+        Some(&value_of(1, 0)[..])
+    );
+    assert!(!storage.gc_value_log().unwrap());
+}
+
+#[test]
+fn test_value_log_gc_pinned() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options()).unwrap());
+    for idx in 0..40 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in 0..40 {
+        storage.put(&key_of(idx), &value_of(idx, 1)).unwrap();
+    }
+    // The first file only holds overwritten values, and is retired right away.
+    let first_file = dir.path().join("00001.vlog");
+    assert!(storage.gc_value_log().unwrap());
+    check_storage(&storage, 40, |_| Some(1));
+    // The iterator pinned the value log before, and still reads from the file.
+    assert!(first_file.exists());
+    for idx in 0..40 {
+        assert_eq!(iter.key(), &key_of(idx)[..]);
+        assert_eq!(iter.value(), &value_of(idx, 0)[..]);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    drop(iter);
+    assert!(!first_file.exists());
+    check_storage(&storage, 40, |_| Some(1));
+}
+
+#[test]
+fn test_value_log_gc_without_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    options.durability = DurabilityMode::NoWal;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    for idx in 0..40 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    storage.force_flush_next_imm_memtable().unwrap();
+    // The values relinked by the GC are flushed before their file is deleted.
+    assert!(storage.gc_value_log().unwrap());
+    assert!(!dir.path().join("00001.vlog").exists());
+    assert!(storage.state.read().imm_memtables.is_empty());
+    assert!(storage.state.read().memtable.is_empty());
+
+    drop(storage);
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    check_storage(&storage, 40, |_| Some(0));
+}
+
+#[test]
+fn test_value_log_gc_retries_deletes() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(LsmStorageInner::open(dir.path(), options()).unwrap());
+    let value_log = storage.value_log.clone().unwrap();
+    for idx in 0..40 {
+        storage.put(&key_of(idx), &value_of(idx, 0)).unwrap();
+    }
+    let iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    for idx in 0..40 {
+        storage.put(&key_of(idx), &value_of(idx, 1)).unwrap();
+    }
+    assert!(storage.gc_value_log().unwrap());
+    // Make the deletion of the retired file fail once the iterator unpins it.
+    let first_file = dir.path().join("00001.vlog");
+    std::fs::remove_file(&first_file).unwrap();
+    std::fs::create_dir(&first_file).unwrap();
+    std::fs::write(first_file.join("file"), b"").unwrap();
+    drop(iter);
+    assert_eq!(
+        value_log.files.read().undeleted.iter().collect::<Vec<_>>(),
+        [&1]
+    );
+
+    // The next GC fails to delete it again, and then succeeds once it can.
+    assert!(storage.gc_value_log().is_err());
+    std::fs::remove_dir_all(&first_file).unwrap();
+    storage.gc_value_log().unwrap();
+    assert!(value_log.files.read().undeleted.is_empty());
+    check_storage(&storage, 40, |_| Some(1));
+}
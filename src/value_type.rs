@@ -0,0 +1,122 @@
+// Copyright (c) 2022-2025 Alex Chi Z
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, bail, ensure};
+use bytes::Bytes;
+
+/// The size of the expiry prefixed to the value of a `ValueType::PutWithExpiry` entry.
+const SIZEOF_EXPIRY: usize = std::mem::size_of::<u64>();
+
+/// The kind of an entry, stored alongside its value in memtables, the WAL and data blocks.
+///
+/// Data written before value types has none: an empty value is a tombstone, and any other value
+/// is a put. The APIs taking a bare value, e.g. `MemTable::put` or `SsTableBuilder::add`, follow
+/// the same convention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueType {
+    #[default]
+    Put,
+    /// A tombstone, whose value is empty.
+    Delete,
+    /// An operand to combine with the older values of the key.
+    Merge,
+    /// A tombstone for a key put exactly once, which compaction drops together with that put
+    /// instead of keeping it down to the bottom level. Its value is empty.
+    SingleDelete,
+    /// A put that expires, whose value is prefixed with its expiry, see `with_expiry`. Once
+    /// expired, it hides the key like a tombstone.
+    PutWithExpiry,
+}
+
+impl ValueType {
+    /// The type of a bare `value`, written without a type.
+    pub fn of_untyped(value: &[u8]) -> Self {
+        if value.is_empty() {
+            ValueType::Delete
+        } else {
+            ValueType::Put
+        }
+    }
+
+    /// Whether the entry deletes the key.
+    pub fn is_tombstone(self) -> bool {
+        matches!(self, ValueType::Delete | ValueType::SingleDelete)
+    }
+
+    /// Whether the entry is a put whose expiry has passed.
+    pub(crate) fn is_expired(self, value: &[u8]) -> bool {
+        self == ValueType::PutWithExpiry
+            && split_expiry(value).is_ok_and(|(expires_at, _)| expires_at <= now_millis())
+    }
+
+    /// Whether the entry hides its key from reads: a tombstone, or an expired put.
+    pub(crate) fn hides_key(self, value: &[u8]) -> bool {
+        self.is_tombstone() || self.is_expired(value)
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            ValueType::Put => 0,
+            ValueType::Delete => 1,
+            ValueType::Merge => 2,
+            ValueType::SingleDelete => 3,
+            ValueType::PutWithExpiry => 4,
+        }
+    }
+
+    pub(crate) fn from_u8(value_type: u8) -> Result<Self> {
+        Ok(match value_type {
+            0 => ValueType::Put,
+            1 => ValueType::Delete,
+            2 => ValueType::Merge,
+            3 => ValueType::SingleDelete,
+            4 => ValueType::PutWithExpiry,
+            _ => bail!("unknown value type {}", value_type),
+        })
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch, the unit of expiries.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Prefix `value` with its expiry, as stored in a `ValueType::PutWithExpiry` entry:
+/// `expires_at (u64, milliseconds since the Unix epoch) | value`.
+pub(crate) fn with_expiry(expires_at: u64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(SIZEOF_EXPIRY + value.len());
+    buf.extend_from_slice(&expires_at.to_be_bytes());
+    buf.extend_from_slice(value);
+    buf
+}
+
+/// Split the value of a `ValueType::PutWithExpiry` entry into its expiry and the value.
+pub(crate) fn split_expiry(value: &[u8]) -> Result<(u64, &[u8])> {
+    ensure!(value.len() >= SIZEOF_EXPIRY, "expiring value is truncated");
+    let (expires_at, value) = value.split_at(SIZEOF_EXPIRY);
+    Ok((u64::from_be_bytes(expires_at.try_into().unwrap()), value))
+}
+
+/// Like `split_expiry`, without copying the value.
+pub(crate) fn split_expiry_bytes(value: &Bytes) -> Result<(u64, Bytes)> {
+    let (expires_at, _) = split_expiry(value)?;
+    Ok((expires_at, value.slice(SIZEOF_EXPIRY..)))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,322 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes};
+use tempfile::tempdir;
+
+use crate::compact::{CompactionTask, TieredCompactionTask};
+use crate::iterators::StorageIterator;
+use crate::key::{KeySlice, KeyVec};
+use crate::lsm_storage::{DurabilityMode, LsmStorageInner, LsmStorageOptions, WriteBatchRecord};
+use crate::merge_operator::AppendOperator;
+use crate::table::{BlockMeta, FileObject, SsTable, SsTableIterator};
+use crate::test_harness::{collect, key_of};
+use crate::value_type::ValueType;
+
+/// Check that `flag` is stored with an empty value, `deleted` is deleted and `value` is kept.
+fn check_storage(storage: &LsmStorageInner) {
+    assert_eq!(storage.get(b"flag").unwrap().as_deref(), Some(&b""[..]));
+    assert_eq!(storage.get(b"deleted").unwrap(), None);
+    assert_eq!(storage.get(b"value").unwrap().as_deref(), Some(&b"1"[..]));
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    assert_eq!(
+        collect(&mut iter),
+        [
+            (b"flag".to_vec(), vec![]),
+            (b"value".to_vec(), b"1".to_vec())
+        ]
+    );
+}
+
+#[test]
+fn test_empty_value_round_trip() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    storage.put(b"deleted", b"1").unwrap();
+    storage.put(b"flag", b"").unwrap();
+    storage.delete(b"deleted").unwrap();
+    storage.put(b"value", b"1").unwrap();
+    check_storage(&storage);
+
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    check_storage(&storage);
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    check_storage(&storage);
+}
+
+#[test]
+fn test_sst_value_types() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    storage.put(b"deleted", b"1").unwrap();
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+
+    let mut builder = storage.new_sst_builder();
+    for (key, value_type) in [
+        (&b"deleted"[..], ValueType::Delete),
+        (b"flag", ValueType::Put),
+    ] {
+        builder.add_typed(KeySlice::for_testing_from_slice_no_ts(key), value_type, b"");
+    }
+    builder.add(KeySlice::for_testing_from_slice_no_ts(b"value"), b"1");
+    let sst_id = storage.next_sst_id();
+    let sst = builder
+        .build(
+            sst_id,
+            Some(storage.block_cache.clone()),
+            storage.path_of_sst(sst_id),
+        )
+        .unwrap();
+    {
+        let mut guard = storage.state.write();
+        let mut snapshot = guard.as_ref().clone();
+        snapshot.imm_memtables.clear();
+        snapshot.l0_sstables.insert(0, sst_id);
+        snapshot.sstables.insert(sst_id, Arc::new(sst));
+        *guard = Arc::new(snapshot);
+    }
+    check_storage(&storage);
+
+    let sst =
+        SsTable::open_for_test(FileObject::open(&storage.path_of_sst(sst_id)).unwrap()).unwrap();
+    assert_eq!(
+        sst.get(KeySlice::for_testing_from_slice_no_ts(b"flag"))
+            .unwrap(),
+        Some((ValueType::Put, Default::default()))
+    );
+    assert_eq!(
+        sst.get(KeySlice::for_testing_from_slice_no_ts(b"deleted"))
+            .unwrap(),
+        Some((ValueType::Delete, Default::default()))
+    );
+}
+
+#[test]
+fn test_txn_empty_value() {
+    let dir = tempdir().unwrap();
+    let storage = Arc::new(
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap(),
+    );
+    storage.put(b"deleted", b"1").unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"flag", b"");
+    txn.delete(b"deleted");
+    txn.put(b"value", b"1");
+    assert_eq!(txn.get(b"flag").unwrap().as_deref(), Some(&b""[..]));
+    assert_eq!(txn.get(b"deleted").unwrap(), None);
+    txn.commit().unwrap();
+    check_storage(&storage);
+}
+
+#[test]
+fn test_open_untyped_sst() {
+    // An SST of format version 4, whose blocks have restart points but no value types.
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let entries = [(&b"a"[..], &b"1"[..]), (b"b", b""), (b"c", b"3")];
+    let mut buf = Vec::new();
+    let mut offsets = Vec::new();
+    for (key, value) in entries {
+        // Every entry is a restart point with its full key.
+        offsets.push(buf.len() as u32);
+        buf.put_u8(0);
+        buf.put_u8(key.len() as u8);
+        buf.put_slice(key);
+        buf.put_u8(value.len() as u8);
+        buf.put_slice(value);
+    }
+    for offset in &offsets {
+        buf.put_u32(*offset);
+    }
+    buf.put_u32(offsets.len() as u32);
+    buf.put_u8(0); // codec
+    let checksum = crc32fast::hash(&buf);
+    buf.put_u32(checksum);
+    let meta_offset = buf.len();
+    let block_meta = BlockMeta {
+        offset: 0,
+        first_key: KeyVec::for_testing_from_vec_no_ts(b"a".to_vec()).into_key_bytes(),
+        last_key: KeyVec::for_testing_from_vec_no_ts(b"c".to_vec()).into_key_bytes(),
+    };
+    BlockMeta::encode_block_meta(&[block_meta], &mut buf);
+    let bloom_offset = buf.len();
+    buf.put_u32(meta_offset as u32);
+    buf.put_u32(bloom_offset as u32);
+    buf.put_u32(bloom_offset as u32); // no range tombstones
+    buf.put_u8(0); // full index
+    buf.put_u8(4); // format version
+    std::fs::write(&path, buf).unwrap();
+
+    let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for ((key, value), value_type) in
+        entries
+            .into_iter()
+            .zip([ValueType::Put, ValueType::Delete, ValueType::Put])
+    {
+        assert_eq!(iter.key().for_testing_key_ref(), key);
+        assert_eq!(iter.value(), value);
+        assert_eq!(iter.value_type(), value_type);
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    assert_eq!(
+        sst.get(KeySlice::for_testing_from_slice_no_ts(b"b"))
+            .unwrap(),
+        Some((ValueType::Delete, Default::default()))
+    );
+}
+
+fn scan_keys(storage: &LsmStorageInner) -> Vec<Vec<u8>> {
+    let mut iter = storage.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    let mut keys = Vec::new();
+    while iter.is_valid() {
+        keys.push(iter.key().to_vec());
+        iter.next().unwrap();
+    }
+    keys
+}
+
+#[test]
+fn test_single_delete_hides_key() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.durability = DurabilityMode::SyncEveryWrite;
+    let storage = LsmStorageInner::open(dir.path(), options.clone()).unwrap();
+    for idx in 0..6 {
+        storage.put(&key_of(idx), b"1").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    // Delete keys put in the immutable memtable, and one put in the same memtable.
+    storage.single_delete(&key_of(1)).unwrap();
+    storage.single_delete(&key_of(4)).unwrap();
+    storage.put(&key_of(7), b"1").unwrap();
+    storage.single_delete(&key_of(7)).unwrap();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(key_of(8), b"1".to_vec()),
+            WriteBatchRecord::SingleDel(key_of(8)),
+        ])
+        .unwrap();
+    let expected = [0, 2, 3, 5].map(key_of);
+    assert_eq!(scan_keys(&storage), expected);
+    assert_eq!(storage.get(&key_of(4)).unwrap(), None);
+    drop(storage);
+
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    assert_eq!(scan_keys(&storage), expected);
+    assert_eq!(storage.get(&key_of(1)).unwrap(), None);
+    assert_eq!(
+        storage.state.read().imm_memtables[0].get_typed(&key_of(7)),
+        Some((ValueType::SingleDelete, Bytes::new()))
+    );
+}
+
+#[test]
+fn test_single_delete_full_compaction() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week1_test();
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    for idx in 0..6 {
+        storage.put(&key_of(idx), b"1").unwrap();
+    }
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    storage.single_delete(&key_of(1)).unwrap();
+    storage.single_delete(&key_of(4)).unwrap();
+    storage
+        .force_freeze_memtable(&storage.state_lock.lock())
+        .unwrap();
+    while !storage.state.read().imm_memtables.is_empty() {
+        storage.force_flush_next_imm_memtable().unwrap();
+    }
+    storage.force_full_compaction().unwrap();
+
+    // The single deletes are dropped together with the puts they delete.
+    let expected = [0, 2, 3, 5].map(key_of);
+    assert_eq!(scan_keys(&storage), expected);
+    let snapshot = storage.state.read().clone();
+    let mut keys = Vec::new();
+    for id in &snapshot.levels[0].1 {
+        let sst = snapshot.sstables[id].clone();
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        while iter.is_valid() {
+            keys.push(iter.key().raw_ref().to_vec());
+            iter.next().unwrap();
+        }
+    }
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn test_single_delete_validation() {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week1_test();
+    options.merge_operator = Some(Arc::new(AppendOperator));
+    let storage = LsmStorageInner::open(dir.path(), options).unwrap();
+    // Nothing to delete.
+    assert!(storage.single_delete(b"missing").is_err());
+    storage.put(b"deleted", b"1").unwrap();
+    storage.delete(b"deleted").unwrap();
+    assert!(storage.single_delete(b"deleted").is_err());
+    storage.merge(b"merged", b"1").unwrap();
+    assert!(storage.single_delete(b"merged").is_err());
+    storage.put(b"once", b"1").unwrap();
+    storage.single_delete(b"once").unwrap();
+    assert!(storage.single_delete(b"once").is_err());
+    assert!(
+        storage
+            .write_batch(&[
+                WriteBatchRecord::Del(&b"once"[..]),
+                WriteBatchRecord::SingleDel(&b"once"[..]),
+            ])
+            .is_err()
+    );
+}
+
+#[test]
+fn test_compact_single_delete() {
+    let task = CompactionTask::Tiered(TieredCompactionTask {
+        tiers: Vec::new(),
+        bottom_tier_included: false,
+    });
+    let single_delete = (ValueType::SingleDelete, Bytes::new());
+    let put = (ValueType::Put, Bytes::from_static(b"1"));
+    assert_eq!(
+        task.compact_entries(None, b"key", &[single_delete.clone(), put.clone()])
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        task.compact_entries(None, b"key", std::slice::from_ref(&single_delete))
+            .unwrap(),
+        Some(single_delete.clone())
+    );
+    // A misused single delete is handled like a delete, rather than failing the compaction.
+    for older in [
+        vec![put.clone(), put.clone()],
+        vec![(ValueType::Delete, Bytes::new())],
+        vec![(ValueType::Merge, Bytes::from_static(b"1"))],
+    ] {
+        let entries = [vec![single_delete.clone()], older].concat();
+        assert_eq!(
+            task.compact_entries(None, b"key", &entries).unwrap(),
+            Some((ValueType::Delete, Bytes::new()))
+        );
+    }
+}
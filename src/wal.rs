@@ -1,4 +1,3 @@
-// REMOVE THIS LINE after fully implementing this functionality
 // Copyright (c) 2022-2025 Alex Chi Z
 //
 // Licensed under the Apache License, Version 2.0 (the "License");
@@ -12,42 +11,536 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-#![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
-#![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
-use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::Result;
-use bytes::Bytes;
+use anyhow::{Context, Result, bail, ensure};
+use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+use crate::range_tombstone::RangeTombstone;
+use crate::value_type::ValueType;
+
+const SIZEOF_U32: usize = std::mem::size_of::<u32>();
+
+/// Written in place of the `ValueType` of an entry to mark a range tombstone, whose key and value
+/// are its start and end keys.
+const RANGE_TOMBSTONE_ENTRY: u8 = u8::MAX;
+
+/// An entry read back from the WAL.
+pub(crate) enum WalEntry {
+    Value(Bytes, ValueType, Bytes),
+    RangeTombstone(RangeTombstone),
+}
+
+/// A WAL is made of one or more segment files: the first one at the path given to `create`, and
+/// the following ones at that path suffixed with `.1`, `.2`, etc.
 #[derive(Debug)]
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
+    file: Arc<Mutex<WalFile>>,
+    group: Arc<GroupCommit>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WalOptions {
+    /// Start a new segment once the current one would grow beyond this size. 0 means a single,
+    /// unbounded segment. Records are never split, so a segment may exceed the limit when a single
+    /// group of records is larger than it.
+    pub segment_size: usize,
+    /// Extend each new segment to `segment_size` up front, so that appending to it does not change
+    /// the file size and an fsync does not need to update the file metadata.
+    pub preallocate: bool,
+}
+
+/// Called before every fsync of a WAL, e.g. to make durable the value log that its records
+/// point into.
+#[derive(Clone)]
+pub(crate) struct BeforeSync(Arc<dyn Fn() -> Result<()> + Send + Sync>);
+
+impl BeforeSync {
+    pub(crate) fn new(before_sync: impl Fn() -> Result<()> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(before_sync))
+    }
+}
+
+impl std::fmt::Debug for BeforeSync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BeforeSync")
+    }
+}
+
+/// The segment being appended to.
+#[derive(Debug)]
+struct WalFile {
+    writer: BufWriter<File>,
+    /// The path of the first segment.
+    path: PathBuf,
+    /// The index of the current segment.
+    segment: usize,
+    /// The end of the records in the current segment.
+    offset: u64,
+    options: WalOptions,
+    before_sync: Option<BeforeSync>,
+}
+
+impl WalFile {
+    fn open_segment(path: &Path, segment: usize, options: &WalOptions) -> Result<File> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(segment_path(path, segment))
+            .context("failed to create WAL")?;
+        if options.preallocate && options.segment_size > 0 {
+            file.set_len(options.segment_size as u64)?;
+        }
+        // The records synced to the segment are only durable once its directory entry is.
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(file)
+    }
+
+    fn write(&mut self, buf: &[u8], sync: bool) -> Result<()> {
+        if self.options.segment_size > 0
+            && self.offset > 0
+            && self.offset as usize + buf.len() > self.options.segment_size
+        {
+            // Make the full segment durable before moving on, so that a later fsync only needs
+            // to cover the new segment.
+            self.writer.flush()?;
+            self.sync_data()?;
+            let file = Self::open_segment(&self.path, self.segment + 1, &self.options)?;
+            self.writer = BufWriter::new(file);
+            self.segment += 1;
+            self.offset = 0;
+        }
+        self.writer.write_all(buf)?;
+        self.writer.flush()?;
+        self.offset += buf.len() as u64;
+        if sync {
+            self.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<()> {
+        if let Some(before_sync) = &self.before_sync {
+            (before_sync.0)()?;
+        }
+        self.writer.get_mut().sync_data()?;
+        Ok(())
+    }
+}
+
+/// The path of the `segment`-th segment of the WAL whose first segment is at `path`.
+fn segment_path(path: &Path, segment: usize) -> PathBuf {
+    if segment == 0 {
+        return path.to_path_buf();
+    }
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", segment));
+    path.into()
+}
+
+/// Records appended by concurrent writers are collected into groups. The first writer that finds
+/// no write in progress becomes the leader: it writes (and fsyncs, if any member asked for it) the
+/// whole group at once, then wakes up the followers with the result.
+#[derive(Debug, Default)]
+struct GroupCommit {
+    state: Mutex<GroupState>,
+    done: Condvar,
+}
+
+#[derive(Debug)]
+struct GroupState {
+    /// Encoded records of the group accepting new writers.
+    pending: Vec<u8>,
+    /// Whether a member of the pending group asked for an fsync.
+    pending_sync: bool,
+    /// The id of the group accepting new writers.
+    open_group: u64,
+    /// All groups up to this id have been written.
+    done_group: u64,
+    /// Whether a leader is writing a group.
+    writing: bool,
+    /// The first group that failed to be written. The WAL refuses any write after that.
+    error: Option<(u64, String)>,
+}
+
+impl Default for GroupState {
+    fn default() -> Self {
+        Self {
+            pending: Vec::new(),
+            pending_sync: false,
+            open_group: 1,
+            done_group: 0,
+            writing: false,
+            error: None,
+        }
+    }
 }
 
 impl Wal {
     pub fn create(_path: impl AsRef<Path>) -> Result<Self> {
-        unimplemented!()
+        Self::create_with_options(_path, WalOptions::default())
+    }
+
+    pub fn create_with_options(path: impl AsRef<Path>, options: WalOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let file = WalFile::open_segment(path, 0, &options)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(WalFile {
+                writer: BufWriter::new(file),
+                path: path.to_path_buf(),
+                segment: 0,
+                offset: 0,
+                options,
+                before_sync: None,
+            })),
+            group: Arc::default(),
+        })
+    }
+
+    /// Call `before_sync` before every fsync of the WAL, including the one made when moving on to
+    /// a new segment.
+    pub(crate) fn set_before_sync(&self, before_sync: Option<BeforeSync>) {
+        self.file.lock().before_sync = before_sync;
     }
 
     pub fn recover(_path: impl AsRef<Path>, _skiplist: &SkipMap<Bytes, Bytes>) -> Result<Self> {
-        unimplemented!()
+        Self::recover_with_options(_path, _skiplist, WalOptions::default())
+    }
+
+    /// Replay all segments of the WAL into `skiplist`, and continue appending to the last one. A
+    /// torn batch at the end of the last segment, left by a crash in the middle of a write, is
+    /// discarded, see `replay_segment`. Tombstones are inserted as empty values and the keys deleted by range
+    /// tombstones are removed, but the other value types are lost; see `recover_with_replay`.
+    pub fn recover_with_options(
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<Bytes, Bytes>,
+        options: WalOptions,
+    ) -> Result<Self> {
+        Self::recover_with_replay(path, options, |entry| match entry {
+            WalEntry::Value(key, value_type, _) if value_type.is_tombstone() => {
+                skiplist.insert(key, Bytes::new());
+            }
+            WalEntry::Value(key, _, value) => {
+                skiplist.insert(key, value);
+            }
+            WalEntry::RangeTombstone(tombstone) => tombstone.remove_covered(skiplist),
+        })
+    }
+
+    /// Like `recover_with_options`, passing each entry to `replay` in the order it was written.
+    pub(crate) fn recover_with_replay(
+        path: impl AsRef<Path>,
+        options: WalOptions,
+        mut replay: impl FnMut(WalEntry),
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let mut segment = 0;
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(segment_path(path, segment))
+                .context("failed to recover from WAL")?;
+            let is_last = !segment_path(path, segment + 1).exists();
+            let offset = Self::replay_segment(
+                &mut file,
+                &segment_path(path, segment),
+                is_last,
+                &mut replay,
+            )?;
+            if !is_last {
+                segment += 1;
+                continue;
+            }
+            // Cut off whatever follows the last complete batch, so that new batches are not
+            // appended after garbage.
+            file.set_len(offset)?;
+            if options.preallocate && options.segment_size as u64 > offset {
+                file.set_len(options.segment_size as u64)?;
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            return Ok(Self {
+                file: Arc::new(Mutex::new(WalFile {
+                    writer: BufWriter::new(file),
+                    path: path.to_path_buf(),
+                    segment,
+                    offset,
+                    options,
+                    before_sync: None,
+                })),
+                group: Arc::default(),
+            });
+        }
+    }
+
+    /// Pass the entries of the batches of a segment to `replay`, and return the end offset of the
+    /// last one. In the last segment, a truncated batch or one whose checksum does not match is
+    /// the end of the log, left by a crash in the middle of a write; e.g. with preallocation, a
+    /// batch length may reach the disk while the batch itself is still zeros. The other segments
+    /// were fsynced in full before the next one was created, so the same is an error there.
+    fn replay_segment(
+        file: &mut File,
+        path: &Path,
+        is_last: bool,
+        replay: &mut impl FnMut(WalEntry),
+    ) -> Result<u64> {
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut rbuf = &buf[..];
+        while rbuf.remaining() >= SIZEOF_U32 {
+            let batch_len = (&rbuf[..SIZEOF_U32]).get_u32() as usize;
+            // A zero length marks the preallocated space after the last batch.
+            if batch_len == 0 {
+                break;
+            }
+            let torn = if rbuf.remaining() < SIZEOF_U32 + batch_len + SIZEOF_U32 {
+                Some("truncated batch")
+            } else {
+                let checksum = (&rbuf[SIZEOF_U32 + batch_len..]).get_u32();
+                (checksum != crc32fast::hash(&rbuf[SIZEOF_U32..SIZEOF_U32 + batch_len]))
+                    .then_some("checksum mismatched")
+            };
+            match torn {
+                Some(_) if is_last => break,
+                Some(reason) => bail!("{} in WAL {}", reason, path.display()),
+                None => {}
+            }
+            let batch = &rbuf[SIZEOF_U32..SIZEOF_U32 + batch_len];
+            rbuf.advance(SIZEOF_U32 + batch_len + SIZEOF_U32);
+            let entries = Self::decode_batch(batch)
+                .with_context(|| format!("malformed batch in WAL {}", path.display()))?;
+            entries.into_iter().for_each(&mut *replay);
+        }
+        Ok((buf.len() - rbuf.len()) as u64)
+    }
+
+    /// Decode the entries of a batch whose checksum matched.
+    fn decode_batch(mut batch: &[u8]) -> Result<Vec<WalEntry>> {
+        fn read_bytes(batch: &mut &[u8]) -> Result<Bytes> {
+            ensure!(batch.remaining() >= SIZEOF_U32, "entry is truncated");
+            let len = batch.get_u32() as usize;
+            ensure!(batch.remaining() >= len, "entry is truncated");
+            let bytes = Bytes::copy_from_slice(&batch[..len]);
+            batch.advance(len);
+            Ok(bytes)
+        }
+        let mut entries = Vec::new();
+        while batch.has_remaining() {
+            let entry_type = batch.get_u8();
+            let key = read_bytes(&mut batch)?;
+            let value = read_bytes(&mut batch)?;
+            entries.push(if entry_type == RANGE_TOMBSTONE_ENTRY {
+                WalEntry::RangeTombstone(RangeTombstone {
+                    start: key,
+                    end: value,
+                })
+            } else {
+                WalEntry::Value(key, ValueType::from_u8(entry_type)?, value)
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Delete all segments of the WAL at `path`. Only call this once the memtable it backs is
+    /// persisted, as the data cannot be recovered afterwards. The last segment goes first, so that
+    /// a crash in the middle leaves the WAL truncated rather than with a hole.
+    pub fn remove(path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut segments = 0;
+        while segment_path(path, segments).exists() {
+            segments += 1;
+        }
+        for segment in (0..segments).rev() {
+            std::fs::remove_file(segment_path(path, segment))?;
+        }
+        Ok(())
     }
 
     pub fn put(&self, _key: &[u8], _value: &[u8]) -> Result<()> {
-        unimplemented!()
+        self.put_batch(&[(_key, _value)])
     }
 
-    /// Implement this in week 3, day 5.
+    /// Append a batch of key-value pairs as one record, so that it is recovered all or nothing.
+    /// Returns once the batch has been written to the file, but not necessarily fsynced. Empty
+    /// values are tombstones.
     pub fn put_batch(&self, _data: &[(&[u8], &[u8])]) -> Result<()> {
-        unimplemented!()
+        let data = _data
+            .iter()
+            .map(|(key, value)| (*key, ValueType::of_untyped(value), *value))
+            .collect::<Vec<_>>();
+        self.put_batch_typed(&data)
+    }
+
+    /// Like `put_batch`, for entries of any type.
+    ///
+    /// | batch len (u32) | type (u8) | key_len (u32) | key | value_len (u32) | value | ... | checksum (u32) |
+    ///
+    /// Fails if the batch is larger than 4GiB.
+    pub fn put_batch_typed(&self, data: &[(&[u8], ValueType, &[u8])]) -> Result<()> {
+        self.enqueue_batch(data, false)?.wait()
+    }
+
+    /// Add a batch to the open group without waiting for it to be written, and fsync the group if
+    /// `sync`. Batches are written in the order they are enqueued, so the caller can enqueue under
+    /// its own lock, release it, and only then wait with the returned ticket, sharing the write
+    /// and fsync with the batches enqueued meanwhile.
+    pub(crate) fn enqueue_batch(
+        &self,
+        data: &[(&[u8], ValueType, &[u8])],
+        sync: bool,
+    ) -> Result<WalTicket> {
+        let data = data
+            .iter()
+            .map(|(key, value_type, value)| (value_type.to_u8(), *key, *value))
+            .collect::<Vec<_>>();
+        let record = if data.is_empty() {
+            Vec::new()
+        } else {
+            Self::encode_batch(&data)?
+        };
+        self.enqueue(&record, sync)
     }
 
+    /// Append a range tombstone deleting the keys in `[start, end)`, as a record of its own. It is
+    /// encoded as an entry of type `u8::MAX` from `start` to `end`.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        let record = Self::encode_batch(&[(RANGE_TOMBSTONE_ENTRY, start, end)])?;
+        self.append(&record, false)
+    }
+
+    /// Encode entries of `(type, key, value)` as a batch record.
+    fn encode_batch(data: &[(u8, &[u8], &[u8])]) -> Result<Vec<u8>> {
+        let batch_len = data
+            .iter()
+            .map(|(_, key, value)| 1 + SIZEOF_U32 + key.len() + SIZEOF_U32 + value.len())
+            .sum::<usize>();
+        ensure!(
+            batch_len <= u32::MAX as usize,
+            "WAL batch of {} bytes is too large",
+            batch_len
+        );
+        let mut buf = Vec::with_capacity(SIZEOF_U32 + batch_len + SIZEOF_U32);
+        buf.put_u32(batch_len as u32);
+        for (entry_type, key, value) in data {
+            buf.put_u8(*entry_type);
+            buf.put_u32(key.len() as u32);
+            buf.put_slice(key);
+            buf.put_u32(value.len() as u32);
+            buf.put_slice(value);
+        }
+        let checksum = crc32fast::hash(&buf[SIZEOF_U32..]);
+        buf.put_u32(checksum);
+        Ok(buf)
+    }
+
+    /// Fsync everything appended so far. Concurrent calls share a single fsync.
     pub fn sync(&self) -> Result<()> {
-        unimplemented!()
+        self.append(&[], true)
     }
+
+    /// Add `record` to the open group and wait until the group is written.
+    fn append(&self, record: &[u8], sync: bool) -> Result<()> {
+        self.enqueue(record, sync)?.wait()
+    }
+
+    /// Fail if a group failed to be written: the records enqueued since may not be in the WAL.
+    pub(crate) fn check(&self) -> Result<()> {
+        Self::check_state(&self.group.state.lock())
+    }
+
+    fn check_state(state: &GroupState) -> Result<()> {
+        if let Some((_, err)) = &state.error {
+            bail!("WAL is unavailable after a failed write: {}", err);
+        }
+        Ok(())
+    }
+
+    /// Add `record` to the open group, to be written by `WalTicket::wait`.
+    fn enqueue(&self, record: &[u8], sync: bool) -> Result<WalTicket> {
+        let mut state = self.group.state.lock();
+        Self::check_state(&state)?;
+        // Nothing to write: group 0 is always done.
+        let group = if record.is_empty() && !sync {
+            0
+        } else {
+            state.pending.extend_from_slice(record);
+            state.pending_sync |= sync;
+            state.open_group
+        };
+        Ok(WalTicket {
+            wal: Wal {
+                file: self.file.clone(),
+                group: self.group.clone(),
+            },
+            group,
+        })
+    }
+
+    /// Wait until `group` is written, leading it if nobody is writing.
+    fn wait(&self, group: u64) -> Result<()> {
+        let mut state = self.group.state.lock();
+        loop {
+            if state.done_group >= group {
+                return match &state.error {
+                    Some((failed_group, err)) if *failed_group <= group => {
+                        bail!("failed to write WAL: {}", err)
+                    }
+                    _ => Ok(()),
+                };
+            }
+            if !state.writing {
+                // The group of this writer is not written and nobody is writing, so it must still
+                // be the open group: lead it.
+                self.lead_group(&mut state);
+                continue;
+            }
+            self.group.done.wait(&mut state);
+        }
+    }
+
+    fn lead_group(&self, state: &mut MutexGuard<'_, GroupState>) {
+        let buf = std::mem::take(&mut state.pending);
+        let sync = std::mem::take(&mut state.pending_sync);
+        let group = state.open_group;
+        state.open_group += 1;
+        state.writing = true;
+        let result = MutexGuard::unlocked(state, || self.write_group(&buf, sync));
+        state.writing = false;
+        state.done_group = group;
+        if let Err(e) = result {
+            state.error.get_or_insert((group, e.to_string()));
+        }
+        self.group.done.notify_all();
+    }
+
+    fn write_group(&self, buf: &[u8], sync: bool) -> Result<()> {
+        self.file.lock().write(buf, sync)
+    }
+}
+
+/// A record enqueued to a WAL, see `Wal::enqueue_batch`.
+#[must_use]
+pub(crate) struct WalTicket {
+    wal: Wal,
+    group: u64,
 }
+
+impl WalTicket {
+    /// Wait until the record is written, and fsynced if asked for by any record of its group.
+    pub(crate) fn wait(self) -> Result<()> {
+        self.wal.wait(self.group)
+    }
+}
+
+#[cfg(test)]
+mod tests;
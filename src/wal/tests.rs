@@ -0,0 +1,228 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::{BufMut, Bytes};
+use crossbeam_skiplist::SkipMap;
+use tempfile::tempdir;
+
+use crate::lsm_storage::{LsmStorageInner, LsmStorageOptions, MAX_KEY_SIZE};
+use crate::wal::{BeforeSync, Wal, WalOptions};
+
+#[test]
+fn test_wal_group_commit_concurrent_writers() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let wal = Arc::new(Wal::create(&path).unwrap());
+
+    let handles = (0..8)
+        .map(|t| {
+            let wal = wal.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key_{}_{:03}", t, i);
+                    let value = format!("value_{}", i);
+                    wal.put_batch(&[
+                        (key.as_bytes(), value.as_bytes()),
+                        (b"last", key.as_bytes()),
+                    ])
+                    .unwrap();
+                    if i % 10 == 0 {
+                        wal.sync().unwrap();
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    wal.sync().unwrap();
+    drop(wal);
+
+    let skiplist = SkipMap::new();
+    Wal::recover(&path, &skiplist).unwrap();
+    assert_eq!(skiplist.len(), 8 * 100 + 1);
+    for t in 0..8 {
+        for i in 0..100 {
+            let key = format!("key_{}_{:03}", t, i);
+            assert_eq!(
+                skiplist.get(key.as_bytes()).unwrap().value(),
+                &Bytes::from(format!("value_{}", i))
+            );
+        }
+    }
+}
+
+#[test]
+fn test_wal_recover_ignores_torn_batch() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let wal = Wal::create(&path).unwrap();
+    wal.put(b"a", b"1").unwrap();
+    wal.put_batch(&[(b"b", b"2"), (b"c", b"3")]).unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+    let len = std::fs::metadata(&path).unwrap().len();
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(len - 1).unwrap();
+
+    let skiplist = SkipMap::new();
+    let wal = Wal::recover(&path, &skiplist).unwrap();
+    assert_eq!(skiplist.len(), 1);
+    assert_eq!(skiplist.get(&b"a"[..]).unwrap().value(), &Bytes::from("1"));
+    wal.put(b"d", b"4").unwrap();
+}
+
+#[test]
+fn test_wal_segments() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let options = WalOptions {
+        segment_size: 256,
+        preallocate: true,
+    };
+    let wal = Wal::create_with_options(&path, options.clone()).unwrap();
+    for i in 0..100 {
+        wal.put(format!("key_{:03}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    wal.sync().unwrap();
+    drop(wal);
+    assert!(dir.path().join("00001.wal.1").exists());
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 256);
+
+    let skiplist = SkipMap::new();
+    let wal = Wal::recover_with_options(&path, &skiplist, options.clone()).unwrap();
+    assert_eq!(skiplist.len(), 100);
+    wal.put(b"key_100", b"value").unwrap();
+    drop(wal);
+
+    let skiplist = SkipMap::new();
+    Wal::recover_with_options(&path, &skiplist, options).unwrap();
+    assert_eq!(skiplist.len(), 101);
+
+    Wal::remove(&path).unwrap();
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_wal_large_entries() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let wal = Wal::create(&path).unwrap();
+    let large_value = vec![b'v'; 100_000];
+    // The type of each entry is written apart from its key, so that keys of any length are read
+    // back as they were written.
+    let long_keys = [
+        vec![b'a'; u16::MAX as usize],
+        vec![b'b'; u16::MAX as usize - 1],
+    ];
+    wal.put(b"large", &large_value).unwrap();
+    wal.put(&long_keys[0], b"1").unwrap();
+    wal.put(&long_keys[1], b"").unwrap();
+    wal.delete_range(b"c", b"d").unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+
+    let skiplist = SkipMap::new();
+    Wal::recover(&path, &skiplist).unwrap();
+    assert_eq!(skiplist.len(), 3);
+    assert_eq!(skiplist.get(&b"large"[..]).unwrap().value(), &large_value);
+    assert_eq!(skiplist.get(&long_keys[0][..]).unwrap().value(), &b"1"[..]);
+    assert_eq!(skiplist.get(&long_keys[1][..]).unwrap().value(), &b""[..]);
+}
+
+#[test]
+fn test_wal_recover_malformed_batch() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    // A batch with a valid checksum, whose key length runs past its end.
+    let mut batch = vec![0u8];
+    batch.put_u32(100);
+    batch.put_slice(b"key");
+    let mut record = Vec::new();
+    record.put_u32(batch.len() as u32);
+    record.put_slice(&batch);
+    record.put_u32(crc32fast::hash(&batch));
+    std::fs::write(&path, record).unwrap();
+
+    let skiplist = SkipMap::new();
+    assert!(Wal::recover(&path, &skiplist).is_err());
+}
+
+#[test]
+fn test_oversize_key_rejected() {
+    let dir = tempdir().unwrap();
+    let storage =
+        LsmStorageInner::open(dir.path(), LsmStorageOptions::default_for_week1_test()).unwrap();
+    let key = vec![b'k'; MAX_KEY_SIZE + 1];
+    assert!(storage.put(&key, b"1").is_err());
+    assert!(storage.delete_range(b"a", &key).is_err());
+    assert_eq!(storage.get(&key[..MAX_KEY_SIZE]).unwrap(), None);
+    storage.put(&key[..MAX_KEY_SIZE], b"1").unwrap();
+    assert_eq!(
+        storage.get(&key[..MAX_KEY_SIZE]).unwrap().as_deref(),
+        Some(&b"1"[..])
+    );
+}
+
+#[test]
+fn test_wal_recover_torn_preallocated_batch() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let options = WalOptions {
+        segment_size: 4096,
+        preallocate: true,
+    };
+    let wal = Wal::create_with_options(&path, options.clone()).unwrap();
+    wal.put(b"a", b"1").unwrap();
+    wal.put(b"b", b"2").unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+    // Only the length of the next batch reached the disk: a record is 19 bytes here.
+    let mut data = std::fs::read(&path).unwrap();
+    (&mut data[38..42]).put_u32(19);
+    std::fs::write(&path, &data).unwrap();
+
+    let skiplist = SkipMap::new();
+    let wal = Wal::recover_with_options(&path, &skiplist, options.clone()).unwrap();
+    assert_eq!(skiplist.len(), 2);
+    wal.put(b"c", b"3").unwrap();
+    drop(wal);
+    let skiplist = SkipMap::new();
+    Wal::recover_with_options(&path, &skiplist, options).unwrap();
+    assert_eq!(skiplist.len(), 3);
+}
+
+#[test]
+fn test_wal_recover_corrupted_segment() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("00001.wal");
+    let options = WalOptions {
+        segment_size: 256,
+        preallocate: false,
+    };
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let wal = Wal::create_with_options(&path, options.clone()).unwrap();
+    {
+        let syncs = syncs.clone();
+        wal.set_before_sync(Some(BeforeSync::new(move || {
+            syncs.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })));
+    }
+    for i in 0..100 {
+        wal.put(format!("key_{:03}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    // Every full segment is fsynced before moving on to the next one.
+    assert!(syncs.load(Ordering::SeqCst) > 0);
+    drop(wal);
+
+    // Only the last segment may end with a torn batch.
+    let mut data = std::fs::read(&path).unwrap();
+    data[10] ^= 1;
+    std::fs::write(&path, &data).unwrap();
+    let skiplist = SkipMap::new();
+    assert!(Wal::recover_with_options(&path, &skiplist, options).is_err());
+}